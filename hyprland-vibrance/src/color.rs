@@ -0,0 +1,539 @@
+//! Generation of the saturation matrices. Every matrix is 3x3, row
+//! major, applied to column RGB vectors, the way the CTM protocol
+//! takes them. Rules can also shift the white point and scale the
+//! brightness and the contrast, which are composed after the
+//! saturation matrix into a single CTM.
+//!
+//! A CTM has no offset, so it can't stretch values around mid-gray:
+//! contrast is a gain of the luma from black, which leaves dark colors
+//! about where they are and spreads the bright ones.
+//!
+//! The compositor applies CTMs to the encoded framebuffer values, while
+//! the algorithms are meant for linear light. A matrix can't undo a
+//! transfer function, but given an assumed one, the level can be
+//! adjusted so a set of reference colors end up with the chroma the
+//! level intends once decoded.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{matrix::Matrix3, probe, units};
+
+/// How saturation levels are turned into a matrix.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Interpolates every channel with the plain average of the three,
+    /// as libvibrant does.
+    #[default]
+    Naive,
+    /// Interpolates every channel with the Rec.709 luma, so boosting
+    /// saturation keeps the perceived brightness of colors. Not the
+    /// default so existing setups keep their look, but what new
+    /// configs should use.
+    #[value(name = "rec709")]
+    Rec709,
+    /// Scales chroma in the opponent space of OKLab (without its
+    /// non-linearity, which a matrix can't express), which shifts hues
+    /// noticeably less at high levels.
+    Oklab,
+}
+
+/// Transfer function the framebuffer values are assumed to be encoded
+/// with.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transfer {
+    /// Apply the level as is, as if values were linear.
+    #[default]
+    None,
+    /// The piecewise sRGB curve.
+    Srgb,
+    #[value(name = "gamma2.2")]
+    Gamma22,
+    #[value(name = "gamma2.4")]
+    Gamma24,
+}
+
+impl Transfer {
+    fn decode(self, value: f64) -> f64 {
+        let value = value.clamp(0.0, 1.0);
+        match self {
+            Transfer::None => value,
+            Transfer::Srgb if value <= 0.04045 => value / 12.92,
+            Transfer::Srgb => ((value + 0.055) / 1.055).powf(2.4),
+            Transfer::Gamma22 => value.powf(2.2),
+            Transfer::Gamma24 => value.powf(2.4),
+        }
+    }
+}
+
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct MatrixOptions {
+    /// Algorithm used to build the saturation matrix
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub algorithm: Algorithm,
+
+    /// Compensate the level for the transfer function the screen
+    /// contents are assumed to be encoded with
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub gamma_compensation: Transfer,
+
+    /// Matrix applied to every output after the one of the rules, e.g.
+    /// from a calibration, as 9 numbers in row-major order
+    #[arg(long, value_parser = probe::parse_matrix, global = true)]
+    pub base_matrix: Option<[f64; 9]>,
+
+    /// How much the saturation of every channel changes, as 3
+    /// multipliers for red, green and blue, 1.0 being the level as is
+    #[arg(long, value_parser = parse_weights, global = true)]
+    pub weights: Option<[f64; 3]>,
+}
+
+impl MatrixOptions {
+    fn base(&self) -> Matrix3 {
+        self.base_matrix.map_or(Matrix3::IDENTITY, Matrix3)
+    }
+}
+
+/// Moderately saturated colors, which are the ones where the difference
+/// between encoded and linear saturation is the most visible.
+const REFERENCE_COLORS: [[f64; 3]; 6] = [
+    [0.8, 0.5, 0.4],
+    [0.3, 0.6, 0.9],
+    [0.5, 0.7, 0.3],
+    [0.6, 0.3, 0.6],
+    [0.9, 0.8, 0.3],
+    [0.3, 0.5, 0.5],
+];
+
+pub const IDENTITY_MATRIX: [f64; 9] = Matrix3::IDENTITY.0;
+
+/// Rec.709 (and sRGB) luma coefficients.
+const REC709_LUMA: [f64; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Linear sRGB to the LMS cone space used by OKLab.
+const RGB_TO_LMS: Matrix3 = Matrix3([
+    0.4122214708,
+    0.5363325363,
+    0.0514459929, //
+    0.2119034982,
+    0.6806995451,
+    0.1073969566, //
+    0.0883024619,
+    0.2817188376,
+    0.6299787005,
+]);
+
+/// LMS to OKLab lightness and opponent axes. OKLab applies it after a
+/// cube root of the LMS values, skipped here.
+const LMS_TO_LAB: Matrix3 = Matrix3([
+    0.2104542553,
+    0.7936177850,
+    -0.0040720468, //
+    1.9779984951,
+    -2.4285922050,
+    0.4505937099, //
+    0.0259040371,
+    0.7827717662,
+    -0.8086757660,
+]);
+
+// between 0.0 and 4.0. Evily stolen from libvibrant
+fn naive_matrix(saturation: f64) -> Matrix3 {
+    let mut matrix = [0f64; 9];
+    let coeff = (1.0 - saturation) / 3.0;
+    for (i, value) in matrix.iter_mut().enumerate() {
+        *value = coeff + if (i % 4) == 0 { saturation } else { 0f64 };
+    }
+
+    Matrix3(matrix)
+}
+
+/// Same as [`naive_matrix`], with every channel weighted by its luma
+/// instead of a third.
+fn luma_matrix(saturation: f64, weights: [f64; 3]) -> Matrix3 {
+    Matrix3(std::array::from_fn(|i| {
+        let (row, col) = (i / 3, i % 3);
+        (1.0 - saturation) * weights[col] + if row == col { saturation } else { 0.0 }
+    }))
+}
+
+fn oklab_matrix(saturation: f64) -> Matrix3 {
+    let to_lab = LMS_TO_LAB * RGB_TO_LMS;
+    let scale = Matrix3::diagonal([1.0, saturation, saturation]);
+    // Both are well conditioned, the inverse always exists.
+    to_lab.inverse().unwrap() * scale * to_lab
+}
+
+pub fn apply(matrix: &[f64; 9], color: &[f64; 3]) -> [f64; 3] {
+    Matrix3(*matrix).apply(color)
+}
+
+fn chroma(color: &[f64; 3]) -> f64 {
+    let max = color.iter().copied().fold(f64::MIN, f64::max);
+    let min = color.iter().copied().fold(f64::MAX, f64::min);
+    max - min
+}
+
+fn algorithm_matrix(saturation: f64, algorithm: Algorithm) -> Matrix3 {
+    match algorithm {
+        Algorithm::Naive => naive_matrix(saturation),
+        Algorithm::Rec709 => luma_matrix(saturation, REC709_LUMA),
+        Algorithm::Oklab => oklab_matrix(saturation),
+    }
+}
+
+/// Finds the level that, applied to encoded values, gives the reference
+/// colors the same total chroma in linear light as applying the
+/// requested level to the linear values. Chroma grows with the level,
+/// so a bisection converges.
+fn compensated_saturation(saturation: f64, options: &MatrixOptions) -> f64 {
+    let transfer = options.gamma_compensation;
+    let decode = |color: [f64; 3]| color.map(|value| transfer.decode(value));
+
+    let target_matrix = algorithm_matrix(saturation, options.algorithm);
+    let target: f64 = REFERENCE_COLORS
+        .iter()
+        .map(|color| {
+            chroma(
+                &target_matrix
+                    .apply(&decode(*color))
+                    .map(|v| v.clamp(0.0, 1.0)),
+            )
+        })
+        .sum();
+    let linear_chroma = |level: f64| -> f64 {
+        let matrix = algorithm_matrix(level, options.algorithm);
+        REFERENCE_COLORS
+            .iter()
+            .map(|color| chroma(&decode(matrix.apply(color))))
+            .sum()
+    };
+
+    let (mut low, mut high) = (units::SATURATION_MIN, units::SATURATION_MAX);
+    for _ in 0..40 {
+        let mid = (low + high) / 2.0;
+        if linear_chroma(mid) < target {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Largest magnitude of a `wl_fixed`, the 24.8 fixed point type the
+/// matrix values are sent as.
+const WL_FIXED_MAX: f64 = (i32::MAX as f64) / 256.0;
+
+/// Checks that every value of the matrix can be sent to the compositor
+/// as is. Hyprland requires finite values, and rejects negative ones
+/// unless patched (see hyprland-build).
+pub fn check_protocol_limits(matrix: &[f64; 9]) -> Result<(), String> {
+    match matrix
+        .iter()
+        .find(|value| !value.is_finite() || value.abs() > WL_FIXED_MAX)
+    {
+        Some(value) => Err(format!(
+            "{} can't be represented as a wl_fixed value",
+            value
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Channel gains of a blackbody at the given temperature, in Kelvin,
+/// from Tanner Helland's fit of the CIE tables, before normalizing.
+fn blackbody_rgb(kelvin: f64) -> [f64; 3] {
+    let t = kelvin / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let green = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    [red, green, blue].map(|value| value.clamp(0.0, 255.0))
+}
+
+/// Scales every channel so white looks like a blackbody at the given
+/// temperature, 6500K being the identity.
+pub fn temperature_matrix(kelvin: f64) -> Matrix3 {
+    let target = blackbody_rgb(kelvin);
+    let neutral = blackbody_rgb(units::TEMPERATURE_NEUTRAL);
+    Matrix3::diagonal([0, 1, 2].map(|channel| target[channel] / neutral[channel]))
+}
+
+/// Matrix replacing the saturation one, for rules used as filters.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    /// Every channel set to the lightness of the color, as the
+    /// algorithm defines it.
+    Grayscale,
+    /// Complementary hues with the same lightness, which is as close to
+    /// inverting colors as a matrix without an offset gets. Needs a
+    /// compositor accepting negative values.
+    Invert,
+    /// A literal matrix, e.g. from a calibration tool.
+    Matrix([f64; 9]),
+}
+
+impl FromStr for Effect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grayscale" => Ok(Effect::Grayscale),
+            "invert" => Ok(Effect::Invert),
+            _ => Err(format!(
+                "'{}' isn't a valid effect, use grayscale or invert",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Grayscale => write!(f, "grayscale"),
+            Effect::Invert => write!(f, "invert"),
+            Effect::Matrix(matrix) => {
+                write!(f, "{}", matrix.map(|value| value.to_string()).join(" "))
+            }
+        }
+    }
+}
+
+/// What an output is asked to show: a saturation level, along with the
+/// adjustments of the rule deciding it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Ctm {
+    pub saturation: f64,
+    /// Replaces the saturation matrix when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    /// White point, in Kelvin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Gain of every channel, 1.0 leaving colors untouched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f64>,
+    /// Gain of the luma, 1.0 leaving colors untouched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f64>,
+}
+
+/// Scales every channel by the same gain.
+pub fn brightness_matrix(brightness: f64) -> Matrix3 {
+    Matrix3::diagonal([brightness; 3])
+}
+
+/// Scales the Rec.709 luma of colors by the gain, adding the same
+/// amount to every channel so that their chroma stays the same.
+pub fn contrast_matrix(contrast: f64) -> Matrix3 {
+    Matrix3(std::array::from_fn(|i| {
+        let (row, col) = (i / 3, i % 3);
+        (contrast - 1.0) * REC709_LUMA[col] + if row == col { 1.0 } else { 0.0 }
+    }))
+}
+
+impl Ctm {
+    pub fn saturation(saturation: f64) -> Ctm {
+        Ctm {
+            saturation,
+            effect: None,
+            temperature: None,
+            brightness: None,
+            contrast: None,
+        }
+    }
+
+    /// The matrix to send: the one of the rule, with the base matrix
+    /// applied after it.
+    pub fn matrix(&self, options: &MatrixOptions) -> [f64; 9] {
+        let mut matrix = match self.effect {
+            Some(Effect::Grayscale) => algorithm_matrix(0.0, options.algorithm),
+            Some(Effect::Invert) => algorithm_matrix(-1.0, options.algorithm),
+            Some(Effect::Matrix(matrix)) => Matrix3(matrix),
+            None => rule_saturation_matrix(self.saturation, options),
+        };
+        if let Some(kelvin) = self.temperature {
+            matrix = temperature_matrix(kelvin) * matrix;
+        }
+        if let Some(contrast) = self.contrast {
+            matrix = contrast_matrix(contrast) * matrix;
+        }
+        if let Some(brightness) = self.brightness {
+            matrix = brightness_matrix(brightness) * matrix;
+        }
+        (options.base() * matrix).into()
+    }
+}
+
+/// Saturation matrix for the level, composed with the base matrix.
+pub fn saturation_matrix(saturation: f64, options: &MatrixOptions) -> [f64; 9] {
+    (options.base() * rule_saturation_matrix(saturation, options)).into()
+}
+
+/// Scales how far every row of the matrix moves from the identity by
+/// the weight of its channel. Rows keep summing to the same value, so
+/// grays stay untouched.
+fn weighted(matrix: Matrix3, weights: [f64; 3]) -> Matrix3 {
+    let identity = Matrix3::IDENTITY.0;
+    Matrix3(std::array::from_fn(|i| {
+        identity[i] + weights[i / 3] * (matrix.0[i] - identity[i])
+    }))
+}
+
+/// Checks that per-channel weights are usable, which they are as long
+/// as the matrix of the highest usual level stays representable.
+pub fn check_weights(weights: [f64; 3]) -> Result<[f64; 3], String> {
+    if let Some(weight) = weights
+        .iter()
+        .find(|weight| weight.is_nan() || **weight < 0.0)
+    {
+        return Err(format!("weights can't be negative, got {}", weight));
+    }
+    for algorithm in [Algorithm::Naive, Algorithm::Rec709, Algorithm::Oklab] {
+        let matrix = weighted(algorithm_matrix(units::SATURATION_MAX, algorithm), weights);
+        check_protocol_limits(&matrix.0)?;
+    }
+    Ok(weights)
+}
+
+/// Parses weights given as 3 numbers for red, green and blue, separated
+/// by commas or whitespace.
+pub fn parse_weights(value: &str) -> Result<[f64; 3], String> {
+    let values = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("invalid weight '{}'", value))
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+    let weights = values
+        .try_into()
+        .map_err(|values: Vec<f64>| format!("expected 3 weights, got {}", values.len()))?;
+    check_weights(weights)
+}
+
+fn rule_saturation_matrix(saturation: f64, options: &MatrixOptions) -> Matrix3 {
+    let saturation = match options.gamma_compensation {
+        Transfer::None => saturation,
+        _ if saturation == 1.0 => saturation,
+        // The search only covers the usual range, extreme levels are
+        // taken as they are.
+        _ if !(units::SATURATION_MIN..=units::SATURATION_MAX).contains(&saturation) => saturation,
+        _ => compensated_saturation(saturation, options),
+    };
+    let matrix = algorithm_matrix(saturation, options.algorithm);
+    match options.weights {
+        Some(weights) => weighted(matrix, weights),
+        None => matrix,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [Algorithm; 3] = [Algorithm::Naive, Algorithm::Rec709, Algorithm::Oklab];
+
+    fn assert_close(a: &[f64; 3], b: &[f64; 3]) {
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-6, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn luma(color: &[f64; 3]) -> f64 {
+        (0..3).map(|i| REC709_LUMA[i] * color[i]).sum()
+    }
+
+    #[test]
+    fn luma_matrix_keeps_greys_and_luma() {
+        let matrix = luma_matrix(2.5, REC709_LUMA);
+        for grey in [0.0, 0.5, 1.0] {
+            assert_close(&matrix.apply(&[grey; 3]), &[grey; 3]);
+        }
+
+        let color = [0.8, 0.3, 0.1];
+        let boosted = matrix.apply(&color);
+        assert!(chroma(&boosted) > chroma(&color));
+        assert!((luma(&boosted) - luma(&color)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn every_algorithm_keeps_greys_and_scales_chroma() {
+        let color = [0.8, 0.5, 0.4];
+        for algorithm in ALGORITHMS {
+            assert_close(&algorithm_matrix(1.0, algorithm).apply(&color), &color);
+            for saturation in [0.0, 0.5, 2.0, 4.0] {
+                let matrix = algorithm_matrix(saturation, algorithm);
+                assert_close(&matrix.apply(&[0.5; 3]), &[0.5; 3]);
+            }
+
+            let gray = algorithm_matrix(0.0, algorithm).apply(&color);
+            assert!(chroma(&gray) < 1e-6, "{:?}: {:?}", algorithm, gray);
+            let muted = chroma(&algorithm_matrix(0.5, algorithm).apply(&color));
+            let boosted = chroma(&algorithm_matrix(2.0, algorithm).apply(&color));
+            assert!(muted < chroma(&color) && chroma(&color) < boosted);
+        }
+    }
+
+    #[test]
+    fn neutral_temperature_is_identity() {
+        assert_eq!(
+            temperature_matrix(units::TEMPERATURE_NEUTRAL),
+            Matrix3::IDENTITY
+        );
+        let warm = temperature_matrix(3000.0).apply(&[1.0; 3]);
+        assert!(warm[0] > warm[1] && warm[1] > warm[2]);
+        let cool = temperature_matrix(10000.0).apply(&[1.0; 3]);
+        assert!(cool[2] > cool[0]);
+    }
+
+    #[test]
+    fn gamma_compensation_tones_down_boosts() {
+        for algorithm in ALGORITHMS {
+            let mut options = MatrixOptions {
+                algorithm,
+                ..Default::default()
+            };
+            let plain = Ctm::saturation(2.0).matrix(&options);
+            options.gamma_compensation = Transfer::Srgb;
+            let color = [0.8, 0.5, 0.4];
+            let identity = Ctm::saturation(1.0).matrix(&options);
+            assert_close(&apply(&identity, &color), &color);
+
+            let compensated = compensated_saturation(2.0, &options);
+            assert!(1.0 < compensated && compensated < 2.0, "{}", compensated);
+            let matrix = Ctm::saturation(2.0).matrix(&options);
+            assert!(chroma(&apply(&matrix, &color)) < chroma(&apply(&plain, &color)));
+        }
+    }
+
+    #[test]
+    fn contrast_scales_luma_only() {
+        let matrix = contrast_matrix(1.5);
+        assert_close(&matrix.apply(&[0.4; 3]), &[0.6; 3]);
+
+        let color = [0.8, 0.3, 0.1];
+        let contrasted = matrix.apply(&color);
+        assert!((luma(&contrasted) - 1.5 * luma(&color)).abs() < 1e-9);
+        assert!((chroma(&contrasted) - chroma(&color)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,114 @@
+//! Shell completion scripts. The static part is generated by clap,
+//! while the values that depend on the session, like output names, are
+//! completed by calling back into the binary with `--complete <KIND>`,
+//! which prints one candidate per line.
+
+use std::io::Write;
+
+use clap_complete::Shell;
+
+const BIN_NAME: &str = "hyprland-vibrance";
+
+/// Candidates `--complete` can print.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    /// Names of the outputs, e.g. DP-1.
+    Outputs,
+    /// App ids of the open windows.
+    AppIds,
+}
+
+impl CompletionKind {
+    fn arg(&self) -> &'static str {
+        match self {
+            CompletionKind::Outputs => "outputs",
+            CompletionKind::AppIds => "app-ids",
+        }
+    }
+}
+
+/// Long options whose values are completed from the session, along
+/// with their value name in the generated scripts.
+const DYNAMIC_OPTIONS: &[(&str, &str, CompletionKind)] = &[
+    ("output", "OUTPUT", CompletionKind::Outputs),
+    ("app-id-match", "APP_ID", CompletionKind::AppIds),
+];
+
+fn bash_hook() -> String {
+    let mut cases = String::new();
+    for (option, _, kind) in DYNAMIC_OPTIONS {
+        cases.push_str(&format!(
+            "        --{option})\n            COMPREPLY=($(compgen -W \"$({BIN_NAME} --complete {kind} 2>/dev/null)\" -- \"${{cur}}\"))\n            return 0\n            ;;\n",
+            kind = kind.arg()
+        ));
+    }
+
+    // Clap names its function after the binary, with dashes doubled.
+    let function = format!("_{}", BIN_NAME.replace('-', "__"));
+    format!(
+        r#"
+{function}_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "${{prev}}" in
+{cases}    esac
+    {function} "$@"
+}}
+complete -F {function}_dynamic -o bashdefault -o default {BIN_NAME}
+"#
+    )
+}
+
+fn fish_hook() -> String {
+    DYNAMIC_OPTIONS
+        .iter()
+        .map(|(option, _, kind)| {
+            format!(
+                "complete -c {BIN_NAME} -l {option} -f -a \"({BIN_NAME} --complete {kind} 2>/dev/null)\"\n",
+                kind = kind.arg()
+            )
+        })
+        .collect()
+}
+
+/// Points the zsh value completions of the dynamic options to helper
+/// functions calling back into the binary.
+fn zsh_patch(script: String) -> String {
+    let mut helpers = String::new();
+    let mut script = script;
+    for (_, value_name, kind) in DYNAMIC_OPTIONS {
+        let helper = format!("_{BIN_NAME}_{}", kind.arg().replace('-', "_"));
+        script = script.replace(
+            &format!(":{value_name}:_default'"),
+            &format!(":{value_name}:{helper}'"),
+        );
+        helpers.push_str(&format!(
+            "{helper}() {{\n    local -a candidates\n    candidates=(${{(f)\"$({BIN_NAME} --complete {kind} 2>/dev/null)\"}})\n    compadd -a candidates\n}}\n\n",
+            kind = kind.arg()
+        ));
+    }
+
+    // The helpers have to be defined before the script calls its main
+    // function, which it does right at the end.
+    match script.rfind("\nif [ \"$funcstack[1]\" = ") {
+        Some(position) => {
+            script.insert_str(position + 1, &helpers);
+            script
+        }
+        None => script + "\n" + &helpers,
+    }
+}
+
+/// Writes the completion script for the given shell.
+pub fn print_script(shell: Shell, command: &mut clap::Command, out: &mut impl Write) {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, command, BIN_NAME, &mut script);
+    let script = String::from_utf8_lossy(&script).into_owned();
+
+    let script = match shell {
+        Shell::Bash => script + &bash_hook(),
+        Shell::Fish => script + &fish_hook(),
+        Shell::Zsh => zsh_patch(script),
+        _ => script,
+    };
+    let _ = out.write_all(script.as_bytes());
+}
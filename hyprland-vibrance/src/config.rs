@@ -0,0 +1,273 @@
+//! Declarative configuration, read from
+//! `$XDG_CONFIG_HOME/hyprland-vibrance/config.toml` (or the file given
+//! with `--config`):
+//!
+//! ```toml
+//! aggregate = true
+//! algorithm = "rec709"
+//! workspace_sat = ["3=1.2", "games=1.5"]
+//!
+//! [[rule]]
+//! name = "games"
+//! title = ["Counter-Strike 2", "Deadlock"]
+//! sat = 3.3
+//! other_sat = 0.8
+//!
+//! [[rule]]
+//! initial_class = "mpv"
+//! min_coverage = "80%"
+//! sat = "150%"
+//! ```
+//!
+//! Options take the same values as the command line flags of the same
+//! name, which override them. Rules take the same keys as `--rule`,
+//! where keys that can be repeated take a list, and come after the
+//! ones given on the command line.
+
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::rules::Rule;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub aggregate: Option<bool>,
+    pub all_outputs: Option<bool>,
+    pub per_output_focus: Option<bool>,
+    pub keep_under_special: Option<bool>,
+    pub hyprland_tags: Option<bool>,
+    pub no_global_shortcuts: Option<bool>,
+    pub follow_cursor: Option<bool>,
+    pub cursor_poll_interval: Option<u64>,
+    pub warm_up: Option<u64>,
+    pub focus_grace: Option<u64>,
+    pub transition: Option<u64>,
+    pub transition_easing: Option<String>,
+    #[serde(default)]
+    pub workspace_sat: Vec<String>,
+    #[serde(default)]
+    pub output_sat: Vec<String>,
+    #[serde(default)]
+    pub output_max_sat: Vec<String>,
+    #[serde(default)]
+    pub cycle_sat: Vec<toml::Value>,
+    pub auto_fullscreen: Option<toml::Value>,
+    pub default_saturation: Option<toml::Value>,
+    pub algorithm: Option<String>,
+    pub gamma_compensation: Option<String>,
+    pub base_matrix: Option<Vec<f64>>,
+    pub weights: Option<Vec<f64>>,
+    pub outputs: Option<Outputs>,
+    pub skip_hdr: Option<bool>,
+    pub hdr_scale: Option<toml::Value>,
+    pub no_presets: Option<bool>,
+    pub track_usage: Option<bool>,
+    pub fifo: Option<PathBuf>,
+    pub osd: Option<bool>,
+    pub dbus: Option<bool>,
+    pub notify: Option<bool>,
+    pub tray: Option<bool>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<toml::Spanned<toml::Table>>,
+    /// Line every rule starts at, from the spans of [`Config::rules`].
+    #[serde(skip)]
+    rule_lines: Vec<usize>,
+}
+
+/// The `[outputs]` table, the same as `--include-output` and
+/// `--exclude-output`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Outputs {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(config_dir.join("hyprland-vibrance").join("config.toml"))
+}
+
+/// Renders a TOML value the way it'd be written on the command line.
+pub fn value_to_string(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(if *b { "yes" } else { "no" }.to_string()),
+        _ => Err(format!("{} isn't a string, number or boolean", value)),
+    }
+}
+
+fn parse_rule(table: &toml::Table) -> Result<Rule, String> {
+    let mut pairs: Vec<(&str, String)> = Vec::new();
+    for (key, value) in table.iter() {
+        match value {
+            toml::Value::Array(values) => {
+                for value in values {
+                    pairs.push((key, value_to_string(value)?));
+                }
+            }
+            value => pairs.push((key, value_to_string(value)?)),
+        }
+    }
+    Rule::from_pairs(pairs.iter().map(|(key, value)| (*key, value.as_str())))
+}
+
+impl Config {
+    /// Loads the config at the given path. Without an explicit path,
+    /// the default file is optional.
+    pub fn load(path: Option<&Path>) -> Result<Option<(Config, PathBuf)>, String> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(None),
+            },
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let config = Config::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some((config, path)))
+    }
+
+    fn parse(contents: &str) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        config.rule_lines = config
+            .rules
+            .iter()
+            .map(|table| contents[..table.span().start].matches('\n').count() + 1)
+            .collect();
+        Ok(config)
+    }
+
+    /// Every rule of the config along with the line it starts at.
+    fn spanned_rules(&self) -> impl Iterator<Item = (&toml::Table, usize)> {
+        self.rules
+            .iter()
+            .zip(self.rule_lines.iter())
+            .map(|(table, line)| (table.get_ref(), *line))
+    }
+
+    /// Every invalid rule of the config, along with the line it starts
+    /// at, where [`Config::named_rules`] only reports the first one.
+    pub fn rule_errors(&self) -> Vec<(usize, String)> {
+        self.spanned_rules()
+            .filter_map(|(table, line)| parse_rule(table).err().map(|e| (line, e)))
+            .collect()
+    }
+
+    /// The rules of the config in precedence order, loaded from `path`,
+    /// along with where each of them comes from, as `path:line`.
+    pub fn named_rules(&self, path: &Path) -> Result<Vec<(Rule, String)>, String> {
+        self.spanned_rules()
+            .map(|(table, line)| {
+                let origin = format!("{}:{}", path.display(), line);
+                match parse_rule(table) {
+                    Ok(rule) => Ok((rule, origin)),
+                    Err(e) => Err(format!("{}: {}", origin, e)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+aggregate = true
+algorithm = "rec709"
+
+[[rule]]
+name = "games"
+title = ["Counter-Strike 2", "Deadlock"]
+sat = 3.3
+
+[[rule]]
+initial_class = "mpv"
+min_coverage = "80%"
+visible = true
+sat = "150%"
+"#;
+
+    #[test]
+    fn rules_come_with_their_line() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.aggregate, Some(true));
+        assert_eq!(config.algorithm.as_deref(), Some("rec709"));
+
+        let rules = config.named_rules(Path::new("config.toml")).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0],
+            (
+                "name=games title='Counter-Strike 2' title=Deadlock sat=3.3"
+                    .parse()
+                    .unwrap(),
+                "config.toml:5".to_string()
+            )
+        );
+        assert_eq!(rules[1].1, "config.toml:10");
+        assert!(rules[1].0.visible);
+        assert_eq!(rules[1].0.min_coverage, Some(0.8));
+    }
+
+    #[test]
+    fn reports_every_invalid_rule() {
+        let config = Config::parse(
+            "[[rule]]\ntitle = \"a\"\n\n[[rule]]\ntitle = \"b\"\nsat = 2\n\n[[rule]]\nsat = [1]\n",
+        )
+        .unwrap();
+        let errors = config.rule_errors();
+        assert_eq!(
+            errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            [1, 8]
+        );
+        assert!(
+            config
+                .named_rules(Path::new("config.toml"))
+                .unwrap_err()
+                .starts_with("config.toml:1: ")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(Config::parse("agregate = true").is_err());
+        assert!(Config::parse("aggregate = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn values_render_as_on_the_command_line() {
+        assert_eq!(
+            value_to_string(&toml::Value::Boolean(true)),
+            Ok("yes".to_string())
+        );
+        assert_eq!(
+            value_to_string(&toml::Value::Float(1.5)),
+            Ok("1.5".to_string())
+        );
+        assert_eq!(
+            value_to_string(&toml::Value::Integer(3)),
+            Ok("3".to_string())
+        );
+        assert!(value_to_string(&toml::Value::Array(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,481 @@
+//! Control socket of the daemon. Clients connect to
+//! `$XDG_RUNTIME_DIR/hyprland-vibrance.sock` and send newline
+//! delimited JSON requests, each of them answered with a single JSON
+//! response line. After a `subscribe` request the connection also
+//! receives a `Status` line every time the state of the daemon
+//! changes.
+//!
+//! For example, `{"command":"set","saturation":2.5}` replaces the level
+//! of the matched rules until `{"command":"reset"}`, `pause`, `resume`
+//! and `toggle` stop and resume applying any saturation, `reload` reads
+//! the rules again and `status` reports the state of every output.
+
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppState, color, units};
+
+const SOCKET_NAME: &str = "hyprland-vibrance.sock";
+
+/// Longest request line accepted. Clients sending more without a
+/// newline are dropped instead of growing their buffer forever.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// Identifies the connected clients, so what they force can be undone
+/// when they go away.
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    EnableRule {
+        name: String,
+    },
+    DisableRule {
+        name: String,
+    },
+    /// Adds a rule, given in the same format as `--rule`, with the
+    /// lowest precedence among the rules of its priority or, with
+    /// `first`, the highest one.
+    AddRule {
+        rule: String,
+        #[serde(default)]
+        first: bool,
+    },
+    RemoveRule {
+        name: String,
+    },
+    ListRules,
+    /// Changes the level of the rule at the given index.
+    SetRuleSaturation {
+        index: usize,
+        saturation: f64,
+    },
+    /// Moves the rule at `index` so it ends up at index `to`.
+    MoveRule {
+        index: usize,
+        to: usize,
+    },
+    /// Replaces the level of the matched rules until `reset`.
+    Set {
+        saturation: f64,
+    },
+    Reset,
+    /// Stops applying any saturation until `resume`.
+    Pause,
+    Resume,
+    /// Switches between paused and resumed.
+    Toggle,
+    /// Reads the rules again from the config file and the rules file.
+    Reload,
+    /// Resets every CTM and exits, for the instance replacing this one.
+    Quit,
+    Status,
+    /// Same as `status`, and keeps sending the status on every change.
+    Subscribe,
+    /// Applies the given level to every output, regardless of the
+    /// rules, until the connection is closed.
+    Force {
+        saturation: f64,
+    },
+}
+
+/// A rule as reported by `list_rules`, in precedence order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleStatus {
+    /// The rule in the same format as `--rule`.
+    pub rule: String,
+    pub name: Option<String>,
+    pub enabled: bool,
+    /// Level of the rule on an output without a base level.
+    pub saturation: f64,
+    /// Whether the rule matches the focused window, or the session
+    /// state for rules without window conditions.
+    pub focused: bool,
+    /// Whether the rule matches any window that's not minimized.
+    pub open: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputStatus {
+    pub name: String,
+    /// Description the compositor gives the output, usually its make
+    /// and model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub saturation: f64,
+    /// Filter applied instead of the saturation, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<color::Effect>,
+    /// White point of the applied CTM, in Kelvin, if shifted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Brightness gain of the applied CTM, if scaled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f64>,
+    /// Contrast gain of the applied CTM, if scaled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f64>,
+    /// Applied CTM, none for the identity.
+    pub matrix: Option<[f64; 9]>,
+    /// What decided the level of the output.
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TopLevelStatus {
+    pub title: Option<String>,
+    pub app_id: Option<String>,
+    pub focused: bool,
+    pub minimized: bool,
+    pub fullscreen: bool,
+    #[serde(default)]
+    pub maximized: bool,
+    /// Position of the window in its Hyprland group, e.g. `2/3`, if
+    /// it's grouped and some rule needs groups.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_tab: Option<String>,
+    pub outputs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub paused: bool,
+    /// Level replacing the one of the matched rules, set with `set`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation_override: Option<f64>,
+    pub disabled_by_file: bool,
+    pub outputs: Vec<OutputStatus>,
+    pub top_levels: Vec<TopLevelStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Problems that didn't prevent the request from succeeding.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<RuleStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl Response {
+    fn ok(warnings: Vec<String>) -> Response {
+        Response {
+            ok: true,
+            error: None,
+            warnings,
+            rules: None,
+            status: None,
+        }
+    }
+
+    fn error(message: String) -> Response {
+        Response {
+            ok: false,
+            error: Some(message),
+            warnings: Vec::new(),
+            rules: None,
+            status: None,
+        }
+    }
+}
+
+pub fn socket_path() -> io::Result<PathBuf> {
+    let runtime_dir = env::var("XDG_RUNTIME_DIR")
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+    Ok(PathBuf::from(runtime_dir).join(SOCKET_NAME))
+}
+
+/// Identifier for a new client, owning what it forces.
+pub fn new_client_id() -> usize {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn handle_request(state: &mut AppState, client: usize, request: Request) -> Response {
+    debug!("Received control request: {:?}", request);
+    let result = match request {
+        Request::EnableRule { name } => state.set_rule_enabled(&name, true).map(|_| Vec::new()),
+        Request::DisableRule { name } => state.set_rule_enabled(&name, false).map(|_| Vec::new()),
+        Request::AddRule { rule, first } => {
+            rule.parse().and_then(|rule| state.add_rule(rule, first))
+        }
+        Request::RemoveRule { name } => state.remove_rule(&name).map(|_| Vec::new()),
+        Request::ListRules => {
+            return Response {
+                rules: Some(state.rule_statuses()),
+                ..Response::ok(Vec::new())
+            };
+        }
+        Request::SetRuleSaturation { index, saturation } => state
+            .set_rule_saturation(index, saturation)
+            .map(|_| Vec::new()),
+        Request::MoveRule { index, to } => state.move_rule(index, to).map(|_| Vec::new()),
+        Request::Set { saturation } => units::check_saturation(saturation).map(|saturation| {
+            state.saturation_override = Some(saturation);
+            Vec::new()
+        }),
+        Request::Reset => {
+            state.saturation_override = None;
+            Ok(Vec::new())
+        }
+        Request::Pause => {
+            state.set_paused(true);
+            Ok(Vec::new())
+        }
+        Request::Resume => {
+            state.set_paused(false);
+            Ok(Vec::new())
+        }
+        Request::Toggle => {
+            state.set_paused(!state.paused);
+            Ok(Vec::new())
+        }
+        Request::Reload => state.reload_rules(),
+        Request::Quit => {
+            info!("Asked to exit, resetting every CTM");
+            state.quitting = true;
+            Ok(Vec::new())
+        }
+        Request::Force { saturation } => units::check_saturation(saturation).map(|saturation| {
+            state.forced_saturations.retain(|(id, _)| *id != client);
+            state.forced_saturations.push((client, saturation));
+            Vec::new()
+        }),
+        Request::Status | Request::Subscribe => {
+            return Response {
+                status: Some(state.status()),
+                ..Response::ok(Vec::new())
+            };
+        }
+    };
+
+    match result {
+        Ok(warnings) => Response::ok(warnings),
+        Err(e) => Response::error(e),
+    }
+}
+
+/// Handles a request line, also telling whether the client asked to
+/// subscribe to status changes.
+fn handle_line(state: &mut AppState, client: usize, line: &[u8]) -> (Response, bool) {
+    match serde_json::from_slice(line) {
+        Ok(request) => {
+            let subscribe = matches!(request, Request::Subscribe);
+            (handle_request(state, client, request), subscribe)
+        }
+        Err(e) => (Response::error(format!("invalid request: {}", e)), false),
+    }
+}
+
+/// Sends the current status to the subscribed clients, if it changed
+/// since the last time. Clients that can't keep up are dropped.
+pub fn publish_status(state: &mut AppState) {
+    if state.subscribers.is_empty() {
+        state.last_status = None;
+        return;
+    }
+
+    let mut encoded = serde_json::to_string(&state.status()).unwrap();
+    encoded.push('\n');
+    if state.last_status.as_ref() == Some(&encoded) {
+        return;
+    }
+
+    state.subscribers.retain(|mut stream| {
+        let written = stream.write_all(encoded.as_bytes());
+        if let Err(e) = &written {
+            debug!("Dropping status subscriber: {}", e);
+        }
+        written.is_ok()
+    });
+    state.last_status = Some(encoded);
+}
+
+fn insert_client(handle: &LoopHandle<'_, AppState>, stream: UnixStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let client = new_client_id();
+    let mut buffer: Vec<u8> = Vec::new();
+    handle
+        .insert_source(
+            Generic::new(stream, Interest::READ, Mode::Level),
+            move |_, stream, state| {
+                let mut stream: &UnixStream = stream;
+                let mut chunk = [0u8; 1024];
+                let closed = loop {
+                    match stream.read(&mut chunk) {
+                        Ok(0) => break true,
+                        Ok(read) => {
+                            buffer.extend_from_slice(&chunk[..read]);
+                            if buffer.len() > MAX_REQUEST_LEN {
+                                break false;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            debug!("Control client read failed: {}", e);
+                            break true;
+                        }
+                    }
+                };
+
+                while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    let (response, subscribe) = handle_line(state, client, &line);
+                    let mut encoded = serde_json::to_vec(&response).unwrap();
+                    encoded.push(b'\n');
+
+                    // Responses are tiny, so they aren't queued: a
+                    // client not reading them is dropped, the same as
+                    // the status subscribers that can't keep up.
+                    if let Err(e) = stream.write_all(&encoded) {
+                        debug!("Control client write failed: {}", e);
+                        state.forced_saturations.retain(|(id, _)| *id != client);
+                        return Ok(PostAction::Remove);
+                    }
+
+                    if subscribe {
+                        state.subscribers.push(stream.try_clone()?);
+                        // The response already has the current status.
+                        state.last_status =
+                            Some(serde_json::to_string(&state.status()).unwrap() + "\n");
+                    }
+                }
+
+                if buffer.len() > MAX_REQUEST_LEN {
+                    debug!("Dropping control client sending a too long request");
+                    state.forced_saturations.retain(|(id, _)| *id != client);
+                    return Ok(PostAction::Remove);
+                }
+
+                Ok(if closed {
+                    state.forced_saturations.retain(|(id, _)| *id != client);
+                    PostAction::Remove
+                } else {
+                    PostAction::Continue
+                })
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Opens the control socket and registers it in the event loop.
+pub fn listen(handle: &LoopHandle<'_, AppState>) -> io::Result<()> {
+    let path = socket_path()?;
+
+    // A leftover socket from a previous instance would make the
+    // binding fail.
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+    info!("Listening for control requests on {}", path.display());
+
+    let client_handle = handle.clone();
+    handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            move |_, listener, _| {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = insert_client(&client_handle, stream) {
+                                error!("Couldn't register control client: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            error!("Couldn't accept control client: {}", e);
+                            break;
+                        }
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Sends a single request to the running daemon and waits for its
+/// response.
+pub fn send(request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    let mut encoded = serde_json::to_vec(request).map_err(io::Error::other)?;
+    encoded.push(b'\n');
+    stream.write_all(&encoded)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(io::Error::other)
+}
+
+/// Forces the given level on every output of the running daemon. It
+/// lasts until the returned connection is closed, including when this
+/// process dies.
+pub fn force(saturation: f64) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    let mut encoded =
+        serde_json::to_vec(&Request::Force { saturation }).map_err(io::Error::other)?;
+    encoded.push(b'\n');
+    stream.write_all(&encoded)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line).map_err(io::Error::other)?;
+    if !response.ok {
+        return Err(io::Error::other(response.error.unwrap_or_default()));
+    }
+    Ok(stream)
+}
+
+/// Subscribes to the status of the running daemon, calling `on_status`
+/// with the current status and then with every change, until the
+/// daemon goes away or `on_status` returns false.
+pub fn subscribe(mut on_status: impl FnMut(Status) -> bool) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    let mut encoded = serde_json::to_vec(&Request::Subscribe).map_err(io::Error::other)?;
+    encoded.push(b'\n');
+    stream.write_all(&encoded)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let first = lines
+        .next()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+    let response: Response = serde_json::from_str(&first).map_err(io::Error::other)?;
+    let Some(status) = response.status else {
+        return Err(io::Error::other(
+            response
+                .error
+                .unwrap_or_else(|| "no status in response".to_string()),
+        ));
+    };
+    if !on_status(status) {
+        return Ok(());
+    }
+
+    for line in lines {
+        let status: Status = serde_json::from_str(&line?).map_err(io::Error::other)?;
+        if !on_status(status) {
+            break;
+        }
+    }
+    Ok(())
+}
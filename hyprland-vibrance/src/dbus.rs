@@ -0,0 +1,233 @@
+//! D-Bus service, `dev.devcexx.HyprlandVibrance` on the session bus,
+//! for desktop tooling that speaks D-Bus rather than the control
+//! socket. Methods are forwarded to the event loop as control requests,
+//! so they behave exactly like their socket counterparts:
+//!
+//! - `SetSaturation(d)`, `Reset()`: same as `set` and `reset`.
+//! - `Pause()`, `Resume()` and `Toggle() -> b`, the latter returning
+//!   whether the daemon ends up paused.
+//! - `Reload() -> as`, returning the warnings of the new rules.
+//! - `GetState() -> (ba(sds))`: whether the daemon is paused, and the
+//!   name, level and level source of every output.
+//!
+//! The `Paused` and `MatchedRule` properties emit `PropertiesChanged`.
+//! `MatchedRule` is the name of the rule deciding the level of some
+//! output, or the rule itself if it has no name, and empty when no rule
+//! applies.
+
+use std::sync::{Arc, Mutex, mpsc};
+
+use calloop::{LoopHandle, channel};
+use log::{debug, error, info};
+use zbus::{fdo, interface};
+
+use crate::{
+    AppState, LevelSource,
+    control::{self, Request, Response, Status},
+    engine,
+};
+
+const BUS_NAME: &str = "dev.devcexx.HyprlandVibrance";
+const OBJECT_PATH: &str = "/dev/devcexx/HyprlandVibrance";
+
+/// What the properties and `GetState` report, as of the last event
+/// loop iteration.
+#[derive(Debug, Default)]
+struct Published {
+    status: Option<Status>,
+    matched_rule: String,
+}
+
+/// Name, level and level source of an output, as sent by `GetState`.
+type OutputState = (String, f64, String);
+
+struct Service {
+    requests: channel::Sender<(Request, mpsc::Sender<Response>)>,
+    published: Arc<Mutex<Published>>,
+}
+
+impl Service {
+    /// Runs the request in the event loop and waits for its response.
+    fn call(&self, request: Request) -> fdo::Result<Response> {
+        let (sender, receiver) = mpsc::channel();
+        self.requests
+            .send((request, sender))
+            .map_err(|_| fdo::Error::Failed("the daemon is exiting".to_string()))?;
+        let response = receiver
+            .recv()
+            .map_err(|_| fdo::Error::Failed("the daemon is exiting".to_string()))?;
+        if response.ok {
+            Ok(response)
+        } else {
+            Err(fdo::Error::Failed(response.error.unwrap_or_default()))
+        }
+    }
+}
+
+#[interface(name = "dev.devcexx.HyprlandVibrance")]
+impl Service {
+    fn set_saturation(&self, saturation: f64) -> fdo::Result<()> {
+        self.call(Request::Set { saturation }).map(|_| ())
+    }
+
+    fn reset(&self) -> fdo::Result<()> {
+        self.call(Request::Reset).map(|_| ())
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.call(Request::Pause).map(|_| ())
+    }
+
+    fn resume(&self) -> fdo::Result<()> {
+        self.call(Request::Resume).map(|_| ())
+    }
+
+    fn toggle(&self) -> fdo::Result<bool> {
+        self.call(Request::Toggle)?;
+        let response = self.call(Request::Status)?;
+        Ok(response.status.is_some_and(|status| status.paused))
+    }
+
+    fn reload(&self) -> fdo::Result<Vec<String>> {
+        self.call(Request::Reload).map(|response| response.warnings)
+    }
+
+    fn get_state(&self) -> fdo::Result<(bool, Vec<OutputState>)> {
+        let status = self
+            .call(Request::Status)?
+            .status
+            .ok_or_else(|| fdo::Error::Failed("no status".to_string()))?;
+        Ok((
+            status.paused,
+            status
+                .outputs
+                .into_iter()
+                .map(|output| {
+                    (
+                        output.name,
+                        output.saturation,
+                        output.source.unwrap_or_default(),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.published
+            .lock()
+            .unwrap()
+            .status
+            .as_ref()
+            .is_some_and(|status| status.paused)
+    }
+
+    #[zbus(property)]
+    fn matched_rule(&self) -> String {
+        self.published.lock().unwrap().matched_rule.clone()
+    }
+}
+
+/// Connection to the session bus, owning the service name.
+#[derive(Debug)]
+pub struct Bus {
+    connection: zbus::blocking::Connection,
+    published: Arc<Mutex<Published>>,
+}
+
+/// Registers the service on the session bus, handling its requests in
+/// the event loop.
+pub fn start(handle: &LoopHandle<'_, AppState>) -> Result<Bus, String> {
+    let (requests, receiver) = channel::channel::<(Request, mpsc::Sender<Response>)>();
+    let client = control::new_client_id();
+    handle
+        .insert_source(receiver, move |event, _, state| {
+            if let channel::Event::Msg((request, sender)) = event {
+                let _ = sender.send(control::handle_request(state, client, request));
+            }
+        })
+        .map_err(|e| e.error.to_string())?;
+
+    let published = Arc::new(Mutex::new(Published::default()));
+    let service = Service {
+        requests,
+        published: published.clone(),
+    };
+    let connection = zbus::blocking::connection::Builder::session()
+        .and_then(|builder| builder.name(BUS_NAME))
+        .and_then(|builder| builder.serve_at(OBJECT_PATH, service))
+        .and_then(|builder| builder.build())
+        .map_err(|e| e.to_string())?;
+    info!("Registered {} on the session bus", BUS_NAME);
+
+    Ok(Bus {
+        connection,
+        published,
+    })
+}
+
+/// Name of the rule deciding the level of some output, the one taking
+/// precedence if there are several.
+fn matched_rule(state: &AppState) -> String {
+    let rule = state
+        .level_sources
+        .iter()
+        .filter_map(|(_, source)| match source {
+            LevelSource::Rule(rule) => Some(*rule),
+            _ => None,
+        })
+        .min_by(|a, b| engine::compare(&state.rules, *a, *b));
+    rule.and_then(|rule| state.rules.get(rule))
+        .map(|rule| rule.name.clone().unwrap_or_else(|| rule.to_string()))
+        .unwrap_or_default()
+}
+
+/// Updates the properties, emitting `PropertiesChanged` for the ones
+/// that changed.
+pub fn publish(state: &AppState) {
+    let Some(bus) = state.dbus.as_ref() else {
+        return;
+    };
+
+    let status = state.status();
+    let matched_rule = matched_rule(state);
+    let (paused_changed, matched_rule_changed) = {
+        let mut published = bus.published.lock().unwrap();
+        let paused_changed =
+            published.status.as_ref().map(|status| status.paused) != Some(status.paused);
+        let matched_rule_changed = published.matched_rule != matched_rule;
+        published.status = Some(status);
+        published.matched_rule = matched_rule;
+        (paused_changed, matched_rule_changed)
+    };
+    if !paused_changed && !matched_rule_changed {
+        return;
+    }
+
+    let iface = match bus
+        .connection
+        .object_server()
+        .interface::<_, Service>(OBJECT_PATH)
+    {
+        Ok(iface) => iface,
+        Err(e) => {
+            error!("Couldn't find the D-Bus interface: {}", e);
+            return;
+        }
+    };
+    let service = iface.get();
+    let emitter = iface.signal_emitter();
+    let result = zbus::block_on(async {
+        if paused_changed {
+            service.paused_changed(emitter).await?;
+        }
+        if matched_rule_changed {
+            service.matched_rule_changed(emitter).await?;
+        }
+        zbus::Result::Ok(())
+    });
+    if let Err(e) = result {
+        debug!("Couldn't emit D-Bus property changes: {}", e);
+    }
+}
@@ -0,0 +1,228 @@
+//! Resolution of the rule set: which rule applies when several match
+//! the same top level.
+//!
+//! The rule with the highest `priority` wins. Among rules with the same
+//! priority, the one given first wins, so without priorities the first
+//! matching rule applies. Rules given first are the ones from
+//! `--title-match`, then `--rule`, `--rules-file` and the config file,
+//! in that order.
+
+use std::cmp::Ordering;
+
+use crate::{
+    TopLevelInfo,
+    rules::{Context, Rule},
+};
+
+/// Orders rules by precedence, the one taking precedence first.
+pub fn compare(rules: &[Rule], a: usize, b: usize) -> Ordering {
+    rules[b]
+        .priority
+        .cmp(&rules[a].priority)
+        .then_with(|| a.cmp(&b))
+}
+
+/// Indices of the rules in precedence order.
+pub fn precedence_order(rules: &[Rule]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..rules.len()).collect();
+    order.sort_by(|a, b| compare(rules, *a, *b));
+    order
+}
+
+/// Returns the index of the rule taking precedence among the ones the
+/// predicate accepts.
+pub fn resolve(rules: &[Rule], accepts: impl Fn(&Rule) -> bool) -> Option<usize> {
+    (0..rules.len())
+        .filter(|idx| accepts(&rules[*idx]))
+        .min_by(|a, b| compare(rules, *a, *b))
+}
+
+/// Returns the index of the rule applying to the top level.
+pub fn best_match(rules: &[Rule], top_level: &TopLevelInfo, context: &Context) -> Option<usize> {
+    resolve(rules, |rule| rule.matches(top_level, context))
+}
+
+/// Returns the index of the rule applying to the top level among the
+/// ones applying while it's on an output, focused or not.
+pub fn best_visible_match(
+    rules: &[Rule],
+    top_level: &TopLevelInfo,
+    context: &Context,
+) -> Option<usize> {
+    resolve(rules, |rule| {
+        rule.visible && rule.matches(top_level, context)
+    })
+}
+
+/// Returns the index of the rule without window conditions applying
+/// in the given context.
+pub fn best_global_match(rules: &[Rule], context: &Context) -> Option<usize> {
+    resolve(rules, |rule| {
+        !rule.is_window_rule() && rule.matches_context(context)
+    })
+}
+
+/// Looks for rules that can never apply because a rule taking
+/// precedence matches everything they match, and for rules repeating
+/// the conditions of such a rule. Returns the index of every offending
+/// rule along with the index of the rule shadowing it and a
+/// description.
+pub fn diagnose(rules: &[Rule]) -> Vec<(usize, usize, String)> {
+    let order = precedence_order(rules);
+    let mut diagnostics = Vec::new();
+    for (position, &idx) in order.iter().enumerate() {
+        let rule = &rules[idx];
+        let Some(&winner) = order[..position]
+            .iter()
+            .find(|winner| rules[**winner].covers(rule))
+        else {
+            continue;
+        };
+
+        let message = if !rules[winner].same_conditions(rule) {
+            "never applies, every window it matches is matched by a rule taking precedence"
+                .to_string()
+        } else if rules[winner].describe_level() == rule.describe_level()
+            && rules[winner].other_saturation == rule.other_saturation
+        {
+            "duplicates a rule taking precedence".to_string()
+        } else {
+            format!(
+                "conflicts with a rule with the same conditions taking precedence, {} is used instead of {}",
+                rules[winner].describe_level(),
+                rule.describe_level()
+            )
+        };
+        diagnostics.push((idx, winner, message));
+    }
+    diagnostics.sort_by_key(|(idx, _, _)| *idx);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(spec: &str) -> Rule {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn first_rule_wins_without_priorities() {
+        let rules = [rule("title=a sat=2"), rule("title=a sat=3")];
+        assert_eq!(resolve(&rules, |_| true), Some(0));
+        assert_eq!(resolve(&rules, |rule| rule.saturation == 3.0), Some(1));
+        assert_eq!(resolve(&rules, |_| false), None);
+    }
+
+    #[test]
+    fn highest_priority_wins() {
+        let rules = [
+            rule("title=a sat=2"),
+            rule("title=a sat=3 priority=10"),
+            rule("title=a sat=4 priority=10"),
+            rule("title=a sat=1 priority=-1"),
+        ];
+        assert_eq!(resolve(&rules, |_| true), Some(1));
+        assert_eq!(precedence_order(&rules), vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn global_rules_follow_priorities() {
+        let rules = [
+            rule("submap=gaming sat=2"),
+            rule("title=a sat=3 priority=5"),
+            rule("submap=gaming sat=4 priority=1"),
+        ];
+        let gaming = Context {
+            submap: Some("gaming"),
+            ..Default::default()
+        };
+        assert_eq!(best_global_match(&rules, &gaming), Some(2));
+        assert_eq!(best_global_match(&rules, &Context::default()), None);
+    }
+
+    #[test]
+    fn workspace_rules_match_by_name_or_id() {
+        let rules = [rule("workspace=games sat=2"), rule("workspace=3 sat=3")];
+        let workspace = |id: i64, name: &str| crate::hyprland::WorkspaceRef {
+            id,
+            name: name.to_string(),
+        };
+        let on = |workspace: &crate::hyprland::WorkspaceRef| {
+            best_global_match(
+                &rules,
+                &Context {
+                    workspace: Some(workspace),
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(on(&workspace(5, "games")), Some(0));
+        assert_eq!(on(&workspace(3, "3")), Some(1));
+        assert_eq!(on(&workspace(1, "1")), None);
+    }
+
+    #[test]
+    fn diagnoses_rules_shadowed_by_precedence() {
+        let rules = [
+            rule("title=a sat=2"),
+            rule("title=a title=b sat=3 priority=1"),
+            rule("title=b sat=3"),
+        ];
+        let diagnostics = diagnose(&rules);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!((diagnostics[0].0, diagnostics[0].1), (0, 1));
+        assert!(diagnostics[0].2.starts_with("never applies"));
+        assert_eq!((diagnostics[1].0, diagnostics[1].1), (2, 1));
+    }
+
+    #[test]
+    fn diagnoses_duplicates_and_conflicts() {
+        let rules = [
+            rule("title=a sat=2"),
+            rule("title=a sat=2"),
+            rule("title=a sat=3"),
+        ];
+        let diagnostics = diagnose(&rules);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].2, "duplicates a rule taking precedence");
+        assert!(diagnostics[1].2.starts_with("conflicts"));
+    }
+
+    #[test]
+    fn output_rules_only_cover_their_outputs() {
+        let rules = [rule("title=a output=DP-1 sat=2"), rule("title=a sat=3")];
+        assert!(diagnose(&rules).is_empty());
+
+        let rules = [rule("title=a sat=2"), rule("title=a output=DP-1 sat=3")];
+        let diagnostics = diagnose(&rules);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].0, diagnostics[0].1), (1, 0));
+    }
+
+    #[test]
+    fn state_only_rules_apply_to_windows_in_that_state() {
+        let rules = [
+            rule("require_fullscreen=yes sat=2"),
+            rule("require_maximized=yes sat=3"),
+        ];
+        let context = Context::default();
+        assert_eq!(best_global_match(&rules, &context), None);
+
+        let mut top_level = TopLevelInfo::inert();
+        assert_eq!(best_match(&rules, &top_level, &context), None);
+        top_level.maximized = true;
+        assert_eq!(best_match(&rules, &top_level, &context), Some(1));
+        top_level.fullscreen = true;
+        assert_eq!(best_match(&rules, &top_level, &context), Some(0));
+    }
+
+    #[test]
+    fn lower_priority_never_shadows() {
+        let rules = [rule("title=a sat=2 priority=-1"), rule("title=a sat=3")];
+        let diagnostics = diagnose(&rules);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].0, diagnostics[0].1), (0, 1));
+    }
+}
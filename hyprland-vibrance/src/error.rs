@@ -0,0 +1,70 @@
+//! Errors ending the daemon or the subcommands talking to the
+//! compositor, each with its own exit status so supervisors and scripts
+//! can tell a broken config from a compositor that went away:
+//!
+//! | Status | Error                                              |
+//! |--------|----------------------------------------------------|
+//! | 1      | anything else, like another instance running       |
+//! | 2      | invalid config file, rules or flags, as with clap  |
+//! | 3      | the Wayland display is unreachable, or hung up     |
+//! | 4      | a protocol the compositor doesn't advertise        |
+//! | 5      | a protocol error                                   |
+
+use std::fmt;
+
+use log::error;
+use wayland_client::{DispatchError, backend::WaylandError};
+
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Connection(String),
+    MissingGlobal(String),
+    Protocol(String),
+    Other(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Config(_) => 2,
+            Error::Connection(_) => 3,
+            Error::MissingGlobal(_) => 4,
+            Error::Protocol(_) => 5,
+        }
+    }
+
+    /// Logs the error and exits with its status.
+    pub fn exit(&self) -> ! {
+        error!("{}", self);
+        std::process::exit(self.exit_code());
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message)
+            | Error::Connection(message)
+            | Error::MissingGlobal(message)
+            | Error::Protocol(message)
+            | Error::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The compositor hanging up shows as an I/O error, anything it
+/// rejected as a protocol error.
+impl From<DispatchError> for Error {
+    fn from(error: DispatchError) -> Self {
+        match error {
+            DispatchError::Backend(WaylandError::Io(e)) => {
+                Error::Connection(format!("Wayland connection failed: {}", e))
+            }
+            e => Error::Protocol(format!("Wayland protocol error: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Last resort for the colors. Every output given a matrix is recorded
+//! until it's cleared again, and a panic on the main thread puts the
+//! identity back on all of them before the process dies, instead of
+//! leaving the screen boosted until something else resets it.
+//!
+//! This runs from the panic hook, and again from a guard dropped while
+//! unwinding in case the hook was replaced. Panics on other threads
+//! (the tray, notifications) don't end the process, so they leave the
+//! outputs alone.
+
+use std::{
+    panic,
+    sync::{Mutex, PoisonError, TryLockError},
+    thread,
+};
+
+use wayland_client::{Connection, Proxy, protocol::wl_output::WlOutput};
+use wayland_protocols_hyprland::ctm_control::v1::client::hyprland_ctm_control_manager_v1::HyprlandCtmControlManagerV1;
+
+/// Outputs left with a matrix, along with the manager it was set
+/// through.
+static TOUCHED: Mutex<Vec<(HyprlandCtmControlManagerV1, WlOutput)>> = Mutex::new(Vec::new());
+
+/// Records that the output was given a matrix.
+pub fn touch(control: &HyprlandCtmControlManagerV1, output: &WlOutput) {
+    let mut touched = TOUCHED.lock().unwrap_or_else(PoisonError::into_inner);
+    if !touched.iter().any(|(_, handle)| handle == output) {
+        touched.push((control.clone(), output.clone()));
+    }
+}
+
+/// Records that the output is back to the identity.
+pub fn untouch(output: &WlOutput) {
+    TOUCHED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|(_, handle)| handle != output);
+}
+
+/// Forgets every output, once the connection they belong to is gone.
+pub fn forget_all() {
+    TOUCHED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// Clears every output still having a matrix, committing and flushing
+/// right away since nothing else is going to.
+fn restore() {
+    let mut touched = match TOUCHED.try_lock() {
+        Ok(touched) => touched,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        // The panic came from inside touch or untouch, waiting would
+        // never end.
+        Err(TryLockError::WouldBlock) => return,
+    };
+
+    let mut controls: Vec<HyprlandCtmControlManagerV1> = Vec::new();
+    for (control, output) in touched.drain(..) {
+        control.set_ctm_for_output(&output, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0);
+        if !controls.contains(&control) {
+            controls.push(control);
+        }
+    }
+    for control in controls {
+        control.commit();
+        if let Some(backend) = control.backend().upgrade() {
+            let _ = Connection::from_backend(backend).flush();
+        }
+    }
+}
+
+/// Resets the outputs when dropped while panicking.
+pub struct Guard(());
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            restore();
+        }
+    }
+}
+
+/// Installs the panic hook, chained before the one already set, and
+/// returns the guard to keep alive for as long as the process runs.
+pub fn install() -> Guard {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().name() == Some("main") {
+            restore();
+        }
+        previous(info);
+    }));
+    Guard(())
+}
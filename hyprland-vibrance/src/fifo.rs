@@ -0,0 +1,172 @@
+//! Named pipe accepting one command per line, for scripts that just
+//! want to `echo 2.5 > /path/to/fifo`:
+//!
+//! - A saturation level (`2.5`, `150%`) replaces the level of the
+//!   matched rules until `reset` is written.
+//! - `off` and `on` stop and resume applying any saturation, and
+//!   `toggle` switches between both.
+
+use std::{
+    ffi::CString,
+    fs::OpenOptions,
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, fs::OpenOptionsExt},
+    path::Path,
+    str::FromStr,
+};
+
+use calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic};
+use log::{debug, error, info, warn};
+
+use crate::{AppState, units};
+
+/// Longest command line accepted. Longer lines are dropped, up to their
+/// newline, instead of growing the buffer forever.
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FifoCommand {
+    Set(f64),
+    Reset,
+    On,
+    Off,
+    Toggle,
+}
+
+impl FromStr for FifoCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reset" => Ok(FifoCommand::Reset),
+            "on" => Ok(FifoCommand::On),
+            "off" => Ok(FifoCommand::Off),
+            "toggle" => Ok(FifoCommand::Toggle),
+            level => units::parse_saturation(level).map(FifoCommand::Set),
+        }
+    }
+}
+
+fn handle_command(state: &mut AppState, command: FifoCommand) {
+    debug!("Received FIFO command: {:?}", command);
+    match command {
+        FifoCommand::Set(saturation) => state.saturation_override = Some(saturation),
+        FifoCommand::Reset => state.saturation_override = None,
+        FifoCommand::On => state.set_paused(false),
+        FifoCommand::Off => state.set_paused(true),
+        FifoCommand::Toggle => state.set_paused(!state.paused),
+    }
+}
+
+fn create_fifo(path: &Path) -> io::Result<()> {
+    match path.metadata() {
+        Ok(metadata) if metadata.file_type().is_fifo() => return Ok(()),
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a FIFO", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: c_path is a valid NUL terminated string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates the FIFO, if it doesn't exist yet, and registers it in the
+/// event loop.
+pub fn listen(handle: &LoopHandle<'_, AppState>, path: &Path) -> io::Result<()> {
+    create_fifo(path)?;
+
+    // Opening it for writing too keeps a writer around, otherwise
+    // every writer closing the pipe would make it permanently readable
+    // with an end of file.
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    info!("Listening for commands on {}", path.display());
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut dropping = false;
+    handle
+        .insert_source(
+            Generic::new(fifo, Interest::READ, Mode::Level),
+            move |_, fifo, state| {
+                let mut fifo: &std::fs::File = fifo;
+                let mut chunk = [0u8; 1024];
+                loop {
+                    match fifo.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(read) => {
+                            let mut read = &chunk[..read];
+                            if dropping {
+                                let Some(newline) = read.iter().position(|&b| b == b'\n') else {
+                                    continue;
+                                };
+                                read = &read[newline + 1..];
+                                dropping = false;
+                            }
+                            buffer.extend_from_slice(read);
+                            let line_start = buffer
+                                .iter()
+                                .rposition(|&b| b == b'\n')
+                                .map_or(0, |i| i + 1);
+                            if buffer.len() - line_start > MAX_LINE_LEN {
+                                warn!("Dropping a FIFO line longer than {} bytes", MAX_LINE_LEN);
+                                buffer.truncate(line_start);
+                                dropping = true;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            error!("Stopped reading the FIFO: {}", e);
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+
+                while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+
+                    match line.parse() {
+                        Ok(command) => handle_command(state, command),
+                        Err(e) => warn!("Ignoring FIFO command '{}': {}", line, e),
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands_and_levels() {
+        assert_eq!("reset".parse(), Ok(FifoCommand::Reset));
+        assert_eq!("on".parse(), Ok(FifoCommand::On));
+        assert_eq!("off".parse(), Ok(FifoCommand::Off));
+        assert_eq!("toggle".parse(), Ok(FifoCommand::Toggle));
+        assert_eq!("2.5".parse(), Ok(FifoCommand::Set(2.5)));
+        assert_eq!("150%".parse(), Ok(FifoCommand::Set(1.5)));
+        assert!("lots".parse::<FifoCommand>().is_err());
+        assert!("-1".parse::<FifoCommand>().is_err());
+    }
+}
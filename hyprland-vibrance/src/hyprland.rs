@@ -0,0 +1,489 @@
+//! Minimal client for the Hyprland IPC sockets. The request socket
+//! (`.socket.sock`) is used for one-off JSON queries, while the event
+//! socket (`.socket2.sock`) streams `EVENT>>DATA` lines that give us
+//! information the Wayland protocols don't expose, like the active
+//! workspace of each monitor.
+
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use serde::{Deserialize, de::DeserializeOwned};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceRef {
+    pub id: i64,
+    pub name: String,
+}
+
+impl WorkspaceRef {
+    /// Whether the reference is the id or the name of this workspace.
+    pub fn is(&self, reference: &str) -> bool {
+        reference == self.name || reference == self.id.to_string()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Monitor {
+    pub id: i64,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub scale: f64,
+    pub transform: i32,
+    pub active_workspace: WorkspaceRef,
+    #[serde(default)]
+    pub focused: bool,
+    /// From the EDID, empty when the monitor doesn't report one.
+    #[serde(default)]
+    pub serial: String,
+    /// `hdr` or `hdredid` when the monitor runs in HDR mode. Missing
+    /// before Hyprland supported HDR.
+    #[serde(default)]
+    pub color_management_preset: String,
+    /// Id of the monitor this one mirrors, or `none`.
+    #[serde(default)]
+    pub mirror_of: String,
+    /// False while the monitor is turned off with `dpms off`.
+    #[serde(default = "default_dpms_status")]
+    pub dpms_status: bool,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+fn default_dpms_status() -> bool {
+    true
+}
+
+impl Monitor {
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.color_management_preset.as_str(), "hdr" | "hdredid")
+    }
+
+    /// Whether the monitor is turned off or disabled, in which case
+    /// nothing sent to it is shown.
+    pub fn is_asleep(&self) -> bool {
+        !self.dpms_status || self.disabled
+    }
+
+    /// Name of the monitor this one mirrors, if any.
+    pub fn mirrored<'a>(&self, monitors: &'a [Monitor]) -> Option<&'a str> {
+        let id: i64 = self.mirror_of.parse().ok()?;
+        monitors
+            .iter()
+            .find(|monitor| monitor.id == id)
+            .map(|monitor| monitor.name.as_str())
+    }
+
+    /// Whether the given point, in the global logical coordinates used
+    /// by Hyprland, lies within this monitor.
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let (width, height) = self.logical_size();
+        (self.x..self.x + width).contains(&x) && (self.y..self.y + height).contains(&y)
+    }
+
+    /// Size of the monitor in the global logical coordinates, which is
+    /// what window positions and sizes are given in.
+    pub fn logical_size(&self) -> (i32, i32) {
+        // Odd transforms rotate the monitor by 90 or 270 degrees.
+        let (width, height) = if self.transform % 2 == 1 {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        };
+        (
+            (width as f64 / self.scale).round() as i32,
+            (height as f64 / self.scale).round() as i32,
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Client {
+    pub address: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub class: String,
+    pub initial_class: String,
+    pub initial_title: String,
+    #[serde(default)]
+    pub pid: i32,
+    #[serde(default)]
+    pub floating: bool,
+    #[serde(default)]
+    pub xwayland: bool,
+    pub at: [i32; 2],
+    pub size: [i32; 2],
+    /// Id of the monitor the window is on.
+    pub monitor: i64,
+    /// Addresses of the windows in the group (tabs) of the window, in
+    /// tab order, empty if it isn't grouped.
+    #[serde(default)]
+    pub grouped: Vec<String>,
+    #[serde(default)]
+    pub workspace: Option<WorkspaceRef>,
+    /// Tags given by window rules. Hyprland appends a `*` to the ones
+    /// set dynamically.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Hyprland formats window addresses as hex strings (`0x55d0...`).
+fn parse_address(address: &str) -> Option<u64> {
+    u64::from_str_radix(address.trim_start_matches("0x"), 16).ok()
+}
+
+impl Client {
+    pub fn address(&self) -> Option<u64> {
+        parse_address(&self.address)
+    }
+
+    /// Whether the window is on a special workspace (a scratchpad),
+    /// which Hyprland gives negative ids.
+    pub fn is_on_special_workspace(&self) -> bool {
+        self.workspace
+            .as_ref()
+            .is_some_and(|workspace| workspace.id < 0 || workspace.name.starts_with("special"))
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .map(|tag| tag.trim_end_matches('*').to_string())
+            .collect()
+    }
+
+    pub fn group(&self) -> Vec<u64> {
+        self.grouped
+            .iter()
+            .filter_map(|address| parse_address(address))
+            .collect()
+    }
+
+    /// Fraction of its monitor the window covers, from 0.0 to 1.0.
+    pub fn coverage(&self, monitors: &[Monitor]) -> Option<f64> {
+        let monitor = monitors.iter().find(|monitor| monitor.id == self.monitor)?;
+        let (width, height) = monitor.logical_size();
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+
+        let overlap = |start: i32, len: i32, monitor_start: i32, monitor_len: i32| {
+            ((start + len).min(monitor_start + monitor_len) - start.max(monitor_start)).max(0)
+        };
+        let covered = overlap(self.at[0], self.size[0], monitor.x, width) as f64
+            * overlap(self.at[1], self.size[1], monitor.y, height) as f64;
+        Some(covered / (width as f64 * height as f64))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct CursorPos {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Value of a config option, as returned by `getoption`. Only the
+/// integer representation is used for now.
+#[derive(Deserialize, Debug, Clone)]
+struct OptionValue {
+    int: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub name: String,
+    pub data: String,
+}
+
+impl Event {
+    /// Address of the window getting the focus, for `activewindowv2`
+    /// events. Focus moving to an empty workspace has none.
+    pub fn active_window_address(&self) -> Option<u64> {
+        (self.name == "activewindowv2")
+            .then(|| parse_address(&self.data))
+            .flatten()
+    }
+
+    fn parse(line: &str) -> Option<Event> {
+        let (name, data) = line.split_once(">>")?;
+        Some(Event {
+            name: name.to_string(),
+            data: data.to_string(),
+        })
+    }
+}
+
+/// Instance picked explicitly, taking precedence over the environment.
+/// Replaced when reconnecting to a restarted compositor.
+static INSTANCE_SIGNATURE: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_instance_signature(signature: String) {
+    *INSTANCE_SIGNATURE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = Some(signature);
+}
+
+/// Signature of the Hyprland instance being talked to.
+pub fn instance_signature() -> Option<String> {
+    INSTANCE_SIGNATURE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .or_else(|| env::var("HYPRLAND_INSTANCE_SIGNATURE").ok())
+}
+
+fn socket_dir() -> io::Result<PathBuf> {
+    let signature = instance_signature().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "HYPRLAND_INSTANCE_SIGNATURE is not set",
+        )
+    })?;
+
+    // Hyprland moved its sockets from /tmp to the runtime dir at
+    // some point. Prefer the new location but keep supporting the
+    // old one.
+    if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
+        let dir = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+        if dir.exists() {
+            return Ok(dir);
+        }
+    }
+
+    Ok(PathBuf::from("/tmp/hypr").join(signature))
+}
+
+/// Sends a raw command through the request socket and returns the
+/// whole response.
+pub fn request(command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_dir()?.join(".socket.sock"))?;
+    stream.write_all(command.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// Sends a command in JSON mode (`j/<command>`) and deserializes its
+/// response.
+pub fn request_json<T: DeserializeOwned>(command: &str) -> io::Result<T> {
+    let response = request(&format!("j/{}", command))?;
+    serde_json::from_str(&response).map_err(io::Error::other)
+}
+
+pub fn monitors() -> io::Result<Vec<Monitor>> {
+    request_json("monitors")
+}
+
+pub fn clients() -> io::Result<Vec<Client>> {
+    request_json("clients")
+}
+
+pub fn cursor_pos() -> io::Result<CursorPos> {
+    request_json("cursorpos")
+}
+
+pub fn option_int(name: &str) -> io::Result<Option<i64>> {
+    request_json::<OptionValue>(&format!("getoption {}", name)).map(|value| value.int)
+}
+
+/// Events after which the monitor list, including the active workspace
+/// of each monitor, may have changed.
+pub const MONITOR_EVENTS: &[&str] = &[
+    "workspace",
+    "workspacev2",
+    "focusedmon",
+    "focusedmonv2",
+    "moveworkspace",
+    "moveworkspacev2",
+    "monitoradded",
+    "monitoraddedv2",
+    "monitorremoved",
+    "monitorremovedv2",
+];
+
+/// Events after which windows may have been opened or closed, or have a
+/// different size, position or group. Hyprland doesn't report plain
+/// resizes, those are picked up on the next focus change.
+pub const CLIENT_EVENTS: &[&str] = &[
+    "openwindow",
+    "closewindow",
+    "activewindowv2",
+    "movewindow",
+    "movewindowv2",
+    "moveworkspace",
+    "moveworkspacev2",
+    "changefloatingmode",
+    "fullscreen",
+    "focusedmon",
+    "monitoradded",
+    "monitorremoved",
+    "togglegroup",
+    "moveintogroup",
+    "moveoutofgroup",
+];
+
+impl Event {
+    pub fn is_monitor_event(&self) -> bool {
+        MONITOR_EVENTS.contains(&self.name.as_str())
+    }
+
+    pub fn is_client_event(&self) -> bool {
+        CLIENT_EVENTS.contains(&self.name.as_str())
+    }
+}
+
+/// Cache of the `clients` and `monitors` queries, so evaluating rules
+/// after every focus change doesn't cost several synchronous requests.
+/// Entries are dropped on the events that can make them stale, which
+/// only works with the event socket connected: without it, every query
+/// goes to Hyprland.
+#[derive(Debug, Default)]
+pub struct Cache {
+    tracking: bool,
+    /// By window address.
+    clients: Option<HashMap<u64, Client>>,
+    monitors: Option<Vec<Monitor>>,
+}
+
+impl Cache {
+    /// Sets whether events are being fed to [`Cache::invalidate`].
+    pub fn set_tracking(&mut self, tracking: bool) {
+        self.tracking = tracking;
+        if !tracking {
+            self.clients = None;
+            self.monitors = None;
+        }
+    }
+
+    /// Drops every entry, for changes noticed before their events are
+    /// read, like an output being plugged in.
+    pub fn clear(&mut self) {
+        self.clients = None;
+        self.monitors = None;
+    }
+
+    pub fn invalidate(&mut self, events: &[Event]) {
+        if events.iter().any(Event::is_client_event) {
+            self.clients = None;
+        }
+        if events.iter().any(Event::is_monitor_event) {
+            self.monitors = None;
+        }
+    }
+
+    /// Drops the monitor list, for what changes without an event.
+    pub fn invalidate_monitors(&mut self) {
+        self.monitors = None;
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        if !self.tracking || self.clients.is_none() {
+            self.clients = Some(
+                clients()?
+                    .into_iter()
+                    .filter_map(|client| Some((client.address()?, client)))
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn monitors(&mut self) -> io::Result<&[Monitor]> {
+        if !self.tracking || self.monitors.is_none() {
+            self.monitors = Some(monitors()?);
+        }
+        Ok(self.monitors.as_deref().unwrap())
+    }
+
+    /// Queries the given window, which is probably new when it's not in
+    /// the cache yet.
+    pub fn client(&mut self, address: u64) -> io::Result<Option<&Client>> {
+        if self
+            .clients
+            .as_ref()
+            .is_some_and(|clients| !clients.contains_key(&address))
+        {
+            self.clients = None;
+        }
+        self.fill()?;
+        Ok(self.clients.as_ref().unwrap().get(&address))
+    }
+
+    /// Every window, by address.
+    pub fn clients(&mut self) -> io::Result<&HashMap<u64, Client>> {
+        self.fill()?;
+        Ok(self.clients.as_ref().unwrap())
+    }
+
+    pub fn clients_and_monitors(&mut self) -> io::Result<(&HashMap<u64, Client>, &[Monitor])> {
+        self.fill()?;
+        self.monitors()?;
+        Ok((
+            self.clients.as_ref().unwrap(),
+            self.monitors.as_deref().unwrap(),
+        ))
+    }
+}
+
+/// Non-blocking reader of the Hyprland event socket.
+pub struct EventStream {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    pub fn connect() -> io::Result<EventStream> {
+        let stream = UnixStream::connect(socket_dir()?.join(".socket2.sock"))?;
+        stream.set_nonblocking(true)?;
+        Ok(EventStream {
+            stream,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn stream(&self) -> &UnixStream {
+        &self.stream
+    }
+
+    /// Reads everything currently available in the socket and returns
+    /// the complete events received so far. Returns an error of kind
+    /// `UnexpectedEof` once Hyprland closes the socket.
+    pub fn read_events(&mut self) -> io::Result<Vec<Event>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Hyprland event socket closed",
+                    ));
+                }
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            if let Some(event) = Event::parse(String::from_utf8_lossy(&line).trim_end()) {
+                events.push(event);
+            }
+        }
+
+        Ok(events)
+    }
+}
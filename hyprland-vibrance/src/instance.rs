@@ -0,0 +1,91 @@
+//! Makes sure a single daemon drives the CTMs of a session. Two of them
+//! would keep overriding each other on every focus change, so the
+//! daemon holds an exclusive lock on `$XDG_RUNTIME_DIR/hyprland-vibrance.lock`
+//! for as long as it runs. The lock is released by the kernel when the
+//! process dies, so a crash never leaves a stale one behind.
+//!
+//! With `--replace`, the running instance is asked through the control
+//! socket to reset its CTMs and exit, and the new one takes over once the
+//! lock is free.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Write},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::control;
+
+const LOCK_FILE_NAME: &str = "hyprland-vibrance.lock";
+
+/// How long the running instance gets to exit when replaced.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn lock_path() -> io::Result<PathBuf> {
+    let runtime_dir = env::var("XDG_RUNTIME_DIR")
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+    Ok(PathBuf::from(runtime_dir).join(LOCK_FILE_NAME))
+}
+
+fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Takes the instance lock, asking the running instance to exit first
+/// when `replace` is set. The lock lasts as long as the returned file.
+pub fn acquire(replace: bool) -> Result<File, String> {
+    let path = lock_path().map_err(|e| format!("Couldn't lock the instance: {}", e))?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let locked = try_lock(&file).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !locked {
+        let pid = fs::read_to_string(&path).unwrap_or_default();
+        let pid = pid.trim();
+        let running = if pid.is_empty() {
+            "Another instance is already running".to_string()
+        } else {
+            format!("Another instance is already running (pid {})", pid)
+        };
+        if !replace {
+            return Err(format!("{}, use --replace to take over", running));
+        }
+
+        info!("{}, asking it to exit", running);
+        control::send(&control::Request::Quit)
+            .map_err(|e| format!("Couldn't ask the running instance to exit: {}", e))?;
+
+        let deadline = Instant::now() + REPLACE_TIMEOUT;
+        while !try_lock(&file).map_err(|e| format!("{}: {}", path.display(), e))? {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "The running instance didn't exit within {}s",
+                    REPLACE_TIMEOUT.as_secs()
+                ));
+            }
+            thread::sleep(REPLACE_POLL_INTERVAL);
+        }
+        info!("Took over from the previous instance");
+    }
+
+    // Only informative, for the message above.
+    file.set_len(0)
+        .and_then(|_| writeln!(file, "{}", std::process::id()))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(file)
+}
@@ -1,32 +1,99 @@
-use std::{borrow::Borrow, sync::Arc};
+mod color;
+mod completions;
+mod config;
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod engine;
+mod error;
+mod failsafe;
+mod fifo;
+mod hyprland;
+mod instance;
+mod matrix;
+mod migrate;
+#[cfg(feature = "notifications")]
+mod notifications;
+#[cfg(feature = "osd")]
+mod osd;
+#[cfg(feature = "osd")]
+mod pattern;
+mod permissions;
+mod persistence;
+mod presets;
+mod probe;
+mod process;
+mod reload;
+mod rules;
+mod session;
+mod shortcuts;
+mod signals;
+mod systemd;
+mod transition;
+#[cfg(feature = "tray")]
+mod tray;
+#[cfg(feature = "tui")]
+mod tui;
+mod units;
+mod usage;
+mod waybar;
 
-use clap::Parser;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use calloop::{
+    EventLoop, Interest, LoopHandle, Mode, PostAction,
+    generic::Generic,
+    timer::{TimeoutAction, Timer},
+};
+use calloop_wayland_source::WaylandSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, parser::ValueSource};
+use color::MatrixOptions;
 use derive_new::new;
-use log::{LevelFilter, debug, error, info};
+use error::Error;
+use log::{LevelFilter, debug, error, info, warn};
+use persistence::PersistedState;
+use rules::Rule;
 use wayland_client::{
-    Connection, Dispatch, Proxy, QueueHandle,
-    backend::{ObjectData, ObjectId},
+    Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle,
+    backend::{ObjectData, ObjectId, WaylandError},
     protocol::{
         wl_output::{self, WlOutput},
         wl_registry::{self},
     },
 };
-use wayland_protocols_hyprland::ctm_control::v1::client::hyprland_ctm_control_manager_v1::{
-    self, HyprlandCtmControlManagerV1,
+use wayland_protocols_hyprland::{
+    ctm_control::v1::client::hyprland_ctm_control_manager_v1::{self, HyprlandCtmControlManagerV1},
+    toplevel_mapping::v1::client::{
+        hyprland_toplevel_mapping_manager_v1::{self, HyprlandToplevelMappingManagerV1},
+        hyprland_toplevel_window_mapping_handle_v1::{self, HyprlandToplevelWindowMappingHandleV1},
+    },
 };
 use wayland_protocols_wlr::foreign_toplevel::v1::client::{
     zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
     zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
 };
 
-const HYPERLAND_CTM_CONTROL_MANAGER_IFACE: &str = "hyprland_ctm_control_manager_v1";
+pub(crate) const HYPERLAND_CTM_CONTROL_MANAGER_IFACE: &str = "hyprland_ctm_control_manager_v1";
+const HYPRLAND_TOPLEVEL_MAPPING_MANAGER_IFACE: &str = "hyprland_toplevel_mapping_manager_v1";
 const ZWLR_TOP_LEVEL_MANAGER_IFACE: &str = "zwlr_foreign_toplevel_manager_v1";
 const WL_OUTPUT_IFACE: &str = "wl_output";
 
+// Highest version of every protocol this program implements. Globals
+// are bound to the lowest of this and what the compositor advertises,
+// so a compositor moving to a newer version doesn't send requests or
+// events nothing here knows about.
+const CTM_CONTROL_MANAGER_VERSION: u32 = 2;
+const TOPLEVEL_MAPPING_MANAGER_VERSION: u32 = 1;
+const ZWLR_TOP_LEVEL_MANAGER_VERSION: u32 = 3;
+/// Outputs report their connector name from version 4 on.
+const WL_OUTPUT_VERSION: u32 = 4;
+
 #[derive(new, Debug, Clone)]
 struct Global {
     name: u32,
-    interface: String,
     version: u32,
 }
 
@@ -34,16 +101,86 @@ struct Global {
 struct TopLevelInfo {
     handle: ZwlrForeignToplevelHandleV1,
     title: Option<String>,
+    app_id: Option<String>,
+    minimized: bool,
+    fullscreen: bool,
+    maximized: bool,
     current_outputs: Vec<WlOutput>,
+    /// Hyprland window address, resolved through the toplevel
+    /// mapping protocol.
+    address: Option<u64>,
+    initial_title: Option<String>,
+    initial_class: Option<String>,
+    /// Current class, as opposed to the one the window had when it was
+    /// opened.
+    class: Option<String>,
+    pid: Option<i32>,
+    /// Executable name and command line of the process, from `/proc`.
+    process: Option<String>,
+    cmdline: Option<String>,
+    /// Flatpak app id and cgroup of the process.
+    flatpak: Option<String>,
+    cgroup: Option<String>,
+    /// Hyprland tags. Only kept up to date when some rule needs them.
+    tags: Vec<String>,
+    xwayland: bool,
+    /// Whether the window is gamescope's, which nests the game in it.
+    gamescope: bool,
+    /// Only kept up to date when some rule needs it.
+    floating: bool,
+    /// Only known when some rule needs it.
+    geometry: Option<WindowGeometry>,
+    /// Addresses of the windows in the Hyprland group of this one, in
+    /// tab order, itself included. Only known when some rule needs it.
+    group: Vec<u64>,
+    /// Whether the window was on a special workspace when it last got
+    /// the focus. Only known with --keep-under-special.
+    on_special_workspace: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WindowGeometry {
+    width: i32,
+    height: i32,
+    /// Fraction of its output covered by the window.
+    coverage: Option<f64>,
 }
 
 impl TopLevelInfo {
     pub fn new(handle: ZwlrForeignToplevelHandleV1) -> Self {
-        return Self {
+        Self {
             handle,
             title: None,
+            app_id: None,
+            minimized: false,
+            fullscreen: false,
+            maximized: false,
             current_outputs: Vec::new(),
-        };
+            address: None,
+            initial_title: None,
+            initial_class: None,
+            class: None,
+            pid: None,
+            process: None,
+            cmdline: None,
+            flatpak: None,
+            cgroup: None,
+            tags: Vec::new(),
+            xwayland: false,
+            gamescope: false,
+            floating: false,
+            geometry: None,
+            group: Vec::new(),
+            on_special_workspace: false,
+        }
+    }
+
+    /// A top level not backed by any compositor, for the tests.
+    #[cfg(test)]
+    pub fn inert() -> Self {
+        let (stream, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let backend = wayland_client::backend::Backend::connect(stream).unwrap();
+        Self::new(ZwlrForeignToplevelHandleV1::inert(backend.downgrade()))
     }
 }
 
@@ -60,38 +197,328 @@ impl TopLevelInfo {
     }
 }
 
+#[derive(Debug)]
+struct OutputInfo {
+    handle: WlOutput,
+    /// Name of the registry global the output was bound from.
+    global: u32,
+    /// Connector name, e.g. DP-1.
+    name: Option<String>,
+    description: Option<String>,
+    make: Option<String>,
+    model: Option<String>,
+    /// Only known through the Hyprland IPC, which is queried for it
+    /// when some reference needs it.
+    serial: Option<String>,
+    /// Whether Hyprland drives the output in HDR mode, only known when
+    /// some option depends on it.
+    hdr: bool,
+    /// Name of the output this one mirrors, as reported by Hyprland.
+    mirror_of: Option<String>,
+    /// Cap from the preset of the monitor model, if any.
+    max_saturation: Option<f64>,
+    /// Whether the output appeared after startup.
+    hotplugged: bool,
+    /// Whether Hyprland reports the output as turned off (DPMS) or
+    /// disabled. CTMs aren't sent to it meanwhile.
+    asleep: bool,
+}
+
+impl OutputInfo {
+    fn new(handle: WlOutput, global: u32) -> Self {
+        Self {
+            handle,
+            global,
+            name: None,
+            description: None,
+            make: None,
+            model: None,
+            serial: None,
+            hdr: false,
+            mirror_of: None,
+            max_saturation: None,
+            hotplugged: false,
+            asleep: false,
+        }
+    }
+
+    /// Whether the output is the one referred to by a config or rule,
+    /// either by connector name or by description, or by its EDID with
+    /// `make=...`, `model=...` or `serial=...`. All of them are announced
+    /// again whenever the output is bound, so references survive the
+    /// output being unplugged and plugged back, and EDID ones survive
+    /// connectors being renamed.
+    fn is(&self, reference: &str) -> bool {
+        let has = |value: &Option<String>, wanted: &str| value.as_deref() == Some(wanted);
+        match reference.split_once('=') {
+            Some(("make", make)) => has(&self.make, make),
+            Some(("model", model)) => has(&self.model, model),
+            Some(("serial", serial)) => has(&self.serial, serial),
+            _ => has(&self.name, reference) || has(&self.description, reference),
+        }
+    }
+}
+
+// Outputs the daemon is allowed to set a CTM on.
+#[derive(clap::Args, Clone, Debug, Default)]
+struct OutputFilter {
+    /// Only ever touch this output, by connector name or description.
+    /// Can be repeated
+    #[arg(long = "include-output", value_name = "OUTPUT")]
+    include: Vec<String>,
+
+    /// Never touch this output, e.g. a calibrated display, by connector
+    /// name or description. Can be repeated
+    #[arg(long = "exclude-output", value_name = "OUTPUT")]
+    exclude: Vec<String>,
+
+    /// Never touch the outputs Hyprland drives in HDR mode, where
+    /// saturation matrices meant for SDR look wrong
+    #[arg(long)]
+    skip_hdr: bool,
+}
+
+impl OutputFilter {
+    fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Outputs whose name isn't known yet are never allowed by a
+    /// filter, as they may turn out to be excluded.
+    fn allows(&self, output: &OutputInfo) -> bool {
+        if self.skip_hdr && output.hdr {
+            return false;
+        }
+        if self.is_empty() {
+            return true;
+        }
+        output.name.is_some()
+            && (self.include.is_empty()
+                || self.include.iter().any(|reference| output.is(reference)))
+            && !self.exclude.iter().any(|reference| output.is(reference))
+    }
+}
+
 struct TopLevelUserData;
 
 #[derive(Debug, Default)]
 struct InitAppState {
-    ctm_manager: Option<HyprlandCtmControlManagerV1>,
     top_level_manager_global: Option<Global>,
+    #[cfg(feature = "osd")]
+    compositor: Option<wayland_client::protocol::wl_compositor::WlCompositor>,
+    #[cfg(feature = "osd")]
+    shm: Option<wayland_client::protocol::wl_shm::WlShm>,
+    #[cfg(feature = "osd")]
+    layer_shell: Option<
+        wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_shell_v1::ZwlrLayerShellV1,
+    >,
 }
 
 #[derive(Debug, Default)]
 struct AppState {
     init: Option<Box<InitAppState>>,
+    /// Missing while Hyprland doesn't let us use the protocol, see
+    /// [`permissions`].
+    ctm_manager: Option<HyprlandCtmControlManagerV1>,
+    rules: Vec<Rule>,
+    persisted: PersistedState,
+    toplevel_mapping_manager: Option<HyprlandToplevelMappingManagerV1>,
+    outputs: Vec<OutputInfo>,
     top_levels: Vec<TopLevelInfo>,
+    hyprland_monitors: Vec<hyprland::Monitor>,
+    hyprland_cache: hyprland::Cache,
+    cursor_monitor: Option<String>,
+    /// Active Hyprland keybind submap, `None` for the default one.
+    submap: Option<String>,
+    #[cfg(feature = "osd")]
+    osd: Option<osd::Osd>,
+    #[cfg(feature = "osd")]
+    pattern: Option<pattern::TestPattern>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<dbus::Bus>,
+    #[cfg(feature = "notifications")]
+    notifier: Option<notifications::Notifier>,
+    #[cfg(feature = "tray")]
+    tray: Option<tray::Tray>,
     focused_top_level_object_id: Option<ObjectId>,
+    /// Every known top level that got the focus at some point, the one
+    /// focused last at the end.
+    focus_history: Vec<ObjectId>,
+    /// Set with --keep-under-special.
+    keep_under_special: bool,
+    /// Set with --hyprland-tags.
+    hyprland_tags: bool,
+    /// Whether to register the global shortcuts once the compositor
+    /// announces their manager.
+    global_shortcuts: bool,
+    /// Levels given with --cycle-sat.
+    cycle_levels: Vec<f64>,
+    /// Top level focused last outside of special workspaces, when
+    /// keeping its level under them.
+    regular_focus_object_id: Option<ObjectId>,
+    /// While paused every CTM is cleared and no new one is applied,
+    /// while top levels are still tracked so resuming is immediate.
+    paused: bool,
+    /// Set once asked to exit, which happens after resetting every CTM.
+    quitting: bool,
+    /// Saturation currently applied to every output without an
+    /// identity CTM.
+    applied_ctms: Vec<(WlOutput, color::Ctm)>,
+    /// Animates CTM changes, when enabled.
+    transitions: Option<transition::Transitions>,
+    /// Source of the level of every output, as of the last evaluation.
+    level_sources: Vec<(WlOutput, LevelSource)>,
+    /// Outputs whose level is capped, along with the level asked for,
+    /// as of the last evaluation.
+    capped: Vec<(WlOutput, f64)>,
+    matrix_options: MatrixOptions,
+    output_filter: OutputFilter,
+    /// Control clients receiving a status line on every change.
+    subscribers: Vec<std::os::unix::net::UnixStream>,
+    last_status: Option<String>,
+    /// Monitor model presets, empty when opted out.
+    presets: Vec<presets::Preset>,
+    /// Set while the disable file exists.
+    disabled_by_file: bool,
+    /// Level replacing the one of the matched rules, set at runtime.
+    saturation_override: Option<f64>,
+    /// Levels forced on every output by control clients, along with
+    /// the client that asked for them. The last one wins, and each is
+    /// dropped when its client disconnects.
+    forced_saturations: Vec<(usize, f64)>,
+    /// Rules file where the rules added at runtime are stored.
+    rules_write_back: Option<std::path::PathBuf>,
+    /// Modification time of the rules file after writing rules back.
+    rules_written_at: Option<std::time::SystemTime>,
+    /// Where the rules are reloaded from.
+    rule_sources: reload::RuleSources,
+    /// Programs going fullscreen without a rule, when tracked.
+    usage: Option<usage::Usage>,
+    /// Set until the warm-up delay is over. No CTM is committed
+    /// meanwhile.
+    warming_up: bool,
+    /// Levels applied by the previous run, restored as soon as their
+    /// output shows up.
+    restoring: Vec<persistence::AppliedOutput>,
+    /// Levels of rules that stopped matching, kept for the
+    /// `--focus-grace` period.
+    held_levels: Vec<HeldLevel>,
 }
 
 fn format_top_level(top_level: &TopLevelInfo) -> String {
     format!(
         "<{}>[{}]",
         top_level.handle.id(),
-        top_level
-            .title
-            .as_ref()
-            .map(|t| t.as_str())
-            .unwrap_or("<no title>")
+        top_level.title.as_deref().unwrap_or("<no title>")
     )
 }
 
 impl AppState {
+    /// Replaces the level of the matched rules with the next level of
+    /// --cycle-sat, or goes back to the levels of the rules after the
+    /// last one. Returns false if there are no levels to cycle through.
+    pub fn cycle_saturation(&mut self) -> bool {
+        if self.cycle_levels.is_empty() {
+            return false;
+        }
+        let next = match self
+            .saturation_override
+            .and_then(|current| self.cycle_levels.iter().position(|level| *level == current))
+        {
+            Some(idx) => self.cycle_levels.get(idx + 1).copied(),
+            None => self.cycle_levels.first().copied(),
+        };
+        match next {
+            Some(saturation) => info!("Switching to {}", saturation),
+            None => info!("Going back to the levels of the rules"),
+        }
+        self.saturation_override = next;
+        true
+    }
+
+    /// Pauses or resumes, taking effect on the next evaluation.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+        if paused {
+            info!("Paused, clearing every CTM");
+        } else {
+            info!("Resumed");
+        }
+    }
+
+    /// Whether the output passes --include-output and --exclude-output.
+    fn manages_output(&self, handle: &WlOutput) -> bool {
+        self.outputs
+            .iter()
+            .find(|output| &output.handle == handle)
+            .is_some_and(|output| self.output_filter.allows(output))
+    }
+
+    /// Looks up an output by its connector name (e.g. DP-1), or by its
+    /// description.
+    pub fn output_by_name(&self, name: &str) -> Result<WlOutput, String> {
+        if let Some(output) = self.outputs.iter().find(|output| output.is(name)) {
+            return Ok(output.handle.clone());
+        }
+
+        let names: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|output| self.format_output(&output.handle))
+            .collect();
+        Err(format!(
+            "Unknown output {}, available outputs are: {}",
+            name,
+            names.join(", ")
+        ))
+    }
+
+    pub fn format_output(&self, handle: &WlOutput) -> String {
+        self.outputs
+            .iter()
+            .find(|output| &output.handle == handle)
+            .and_then(|output| output.name.clone())
+            .unwrap_or_else(|| handle.id().to_string())
+    }
+
     fn index_of_top_level_for_object_id(&self, id: &ObjectId) -> Option<usize> {
         self.top_levels.iter().position(|e| &e.handle.id() == id)
     }
 
+    fn is_on_special_workspace(&mut self, handle: &ZwlrForeignToplevelHandleV1) -> bool {
+        let Some(address) = self
+            .index_of_top_level_for_object_id(&handle.id())
+            .and_then(|idx| self.top_levels[idx].address)
+        else {
+            return false;
+        };
+        match self.hyprland_cache.client(address) {
+            Ok(client) => client.is_some_and(hyprland::Client::is_on_special_workspace),
+            Err(e) => {
+                error!("Couldn't query Hyprland clients: {}", e);
+                false
+            }
+        }
+    }
+
+    /// The top level whose rule applies to its outputs: the focused one,
+    /// or with --keep-under-special, the one focused last outside of
+    /// special workspaces while a window on one has the focus.
+    pub fn rule_focus(&self) -> Option<&TopLevelInfo> {
+        let focused = self.focused_top_level()?;
+        if !self.keep_under_special || !focused.on_special_workspace {
+            return Some(focused);
+        }
+        self.regular_focus_object_id
+            .as_ref()
+            .and_then(|id| self.index_of_top_level_for_object_id(id))
+            .map(|idx| &self.top_levels[idx])
+            .filter(|top_level| !top_level.minimized)
+    }
+
     pub fn focused_top_level(&self) -> Option<&TopLevelInfo> {
         self.focused_top_level_object_id
             .as_ref()
@@ -119,16 +546,655 @@ impl AppState {
     ) {
         if focused {
             self.focused_top_level_object_id = Some(changed_handle.id());
+            self.focus_history.retain(|id| id != &changed_handle.id());
+            self.focus_history.push(changed_handle.id());
+            if self.keep_under_special {
+                let on_special_workspace = self.is_on_special_workspace(changed_handle);
+                if let Some(idx) = self.index_of_top_level_for_object_id(&changed_handle.id()) {
+                    self.top_levels[idx].on_special_workspace = on_special_workspace;
+                }
+                if !on_special_workspace {
+                    self.regular_focus_object_id = Some(changed_handle.id());
+                }
+            }
         } else if self.focused_top_level_object_id == Some(changed_handle.id()) {
             self.focused_top_level_object_id = None;
         }
     }
 
+    /// Records the Hyprland window address of a top level and fetches
+    /// the window properties that never change during its lifetime.
+    pub fn notify_top_level_address(&mut self, id: &ObjectId, address: u64) {
+        let Some(idx) = self.index_of_top_level_for_object_id(id) else {
+            return;
+        };
+
+        let top_level = &mut self.top_levels[idx];
+        top_level.address = Some(address);
+        match self.hyprland_cache.client(address) {
+            Ok(Some(client)) => {
+                top_level.initial_title = Some(client.initial_title.clone());
+                top_level.initial_class = Some(client.initial_class.clone());
+                top_level.class = Some(client.class.clone());
+                top_level.pid = Some(client.pid).filter(|pid| *pid > 0);
+                top_level.process = top_level.pid.and_then(process::name);
+                top_level.cmdline = top_level.pid.and_then(process::command_line);
+                top_level.flatpak = top_level.pid.and_then(process::flatpak_id);
+                top_level.cgroup = top_level.pid.and_then(process::cgroup);
+                top_level.tags = client.tags();
+                top_level.xwayland = client.xwayland;
+                top_level.gamescope = process::is_gamescope_class(&client.class)
+                    || top_level.pid.is_some_and(process::runs_under_gamescope);
+                top_level.floating = client.floating;
+            }
+            Ok(None) => {}
+            Err(e) => error!("Couldn't query Hyprland clients: {}", e),
+        }
+        debug!(
+            "Top level {} mapped to Hyprland window {:#x}",
+            format_top_level(top_level),
+            address
+        );
+        self.refresh_window_info();
+    }
+
+    /// Top level of the Hyprland window with the given address, to map
+    /// IPC events back to what the wlr protocol reports.
+    pub fn top_level_for_address(&self, address: u64) -> Option<&TopLevelInfo> {
+        self.top_levels
+            .iter()
+            .find(|top_level| top_level.address == Some(address))
+    }
+
+    /// Without the toplevel mapping protocol, finds the address of the
+    /// top levels that don't have one yet among the Hyprland windows
+    /// with the same title and class, as long as a single one of them
+    /// is left.
+    pub fn correlate_top_levels(&mut self) {
+        if self.toplevel_mapping_manager.is_some()
+            || self
+                .top_levels
+                .iter()
+                .all(|top_level| top_level.address.is_some())
+        {
+            return;
+        }
+
+        let clients = match self.hyprland_cache.clients() {
+            Ok(clients) => clients,
+            Err(e) => {
+                debug!("Couldn't query Hyprland windows to map top levels: {}", e);
+                return;
+            }
+        };
+        let mapped: Vec<u64> = self
+            .top_levels
+            .iter()
+            .filter_map(|top_level| top_level.address)
+            .collect();
+        let mut found = Vec::new();
+        for top_level in self.top_levels.iter().filter(|t| t.address.is_none()) {
+            let mut candidates = clients.iter().filter(|(address, client)| {
+                !mapped.contains(address)
+                    && top_level.title.as_deref() == Some(client.title.as_str())
+                    && top_level.app_id.as_deref() == Some(client.class.as_str())
+            });
+            if let (Some((address, _)), None) = (candidates.next(), candidates.next()) {
+                found.push((top_level.handle.id(), *address));
+            }
+        }
+
+        for (id, address) in found {
+            if !self.top_levels.iter().any(|t| t.address == Some(address)) {
+                self.notify_top_level_address(&id, address);
+            }
+        }
+    }
+
+    /// Updates the geometry, group, class and floating state of every
+    /// mapped top level from the Hyprland clients, when some rule needs
+    /// them.
+    pub fn refresh_window_info(&mut self) {
+        if !self.hyprland_tags && !self.rules.iter().any(Rule::uses_clients) {
+            return;
+        }
+
+        let (clients, monitors) = match self.hyprland_cache.clients_and_monitors() {
+            Ok(queried) => queried,
+            Err(e) => {
+                error!("Couldn't query Hyprland windows: {}", e);
+                return;
+            }
+        };
+
+        for top_level in self.top_levels.iter_mut() {
+            let Some(client) = top_level.address.and_then(|address| clients.get(&address)) else {
+                continue;
+            };
+
+            top_level.geometry = Some(WindowGeometry {
+                width: client.size[0],
+                height: client.size[1],
+                coverage: client.coverage(monitors),
+            });
+            top_level.group = client.group();
+            top_level.class = Some(client.class.clone());
+            top_level.floating = client.floating;
+            top_level.tags = client.tags();
+        }
+
+        if self.hyprland_tags {
+            self.add_tag_rules();
+        }
+    }
+
+    /// Adds the rule of every `vibrance:LEVEL` tag some window has, if
+    /// it isn't there yet.
+    fn add_tag_rules(&mut self) {
+        let mut tags: Vec<&String> = self
+            .top_levels
+            .iter()
+            .flat_map(|top_level| top_level.tags.iter())
+            .filter(|tag| tag.starts_with(rules::TAG_PREFIX))
+            .collect();
+        tags.sort();
+        tags.dedup();
+
+        let mut added = Vec::new();
+        for tag in tags {
+            if self
+                .rules
+                .iter()
+                .any(|rule| rule.from_tag && rule.tags == [tag.clone()])
+            {
+                continue;
+            }
+            match rules::tag_rule(tag) {
+                Some(rule) => added.push(rule),
+                None => warn!("The Hyprland tag '{}' doesn't carry a valid level", tag),
+            }
+        }
+        for rule in added {
+            info!("Adding rule {} for a Hyprland tag", rule);
+            self.rules.push(rule);
+        }
+    }
+
+    /// Saturation applied while the given rule matches on an output
+    /// with the given base level, unless it has been overridden at
+    /// runtime.
+    pub fn rule_saturation(&self, rule: usize, base: f64) -> f64 {
+        self.saturation_override
+            .unwrap_or_else(|| self.rules[rule].level(base))
+    }
+
+    /// CTM of the rule on an output with the given base level.
+    fn rule_ctm(&self, rule: usize, base: f64) -> color::Ctm {
+        color::Ctm {
+            saturation: self.rule_saturation(rule, base),
+            effect: self.rules[rule].effect,
+            temperature: self.rules[rule].temperature,
+            brightness: self.rules[rule].brightness,
+            contrast: self.rules[rule].contrast,
+        }
+    }
+
+    /// Records the program of a top level that just went fullscreen, if
+    /// usage is tracked and no rule matches it.
+    fn notify_top_level_fullscreen(&mut self, handle: &ZwlrForeignToplevelHandleV1) {
+        if self.usage.is_none() {
+            return;
+        }
+        let Some(top_level) = self.top_levels.iter().find(|t| &t.handle == handle) else {
+            return;
+        };
+        if engine::best_match(&self.rules, top_level, &self.rule_context()).is_some() {
+            return;
+        }
+        let Some(app_id) = top_level
+            .app_id
+            .clone()
+            .or_else(|| top_level.initial_class.clone())
+            .filter(|app_id| !app_id.is_empty())
+        else {
+            return;
+        };
+        let initial_class = top_level.initial_class.clone();
+        let title = top_level.title.clone();
+
+        let usage = self.usage.as_mut().unwrap();
+        usage.record_fullscreen(&app_id, initial_class.as_deref(), title.as_deref());
+        if let Err(e) = usage.save() {
+            warn!("Couldn't save the usage record: {}", e);
+        }
+    }
+
+    /// Forgets about an unplugged output, given its registry global, so
+    /// no request is ever sent to its proxy again.
+    fn remove_output(&mut self, global: u32) {
+        let Some(idx) = self
+            .outputs
+            .iter()
+            .position(|output| output.global == global)
+        else {
+            return;
+        };
+        let output = self.outputs.remove(idx);
+        info!(
+            "Output {} unplugged",
+            output
+                .name
+                .clone()
+                .unwrap_or_else(|| output.handle.id().to_string())
+        );
+
+        let handle = &output.handle;
+        for top_level in self.top_levels.iter_mut() {
+            top_level.pop_current_output(handle);
+        }
+        self.applied_ctms.retain(|(applied, _)| applied != handle);
+        self.level_sources.retain(|(output, _)| output != handle);
+        self.capped.retain(|(output, _)| output != handle);
+        if let Some(transitions) = self.transitions.as_mut() {
+            transitions.forget_output(handle);
+        }
+        #[cfg(feature = "osd")]
+        if let Some(osd) = self.osd.as_mut() {
+            osd.forget_output(handle);
+        }
+        failsafe::untouch(handle);
+        if handle.version() >= 3 {
+            handle.release();
+        }
+    }
+
+    /// Assigns a newly plugged output to the top levels Hyprland shows
+    /// on it. Compositors may announce the top levels that are already
+    /// on an output only when they move, which would keep a game moved
+    /// there before the output was bound from getting its level.
+    fn adopt_top_levels(&mut self, output: &WlOutput, name: &str) {
+        self.hyprland_cache.clear();
+        let (clients, monitors) = match self.hyprland_cache.clients_and_monitors() {
+            Ok(queried) => queried,
+            Err(e) => {
+                debug!("Couldn't query Hyprland windows: {}", e);
+                return;
+            }
+        };
+        let Some(monitor) = monitors.iter().find(|monitor| monitor.name == name) else {
+            return;
+        };
+
+        for top_level in self.top_levels.iter_mut() {
+            if top_level
+                .address
+                .and_then(|address| clients.get(&address))
+                .is_some_and(|client| client.monitor == monitor.id)
+            {
+                top_level.push_current_output(output.clone());
+            }
+        }
+        self.hyprland_monitors = monitors.to_vec();
+        if let Some(info) = self.outputs.iter_mut().find(|info| &info.handle == output) {
+            info.serial = Some(monitor.serial.clone()).filter(|serial| !serial.is_empty());
+            info.hdr = monitor.is_hdr();
+            info.asleep = monitor.is_asleep();
+        }
+    }
+
+    /// Applies the level the previous run left on the output, without
+    /// waiting for the first evaluation.
+    fn restore_output(&mut self, output: &WlOutput, name: &str) {
+        let Some(idx) = self.restoring.iter().position(|entry| entry.output == name) else {
+            return;
+        };
+        let entry = self.restoring.remove(idx);
+        let Some(ctm_control) = self.ctm_manager.clone() else {
+            return;
+        };
+        if self.disabled_by_file || !self.manages_output(output) {
+            return;
+        }
+        // The levels are compared rather than the matrices from then on,
+        // so a matrix built with other options would stay until the
+        // level changes.
+        if entry.matrix != entry.ctm.matrix(&self.matrix_options) {
+            debug!(
+                "Not restoring the level of {}, the matrix options changed",
+                name
+            );
+            return;
+        }
+
+        info!(
+            "Restoring saturation {} on {} ({})",
+            entry.ctm.saturation,
+            name,
+            entry.source.as_deref().unwrap_or("unknown source")
+        );
+        if !set_ctm_matrix_for_display(&ctm_control, output, &entry.matrix) {
+            return;
+        }
+        ctm_control.commit();
+        self.applied_ctms.push((output.clone(), entry.ctm));
+        if let Some(transitions) = self.transitions.as_mut() {
+            transitions.set_shown(output, entry.matrix);
+        }
+    }
+
+    /// Stores the applied levels, if they changed since the last time.
+    fn persist_applied(&mut self) {
+        let last_applied = persistence::LastApplied {
+            instance_signature: hyprland::instance_signature(),
+            outputs: self
+                .applied_ctms
+                .iter()
+                .map(|(output, ctm)| persistence::AppliedOutput {
+                    output: self.format_output(output),
+                    ctm: *ctm,
+                    matrix: ctm.matrix(&self.matrix_options),
+                    source: self
+                        .level_sources
+                        .iter()
+                        .find(|(handle, _)| handle == output)
+                        .map(|(_, source)| source.to_string()),
+                })
+                .collect(),
+        };
+        if self.persisted.last_applied.as_ref() == Some(&last_applied) {
+            return;
+        }
+
+        self.persisted.last_applied = Some(last_applied);
+        if let Err(e) = self.persisted.save() {
+            warn!("Couldn't save the applied state: {}", e);
+        }
+    }
+
+    pub fn rule_context(&self) -> rules::Context<'_> {
+        rules::Context {
+            submap: self.submap.as_deref(),
+            workspace: self
+                .hyprland_monitors
+                .iter()
+                .find(|monitor| monitor.focused)
+                .map(|monitor| &monitor.active_workspace),
+            top_levels: &self.top_levels,
+        }
+    }
+
+    /// Adds a rule at runtime. Returns the diagnostics of the new rule,
+    /// if it's shadowed by another one.
+    pub fn add_rule(&mut self, mut rule: Rule, first: bool) -> Result<Vec<String>, String> {
+        if let Some(name) = rule.name.as_deref()
+            && self.rules.iter().any(|r| r.name.as_deref() == Some(name))
+        {
+            return Err(format!("there's already a rule named '{}'", name));
+        }
+
+        self.persisted
+            .apply_to_rules(std::slice::from_mut(&mut rule));
+        rule.managed = self.rules_write_back.is_some();
+        rule.added_at_runtime = !rule.managed;
+        info!("Adding rule {}", rule);
+        let idx = if first { 0 } else { self.rules.len() };
+        self.rules.insert(idx, rule);
+        self.write_back_rules();
+
+        Ok(engine::diagnose(&self.rules)
+            .into_iter()
+            .filter(|(shadowed, earlier, _)| *shadowed == idx || *earlier == idx)
+            .map(|(shadowed, earlier, message)| {
+                format!(
+                    "rule {} {} ({})",
+                    self.rules[shadowed], message, self.rules[earlier]
+                )
+            })
+            .collect())
+    }
+
+    pub fn remove_rule(&mut self, name: &str) -> Result<(), String> {
+        let count = self.rules.len();
+        self.rules.retain(|rule| rule.name.as_deref() != Some(name));
+        if self.rules.len() == count {
+            return Err(format!("no rule named '{}'", name));
+        }
+
+        info!("Rule '{}' removed", name);
+        self.write_back_rules();
+        Ok(())
+    }
+
+    pub fn status(&self) -> control::Status {
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|output| {
+                let ctm = self
+                    .applied_ctms
+                    .iter()
+                    .find(|(handle, _)| handle == &output.handle)
+                    .map(|(_, ctm)| *ctm);
+                control::OutputStatus {
+                    name: self.format_output(&output.handle),
+                    description: output.description.clone(),
+                    saturation: ctm.map_or(1.0, |ctm| ctm.saturation),
+                    effect: ctm.and_then(|ctm| ctm.effect),
+                    temperature: ctm.and_then(|ctm| ctm.temperature),
+                    brightness: ctm.and_then(|ctm| ctm.brightness),
+                    contrast: ctm.and_then(|ctm| ctm.contrast),
+                    matrix: ctm.map(|ctm| ctm.matrix(&self.matrix_options)),
+                    source: self
+                        .level_sources
+                        .iter()
+                        .find(|(handle, _)| handle == &output.handle)
+                        .map(|(_, source)| source.to_string()),
+                }
+            })
+            .collect();
+
+        let focused = self.focused_top_level_object_id.as_ref();
+        let top_levels = self
+            .top_levels
+            .iter()
+            .map(|top_level| control::TopLevelStatus {
+                title: top_level.title.clone(),
+                app_id: top_level.app_id.clone(),
+                focused: focused == Some(&top_level.handle.id()),
+                minimized: top_level.minimized,
+                fullscreen: top_level.fullscreen,
+                maximized: top_level.maximized,
+                group_tab: top_level.address.and_then(|address| {
+                    let position = top_level.group.iter().position(|a| *a == address)?;
+                    Some(format!("{}/{}", position + 1, top_level.group.len()))
+                }),
+                outputs: top_level
+                    .current_outputs
+                    .iter()
+                    .map(|output| self.format_output(output))
+                    .collect(),
+            })
+            .collect();
+
+        control::Status {
+            paused: self.paused,
+            saturation_override: self.saturation_override,
+            disabled_by_file: self.disabled_by_file,
+            outputs,
+            top_levels,
+        }
+    }
+
+    pub fn rule_statuses(&self) -> Vec<control::RuleStatus> {
+        let context = self.rule_context();
+        let focused = self.focused_top_level();
+        self.rules
+            .iter()
+            .map(|rule| {
+                let focused = if rule.is_window_rule() {
+                    focused.is_some_and(|top_level| rule.matches(top_level, &context))
+                } else {
+                    rule.matches_context(&context)
+                };
+                control::RuleStatus {
+                    rule: rule.to_string(),
+                    name: rule.name.clone(),
+                    enabled: rule.enabled,
+                    saturation: rule.level(1.0),
+                    focused,
+                    open: self
+                        .top_levels
+                        .iter()
+                        .any(|top_level| !top_level.minimized && rule.matches(top_level, &context)),
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_rule_saturation(&mut self, index: usize, saturation: f64) -> Result<(), String> {
+        units::check_saturation(saturation)?;
+
+        let rule = self
+            .rules
+            .get_mut(index)
+            .ok_or_else(|| format!("there's no rule #{}", index))?;
+        rule.keep_loaded();
+        rule.saturation = saturation;
+        rule.boost = None;
+        info!("Rule #{} level set to {}", index, saturation);
+        if rule.managed {
+            self.write_back_rules();
+        }
+        Ok(())
+    }
+
+    pub fn move_rule(&mut self, index: usize, to: usize) -> Result<(), String> {
+        if index >= self.rules.len() || to >= self.rules.len() {
+            return Err(format!(
+                "rule indices must be lower than {}",
+                self.rules.len()
+            ));
+        }
+
+        let mut rule = self.rules.remove(index);
+        rule.keep_loaded();
+        let managed = rule.managed;
+        self.rules.insert(to, rule);
+        info!("Rule #{} moved to #{}", index, to);
+        if managed {
+            self.write_back_rules();
+        }
+        Ok(())
+    }
+
+    fn write_back_rules(&mut self) {
+        let Some(path) = self.rules_write_back.as_ref() else {
+            return;
+        };
+
+        match rules::write_managed(path, &self.rules) {
+            Ok(()) => {
+                self.rules_written_at = path.metadata().and_then(|m| m.modified()).ok();
+            }
+            Err(e) => error!("Couldn't write the rules back to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Whether the file is the rules file and hasn't changed since the
+    /// rules were last written back to it.
+    pub fn rules_file_written_by_us(&self, path: &std::path::Path) -> bool {
+        self.rules_write_back.as_deref() == Some(path)
+            && self.rules_written_at.is_some()
+            && path.metadata().and_then(|m| m.modified()).ok() == self.rules_written_at
+    }
+
+    /// Builds the rule set again from its sources, keeping the current
+    /// one if any of them is invalid. Returns the diagnostics of the new
+    /// rules.
+    pub fn reload_rules(&mut self) -> Result<Vec<String>, String> {
+        let (mut rules, origins): (Vec<Rule>, Vec<String>) = match self.rule_sources.load() {
+            Ok(rules) => rules.into_iter().unzip(),
+            Err(e) => {
+                error!("Couldn't reload the rules, keeping the current ones: {}", e);
+                return Err(e);
+            }
+        };
+        let diagnostics = warn_rule_diagnostics(&rules, &origins);
+        self.persisted.apply_to_rules(&mut rules);
+        self.carry_runtime_rules(&mut rules);
+
+        let loaded: Vec<&Rule> = self.rules.iter().filter(|rule| !rule.from_tag).collect();
+        if rules.iter().eq(loaded) {
+            debug!("The rules didn't change");
+            return Ok(diagnostics);
+        }
+        info!("Loaded {} rules", rules.len());
+        self.rules = rules;
+        self.refresh_window_info();
+        Ok(diagnostics)
+    }
+
+    /// Puts the rules added at runtime back into the reloaded ones, and
+    /// the rules changed at runtime in place of the loaded ones they
+    /// came from, about where they were. A changed rule whose source
+    /// doesn't have it as it was anymore is taken from the source.
+    fn carry_runtime_rules(&self, rules: &mut Vec<Rule>) {
+        let mut carried = Vec::new();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if let Some(loaded) = rule.loaded_as.as_deref() {
+                let spec = loaded.to_string();
+                let Some(position) = rules.iter().position(|r| r.to_string() == spec) else {
+                    continue;
+                };
+                rules.remove(position);
+            } else if !rule.added_at_runtime {
+                continue;
+            }
+            carried.push((idx, rule.clone()));
+        }
+        for (idx, rule) in carried {
+            rules.insert(idx.min(rules.len()), rule);
+        }
+    }
+
+    pub fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let mut found = false;
+        for rule in self.rules.iter_mut() {
+            if rule.name.as_deref() == Some(name) {
+                rule.enabled = enabled;
+                found = true;
+            }
+        }
+
+        if !found {
+            return Err(format!("no rule named '{}'", name));
+        }
+
+        self.persisted
+            .rule_enabled
+            .insert(name.to_string(), enabled);
+        if let Err(e) = self.persisted.save() {
+            error!("Couldn't persist the state of rule '{}': {}", name, e);
+        }
+
+        info!(
+            "Rule '{}' {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    pub fn notify_no_top_level_focused(&mut self) {
+        self.focused_top_level_object_id = None;
+    }
+
     pub fn notify_top_level_closed(&mut self, handle: &ZwlrForeignToplevelHandleV1) {
         if let Some(idx) = self.index_of_top_level_for_object_id(&handle.id()) {
             if Some(handle.id()) == self.focused_top_level_object_id {
                 self.focused_top_level_object_id.take();
             }
+            self.focus_history.retain(|id| id != &handle.id());
             self.top_levels.remove(idx);
         }
     }
@@ -153,6 +1219,11 @@ impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
         // first set of globals.
         debug!("Received globals event: {:?}", event);
 
+        if let wl_registry::Event::GlobalRemove { name } = event {
+            this.remove_output(name);
+            return;
+        }
+
         let wl_registry::Event::Global {
             name,
             interface,
@@ -163,22 +1234,78 @@ impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
         };
 
         if interface == WL_OUTPUT_IFACE {
-            registry.bind::<WlOutput, _, _>(name, version, qh, ());
+            if version < WL_OUTPUT_VERSION {
+                warn!(
+                    "The compositor only supports wl_output version {}, outputs can't be referred to by name",
+                    version
+                );
+            }
+            let handle =
+                registry.bind::<WlOutput, _, _>(name, version.min(WL_OUTPUT_VERSION), qh, ());
+            let mut info = OutputInfo::new(handle, name);
+            info.hotplugged = this.init.is_none();
+            this.outputs.push(info);
         }
 
-        let Some(init) = this.init.as_mut() else {
-            return;
-        };
+        if interface == shortcuts::GLOBAL_SHORTCUTS_MANAGER_IFACE && this.global_shortcuts {
+            let manager = registry.bind(
+                name,
+                version.min(shortcuts::GLOBAL_SHORTCUTS_MANAGER_VERSION),
+                qh,
+                (),
+            );
+            shortcuts::register(&manager, qh);
+        }
 
-        match &interface[..] {
-            HYPERLAND_CTM_CONTROL_MANAGER_IFACE => {
-                init.ctm_manager = Some(registry.bind(name, version, qh, ()));
-                info!("Bound to Hyprland CTM control manager");
+        if interface == HYPRLAND_TOPLEVEL_MAPPING_MANAGER_IFACE {
+            this.toplevel_mapping_manager =
+                Some(registry.bind(name, version.min(TOPLEVEL_MAPPING_MANAGER_VERSION), qh, ()));
+            info!("Bound to Hyprland toplevel mapping manager");
+        }
+
+        // Bound even after initialization, as Hyprland may announce
+        // it late once the permission to use it is granted.
+        if interface == HYPERLAND_CTM_CONTROL_MANAGER_IFACE {
+            this.ctm_manager =
+                Some(registry.bind(name, version.min(CTM_CONTROL_MANAGER_VERSION), qh, ()));
+            if this.init.is_some() {
+                info!("Bound to Hyprland CTM control manager");
+            } else {
+                info!("Hyprland CTM control manager is now available, leaving degraded mode");
+                systemd::notify("READY=1\nSTATUS=Applying saturation");
             }
+        }
+
+        let Some(init) = this.init.as_mut() else {
+            return;
+        };
+
+        match &interface[..] {
             ZWLR_TOP_LEVEL_MANAGER_IFACE => {
-                init.top_level_manager_global = Some(Global::new(name, interface, version));
+                if version < 2 {
+                    warn!(
+                        "The compositor only supports {} version {}, fullscreen windows won't be reported",
+                        ZWLR_TOP_LEVEL_MANAGER_IFACE, version
+                    );
+                }
+                init.top_level_manager_global = Some(Global::new(
+                    name,
+                    version.min(ZWLR_TOP_LEVEL_MANAGER_VERSION),
+                ));
                 info!("Discovered to wlr top level manager");
             }
+            #[cfg(feature = "osd")]
+            osd::WL_COMPOSITOR_IFACE => {
+                init.compositor = Some(registry.bind(name, version.min(4), qh, ()));
+            }
+            #[cfg(feature = "osd")]
+            osd::WL_SHM_IFACE => {
+                init.shm = Some(registry.bind(name, 1, qh, ()));
+            }
+            #[cfg(feature = "osd")]
+            osd::ZWLR_LAYER_SHELL_IFACE => {
+                init.layer_shell = Some(registry.bind(name, version.min(4), qh, ()));
+            }
             _ => {}
         }
     }
@@ -186,28 +1313,75 @@ impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
 
 impl Dispatch<WlOutput, ()> for AppState {
     fn event(
-        _: &mut Self,
+        this: &mut Self,
         output: &WlOutput,
         event: <WlOutput as Proxy>::Event,
         _: &(),
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        if let wl_output::Event::Name { name } = event {
-            debug!("Discovered display {}: {}", output.id(), name);
+        let Some(info) = this.outputs.iter_mut().find(|info| &info.handle == output) else {
+            return;
+        };
+
+        match event {
+            wl_output::Event::Name { name } => {
+                debug!("Discovered display {}: {}", output.id(), name);
+                info.name = Some(name.clone());
+                if info.hotplugged {
+                    info!("Output {} plugged in", name);
+                    this.adopt_top_levels(output, &name);
+                }
+                this.restore_output(output, &name);
+            }
+            wl_output::Event::Description { description } => {
+                debug!("Display {} is {}", output.id(), description);
+                info.description = Some(description);
+            }
+            wl_output::Event::Geometry { make, model, .. } => {
+                if let Some(preset) = presets::find(&this.presets, &make, &model) {
+                    info!(
+                        "Using the preset for {} {}: max saturation {}, suggested temperature {}",
+                        make,
+                        model,
+                        preset
+                            .max_saturation
+                            .map_or("none".to_string(), |sat| sat.to_string()),
+                        preset
+                            .temperature
+                            .map_or("none".to_string(), |temp| format!("{}K", temp))
+                    );
+                    info.max_saturation = preset.max_saturation;
+                }
+                info.make = Some(make);
+                info.model = Some(model);
+            }
+            _ => {}
         }
     }
 }
 
 impl Dispatch<HyprlandCtmControlManagerV1, ()> for AppState {
     fn event(
-        _: &mut Self,
-        _: &HyprlandCtmControlManagerV1,
-        _: hyprland_ctm_control_manager_v1::Event,
+        this: &mut Self,
+        manager: &HyprlandCtmControlManagerV1,
+        event: hyprland_ctm_control_manager_v1::Event,
         _: &(),
         _: &Connection,
         _: &QueueHandle<AppState>,
     ) {
+        // Only sent from version 2 on, older managers are ignored
+        // without a word.
+        if let hyprland_ctm_control_manager_v1::Event::Blocked = event {
+            error!(
+                "Another program, such as hyprsunset, already controls the CTMs. Running in degraded mode: window focus is tracked, but no CTM will be applied"
+            );
+            systemd::notify("STATUS=Blocked by another program controlling the CTMs");
+            if this.ctm_manager.as_ref() == Some(manager) {
+                this.ctm_manager = None;
+            }
+            manager.destroy();
+        }
     }
 }
 
@@ -233,14 +1407,14 @@ impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for AppState {
         event: zwlr_foreign_toplevel_manager_v1::Event,
         _: &(),
         _: &Connection,
-        _: &QueueHandle<AppState>,
+        qh: &QueueHandle<AppState>,
     ) {
-        match event {
-            zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
-                debug!("New top level found: {}", toplevel.id());
-                this.get_or_create_top_level(&toplevel);
+        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
+            debug!("New top level found: {}", toplevel.id());
+            this.get_or_create_top_level(&toplevel);
+            if let Some(mapping_manager) = this.toplevel_mapping_manager.as_ref() {
+                mapping_manager.get_window_for_toplevel_wlr(&toplevel, qh, toplevel.id());
             }
-            _ => {}
         }
     }
 
@@ -272,15 +1446,18 @@ impl Dispatch<ZwlrForeignToplevelHandleV1, TopLevelUserData> for AppState {
             zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
                 debug!(
                     "Top level {} title updated: '{}'",
-                    format_top_level(&top_level),
+                    format_top_level(top_level),
                     title
                 );
                 top_level.title = Some(title);
             }
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                top_level.app_id = Some(app_id);
+            }
             zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output } => {
                 debug!(
                     "Top level {} moved to new display: {}",
-                    format_top_level(&top_level),
+                    format_top_level(top_level),
                     output.id()
                 );
                 top_level.push_current_output(output);
@@ -288,7 +1465,7 @@ impl Dispatch<ZwlrForeignToplevelHandleV1, TopLevelUserData> for AppState {
             zwlr_foreign_toplevel_handle_v1::Event::OutputLeave { output } => {
                 debug!(
                     "Top level {} left display: {}",
-                    format_top_level(&top_level),
+                    format_top_level(top_level),
                     output.id()
                 );
                 top_level.pop_current_output(&output);
@@ -296,15 +1473,27 @@ impl Dispatch<ZwlrForeignToplevelHandleV1, TopLevelUserData> for AppState {
             zwlr_foreign_toplevel_handle_v1::Event::State { state } => {
                 debug!(
                     "Top level {} state update: {:?}",
-                    format_top_level(&top_level),
+                    format_top_level(top_level),
                     state
                 );
+                let states = decode_top_level_states(&state);
+                let was_fullscreen = top_level.fullscreen;
+                top_level.minimized =
+                    states.contains(&(zwlr_foreign_toplevel_handle_v1::State::Minimized as u32));
+                top_level.fullscreen =
+                    states.contains(&(zwlr_foreign_toplevel_handle_v1::State::Fullscreen as u32));
+                top_level.maximized =
+                    states.contains(&(zwlr_foreign_toplevel_handle_v1::State::Maximized as u32));
                 let focused =
-                    state.contains(&(zwlr_foreign_toplevel_handle_v1::State::Activated as u8));
-                this.notify_top_level_focus_changed(&handle, focused);
+                    states.contains(&(zwlr_foreign_toplevel_handle_v1::State::Activated as u32));
+                let fullscreen = top_level.fullscreen;
+                this.notify_top_level_focus_changed(handle, focused);
+                if fullscreen && !was_fullscreen {
+                    this.notify_top_level_fullscreen(handle);
+                }
             }
             zwlr_foreign_toplevel_handle_v1::Event::Closed => {
-                debug!("Top level {} state closed", format_top_level(&top_level));
+                debug!("Top level {} state closed", format_top_level(top_level));
                 this.notify_top_level_closed(handle);
             }
             _ => {}
@@ -312,169 +1501,3141 @@ impl Dispatch<ZwlrForeignToplevelHandleV1, TopLevelUserData> for AppState {
     }
 }
 
-// between 0.0 and 4.0. Evily stolen from libvibrant
-fn calc_ctm_matrix(saturation: f64) -> [f64; 9] {
-    let mut matrix = [0f64; 9];
-    let coeff = (1.0 - saturation) / 3.0;
-    for i in 0..9 {
-        matrix[i] = coeff + if (i % 4) == 0 { saturation } else { 0f64 };
+impl Dispatch<HyprlandToplevelMappingManagerV1, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &HyprlandToplevelMappingManagerV1,
+        _: hyprland_toplevel_mapping_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<HyprlandToplevelWindowMappingHandleV1, ObjectId> for AppState {
+    fn event(
+        this: &mut Self,
+        mapping: &HyprlandToplevelWindowMappingHandleV1,
+        event: hyprland_toplevel_window_mapping_handle_v1::Event,
+        top_level_id: &ObjectId,
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+        match event {
+            hyprland_toplevel_window_mapping_handle_v1::Event::WindowAddress {
+                address_hi,
+                address,
+            } => {
+                let address = ((address_hi as u64) << 32) | address as u64;
+                this.notify_top_level_address(top_level_id, address);
+            }
+            hyprland_toplevel_window_mapping_handle_v1::Event::Failed => {
+                debug!(
+                    "Couldn't resolve window address of top level {}",
+                    top_level_id
+                );
+            }
+            _ => {}
+        }
+        mapping.destroy();
     }
+}
 
-    return matrix;
+/// The state event carries an array of native endian u32 values.
+fn decode_top_level_states(raw: &[u8]) -> Vec<u32> {
+    raw.chunks_exact(4)
+        .map(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap()))
+        .collect()
 }
 
 fn clear_ctm_matrix_for_display(control: &HyprlandCtmControlManagerV1, display: &WlOutput) {
     control.set_ctm_for_output(display, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0);
+    failsafe::untouch(display);
 }
 
+/// Sepia tone matrix, used by the self test because it's impossible
+/// to miss.
+const SEPIA_CTM_MATRIX: [f64; 9] = [
+    0.393, 0.769, 0.189, //
+    0.349, 0.686, 0.168, //
+    0.272, 0.534, 0.131,
+];
+
 fn set_sat_ctm_for_display(
     control: &HyprlandCtmControlManagerV1,
     display: &WlOutput,
     saturation: f64,
+    options: &MatrixOptions,
 ) {
-    let matrix = calc_ctm_matrix(saturation);
+    set_ctm_matrix_for_display(
+        control,
+        display,
+        &color::saturation_matrix(saturation, options),
+    );
+}
+
+/// Sets the matrix on the output, unless it's beyond the limits of the
+/// protocol. Returns whether it was sent.
+fn set_ctm_matrix_for_display(
+    control: &HyprlandCtmControlManagerV1,
+    display: &WlOutput,
+    matrix: &[f64; 9],
+) -> bool {
+    if let Err(e) = color::check_protocol_limits(matrix) {
+        error!(
+            "Not applying matrix {:?} to {}: {}",
+            matrix,
+            display.id(),
+            e
+        );
+        return false;
+    }
+
     control.set_ctm_for_output(
         display, matrix[0], matrix[1], matrix[2], matrix[3], matrix[4], matrix[5], matrix[6],
         matrix[7], matrix[8],
     );
+    failsafe::touch(control, display);
+    true
 }
 
-fn diff_lists<'a, A: Eq, E1: Borrow<A>, E2: Borrow<A>>(
-    old: &'a [E1],
-    new: &'a [E2],
-) -> (Vec<&'a A>, Vec<&'a A>, Vec<&'a A>) {
-    fn contains_ref<A: Eq>(haystack: &[impl Borrow<A>], needle: &A) -> bool {
-        for elem in haystack {
-            if elem.borrow() == needle {
-                return true;
-            }
+/// Saturation level assigned to a Hyprland workspace, matched
+/// either by its id or by its name.
+#[derive(Debug, Clone)]
+struct WorkspaceSaturation {
+    workspace: String,
+    saturation: f64,
+}
+
+impl WorkspaceSaturation {
+    fn matches(&self, workspace: &hyprland::WorkspaceRef) -> bool {
+        workspace.is(&self.workspace)
+    }
+}
+
+/// Warns about the rules that never apply, or conflict with another
+/// one, given where each rule comes from. Returns the warnings.
+fn warn_rule_diagnostics(rules: &[Rule], origins: &[String]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (rule, origin) in rules.iter().zip(origins) {
+        for warning in rule.warnings() {
+            warnings.push(format!("{}: {}", origin, warning));
         }
+    }
+    for (idx, earlier, message) in engine::diagnose(rules) {
+        warnings.push(format!(
+            "{}: rule {} (see {})",
+            origins[idx], message, origins[earlier]
+        ));
+    }
+    for warning in warnings.iter() {
+        warn!("{}", warning);
+    }
+    warnings
+}
 
-        return false;
+/// Saturation level given to an output, matched by connector name or
+/// description, as a level it always has or as a cap.
+#[derive(Debug, Clone)]
+struct OutputSaturation {
+    output: String,
+    saturation: f64,
+}
+
+fn parse_output_sat(s: &str) -> Result<OutputSaturation, String> {
+    let Some((output, saturation)) = s.rsplit_once('=') else {
+        return Err(format!("'{}' doesn't follow the OUTPUT=SAT format", s));
+    };
+
+    Ok(OutputSaturation {
+        output: output.to_string(),
+        saturation: units::parse_saturation(saturation)?,
+    })
+}
+
+fn output_saturation(output: &OutputInfo, args: &Cli) -> Option<f64> {
+    args.output_sat
+        .iter()
+        .find(|level| output.is(&level.output))
+        .map(|level| level.saturation)
+}
+
+fn output_max_saturation(output: &OutputInfo, args: &Cli) -> Option<f64> {
+    args.output_max_sat
+        .iter()
+        .find(|level| output.is(&level.output))
+        .map(|level| level.saturation)
+}
+
+fn parse_workspace_sat(s: &str) -> Result<WorkspaceSaturation, String> {
+    let Some((workspace, saturation)) = s.rsplit_once('=') else {
+        return Err(format!("'{}' doesn't follow the WORKSPACE=SAT format", s));
+    };
+
+    Ok(WorkspaceSaturation {
+        workspace: workspace.to_string(),
+        saturation: units::parse_saturation(saturation)?,
+    })
+}
+
+/// Returns the focused top level (see [`AppState::rule_focus`]) along
+/// with the index of the rule it matches. Its current outputs are the
+/// ones that should get the saturation applied.
+fn matching_top_level(state: &AppState) -> Option<(&TopLevelInfo, usize)> {
+    let top_level = state.rule_focus()?;
+    let rule = engine::best_match(&state.rules, top_level, &state.rule_context())?;
+    Some((top_level, rule))
+}
+
+/// Returns every output that shows at least one matching,
+/// non-minimized top level, along with the index of the winning rule.
+/// When several of them share an output, the one matching the
+/// rule taking precedence wins.
+fn aggregated_matches(state: &AppState, args: &Cli) -> Vec<(WlOutput, usize)> {
+    matches_on_outputs(state, args, engine::best_match)
+}
+
+/// Same as [`aggregated_matches`], only for the rules applying while
+/// their window is visible.
+fn visible_matches(state: &AppState, args: &Cli) -> Vec<(WlOutput, usize)> {
+    if !state.rules.iter().any(|rule| rule.visible) {
+        return Vec::new();
     }
+    matches_on_outputs(state, args, engine::best_visible_match)
+}
 
-    let mut removed = vec![];
-    let mut unchanged = vec![];
-    let mut added = vec![];
+/// With --per-output-focus, the rule of the top level focused last
+/// among the ones shown on every output, for the outputs the focused
+/// one isn't on.
+fn last_focused_matches(state: &AppState, args: &Cli) -> Vec<(WlOutput, usize)> {
+    if !args.per_output_focus {
+        return Vec::new();
+    }
 
-    for old_value in old.iter() {
-        if contains_ref(new, old_value.borrow()) {
-            unchanged.push(old_value.borrow());
-        } else {
-            removed.push(old_value.borrow());
+    let context = state.rule_context();
+    let mut decided: Vec<WlOutput> = Vec::new();
+    let mut matches = Vec::new();
+    for id in state.focus_history.iter().rev() {
+        let Some(top_level) = state
+            .index_of_top_level_for_object_id(id)
+            .map(|idx| &state.top_levels[idx])
+            .filter(|top_level| !top_level.minimized)
+        else {
+            continue;
+        };
+        let rule = engine::best_match(&state.rules, top_level, &context);
+        for output in top_level.current_outputs.iter() {
+            if decided.contains(output) {
+                continue;
+            }
+            decided.push(output.clone());
+            if let Some(rule) = rule {
+                matches.push((output.clone(), rule));
+            }
         }
     }
+    matches
+}
+
+fn matches_on_outputs(
+    state: &AppState,
+    args: &Cli,
+    best_match: impl Fn(&[Rule], &TopLevelInfo, &rules::Context) -> Option<usize>,
+) -> Vec<(WlOutput, usize)> {
+    let context = state.rule_context();
+    let mut winners: Vec<(WlOutput, usize)> = Vec::new();
+    for top_level in state.top_levels.iter().filter(|t| !t.minimized) {
+        let Some(rule) = best_match(&state.rules, top_level, &context) else {
+            continue;
+        };
 
-    for new_value in new.iter() {
-        if !contains_ref(old, new_value.borrow()) {
-            added.push(new_value.borrow());
+        for output in target_outputs(state, top_level, rule, args).iter() {
+            match winners.iter_mut().find(|(handle, _)| handle == output) {
+                Some(winner) => {
+                    if engine::compare(&state.rules, rule, winner.1).is_lt() {
+                        winner.1 = rule;
+                    }
+                }
+                None => winners.push((output.clone(), rule)),
+            }
         }
     }
 
-    return (removed, unchanged, added);
+    winners
 }
-#[derive(Parser, Debug)]
-#[command(author, version, about)]
-struct Cli {
-    /// Saturation level (must be between 0.0 and 4.0)
-    #[arg(short, long, value_parser = validate_sat_level)]
-    sat_level: f64,
 
-    /// Title match filters (can be used multiple times)
-    #[arg(short, long, num_args = 1.., value_name = "TITLE", required = true)]
-    title_match: Vec<String>,
+/// Returns the outputs the saturation of a top level matching the given
+/// rule should be applied to: the ones it's shown on, the one
+/// containing the pointer when following the cursor, or all of them
+/// with --all-outputs or a rule asking for it.
+fn target_outputs(
+    state: &AppState,
+    top_level: &TopLevelInfo,
+    rule: usize,
+    args: &Cli,
+) -> Vec<WlOutput> {
+    if args.all_outputs || state.rules[rule].all_outputs {
+        return state
+            .outputs
+            .iter()
+            .map(|output| output.handle.clone())
+            .collect();
+    }
+    if !args.follow_cursor {
+        return top_level.current_outputs.clone();
+    }
+
+    state
+        .outputs
+        .iter()
+        .filter(|output| output.name.is_some() && output.name == state.cursor_monitor)
+        .map(|output| output.handle.clone())
+        .collect()
 }
 
-fn validate_sat_level(s: &str) -> Result<f64, String> {
-    let val: f64 = s
-        .parse()
-        .map_err(|_| format!("'{}' is not a valid float", s))?;
-    if (0.0..=4.0).contains(&val) {
-        Ok(val)
-    } else {
-        Err(format!(
-            "sat-level must be between 0.0 and 4.0, got {}",
-            val
-        ))
-    }
+/// Returns the saturation of the workspace currently shown on the
+/// given output, if any has been configured for it.
+fn workspace_saturation_for_output(
+    state: &AppState,
+    output: &OutputInfo,
+    args: &Cli,
+) -> Option<f64> {
+    let name = output.name.as_ref()?;
+    let monitor = state.hyprland_monitors.iter().find(|m| &m.name == name)?;
+    args.workspace_sat
+        .iter()
+        .find(|ws| ws.matches(&monitor.active_workspace))
+        .map(|ws| ws.saturation)
 }
 
-fn main() {
-    env_logger::builder()
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
+fn shows_fullscreen_top_level(state: &AppState, output: &WlOutput) -> bool {
+    state.top_levels.iter().any(|top_level| {
+        top_level.fullscreen && !top_level.minimized && top_level.current_outputs.contains(output)
+    })
+}
 
-    let args = Cli::parse();
+/// Level of an output when no rule applies to it, which relative rules
+/// are based on.
+fn base_saturation(state: &AppState, output: &WlOutput, args: &Cli) -> f64 {
+    state
+        .outputs
+        .iter()
+        .find(|info| &info.handle == output)
+        .and_then(|info| {
+            output_saturation(info, args)
+                .or_else(|| workspace_saturation_for_output(state, info, args))
+        })
+        .or(args.default_saturation)
+        .unwrap_or(1.0)
+}
 
-    let conn = Connection::connect_to_env().unwrap();
-    let display = conn.display();
-    let mut event_queue = conn.new_event_queue();
-    let qh: QueueHandle<AppState> = event_queue.handle();
+/// Computes the saturation each output should have right now. The
+/// outputs of the focused top level (or of every matching top level
+/// when aggregating) get the saturation of their rule. The rest of
+/// outputs get the secondary saturation of the matched rule if it has
+/// one, then the one of the first window independent rule that holds,
+/// then the --auto-fullscreen level if they show a fullscreen window,
+/// or fall back to the level of their active workspace and then to
+/// --default-saturation. With a base matrix, every other output gets a
+/// neutral level so the base still applies. Outputs not present in the
+/// result should have an identity CTM.
+fn desired_ctms(state: &AppState, args: &Cli) -> Vec<(WlOutput, color::Ctm)> {
+    desired_levels(state, args)
+        .0
+        .into_iter()
+        .map(|(output, ctm, _)| (output, ctm))
+        .collect()
+}
 
-    let mut state = AppState::default();
-    state.init = Some(Box::new(InitAppState::default()));
+/// Where the level of an output comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LevelSource {
+    Rule(usize),
+    OtherSaturation(usize),
+    AutoFullscreen,
+    Workspace,
+    Output,
+    Default,
+    /// Nothing but the base matrix.
+    Base,
+    Forced,
+}
 
-    let registry = display.get_registry(&qh, ());
-    event_queue.roundtrip(&mut state).unwrap();
+/// Level an output keeps for a moment after its rule stopped matching.
+#[derive(Debug)]
+struct HeldLevel {
+    output: WlOutput,
+    ctm: color::Ctm,
+    source: LevelSource,
+    until: Instant,
+}
 
-    let init_state = state.init.take().unwrap();
-    let Some(ctm_control) = init_state.ctm_manager else {
-        error!(
-            "Couldn't find Hyprland CTM control manager interface. Are you actually running Hyprland?"
-        );
-        return;
-    };
+/// Desired CTM of some outputs, along with where each comes from.
+type Levels = Vec<(WlOutput, color::Ctm, LevelSource)>;
 
-    let Some(top_level_manager_global) = init_state.top_level_manager_global else {
-        error!("Couldn't find wlr top level manager interface");
+impl std::fmt::Display for LevelSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LevelSource::Rule(rule) => write!(f, "rule #{}", rule),
+            LevelSource::OtherSaturation(rule) => write!(f, "other_sat of rule #{}", rule),
+            LevelSource::AutoFullscreen => write!(f, "auto-fullscreen"),
+            LevelSource::Workspace => write!(f, "workspace"),
+            LevelSource::Output => write!(f, "output"),
+            LevelSource::Default => write!(f, "default"),
+            LevelSource::Base => write!(f, "base matrix"),
+            LevelSource::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+/// Same as [`desired_ctms`], along with the source of every level, and
+/// the outputs whose level got capped, along with the level they'd
+/// have otherwise.
+fn desired_levels(state: &AppState, args: &Cli) -> (Levels, Vec<(WlOutput, f64)>) {
+    let mut desired = requested_levels(state, args);
+
+    // Mirrors show the same colors as the output they mirror.
+    for output in state.outputs.iter() {
+        let Some(source) = output.mirror_of.as_ref() else {
+            continue;
+        };
+        let level = desired
+            .iter()
+            .find(|(handle, _, _)| {
+                state
+                    .outputs
+                    .iter()
+                    .any(|info| &info.handle == handle && info.name.as_ref() == Some(source))
+            })
+            .map(|(_, ctm, source)| (*ctm, *source));
+        desired.retain(|(handle, _, _)| handle != &output.handle);
+        if let Some((ctm, source)) = level {
+            desired.push((output.handle.clone(), ctm, source));
+        }
+    }
+
+    let mut capped = Vec::new();
+    for (handle, ctm, _) in desired.iter_mut() {
+        let Some(output) = state.outputs.iter().find(|output| &output.handle == handle) else {
+            continue;
+        };
+        if let Some(scale) = args.hdr_scale.filter(|_| output.hdr) {
+            ctm.saturation = 1.0 + (ctm.saturation - 1.0) * scale;
+        }
+        // A cap given for the output wins over the one of its preset.
+        if let Some(max) = output_max_saturation(output, args).or(output.max_saturation)
+            && ctm.saturation > max
+        {
+            capped.push((handle.clone(), ctm.saturation));
+            ctm.saturation = max;
+        }
+    }
+
+    desired.retain(|(handle, _, _)| state.manages_output(handle));
+    (desired, capped)
+}
+
+/// Keeps the level of the outputs whose rule just stopped matching for
+/// `grace`, so alt-tabbing or a dialog taking the focus for a moment
+/// doesn't make the colors flicker. An output gets its new level once
+/// the period is over, or right away if another rule matches it.
+fn hold_lost_levels(
+    state: &mut AppState,
+    handle: &LoopHandle<'static, AppState>,
+    grace: Duration,
+    desired: &mut Levels,
+) {
+    // Whatever forces a level or clears every CTM is never delayed.
+    if state.paused || state.disabled_by_file || !state.forced_saturations.is_empty() {
+        state.held_levels.clear();
         return;
+    }
+
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    state.held_levels.retain(|held| {
+        if held.until <= now {
+            expired.push(held.output.clone());
+        }
+        held.until > now
+    });
+
+    for (output, source) in state.level_sources.clone() {
+        if !matches!(source, LevelSource::Rule(_)) || expired.contains(&output) {
+            continue;
+        }
+        if desired
+            .iter()
+            .any(|(handle, _, source)| handle == &output && matches!(source, LevelSource::Rule(_)))
+        {
+            state.held_levels.retain(|held| held.output != output);
+            continue;
+        }
+
+        let held = match state.held_levels.iter().find(|held| held.output == output) {
+            Some(held) => (held.ctm, held.source),
+            None => {
+                let Some((_, ctm)) = state
+                    .applied_ctms
+                    .iter()
+                    .find(|(handle, _)| handle == &output)
+                else {
+                    continue;
+                };
+                debug!(
+                    "Holding the level of {} for {}ms",
+                    state.format_output(&output),
+                    grace.as_millis()
+                );
+                state.held_levels.push(HeldLevel {
+                    output: output.clone(),
+                    ctm: *ctm,
+                    source,
+                    until: now + grace,
+                });
+                // Nothing to do once it fires, the evaluation following
+                // it drops the held level.
+                handle
+                    .insert_source(Timer::from_duration(grace), |_, _, _| TimeoutAction::Drop)
+                    .unwrap();
+                (*ctm, source)
+            }
+        };
+        desired.retain(|(handle, _, _)| handle != &output);
+        desired.push((output, held.0, held.1));
+    }
+}
+
+fn requested_levels(state: &AppState, args: &Cli) -> Levels {
+    if state.disabled_by_file {
+        return Vec::new();
+    }
+
+    if let Some((_, saturation)) = state.forced_saturations.last() {
+        if *saturation == 1.0 && state.matrix_options.base_matrix.is_none() {
+            return Vec::new();
+        }
+
+        return state
+            .outputs
+            .iter()
+            .filter(|output| state.output_filter.allows(output))
+            .map(|output| {
+                (
+                    output.handle.clone(),
+                    color::Ctm::saturation(*saturation),
+                    LevelSource::Forced,
+                )
+            })
+            .collect();
+    }
+
+    if state.paused {
+        return Vec::new();
+    }
+
+    let matches: Vec<(WlOutput, usize)> = if args.aggregate {
+        aggregated_matches(state, args)
+    } else {
+        let mut matches: Vec<(WlOutput, usize)> = matching_top_level(state)
+            .map(|(top_level, rule)| {
+                target_outputs(state, top_level, rule, args)
+                    .into_iter()
+                    .map(|output| (output, rule))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (output, rule) in visible_matches(state, args)
+            .into_iter()
+            .chain(last_focused_matches(state, args))
+        {
+            if !matches.iter().any(|(handle, _)| handle == &output) {
+                matches.push((output, rule));
+            }
+        }
+        matches
     };
 
-    registry.bind::<ZwlrForeignToplevelManagerV1, _, _>(
-        top_level_manager_global.name,
-        top_level_manager_global.version,
-        &qh,
-        (),
-    );
-    info!("Bound to top level manager interface");
+    // When aggregating, the secondary saturation comes from the
+    // highest precedence rule that defines one.
+    let mut matched_rules: Vec<usize> = matches.iter().map(|(_, rule)| *rule).collect();
+    matched_rules.sort_by(|a, b| engine::compare(&state.rules, *a, *b));
+    let other_saturation = matched_rules.into_iter().find_map(|rule| {
+        state.rules[rule].other_saturation.map(|saturation| {
+            (
+                color::Ctm::saturation(saturation),
+                LevelSource::OtherSaturation(rule),
+            )
+        })
+    });
+    let global_rule = engine::best_global_match(&state.rules, &state.rule_context());
 
-    info!("CTM control initialized successfully");
-    let mut outputs_with_custom_ctm: Vec<WlOutput> = Vec::new();
-    const SATURATION: f64 = 3.3;
+    let applies_to_output = |rule: usize, output: &WlOutput| {
+        state.rules[rule].applies_to_output(|reference| {
+            state
+                .outputs
+                .iter()
+                .any(|info| &info.handle == output && info.is(reference))
+        })
+    };
+    let mut desired: Vec<(WlOutput, color::Ctm, LevelSource)> = matches
+        .into_iter()
+        .filter(|(output, rule)| applies_to_output(*rule, output))
+        .map(|(output, rule)| {
+            let base = base_saturation(state, &output, args);
+            (output, state.rule_ctm(rule, base), LevelSource::Rule(rule))
+        })
+        .collect();
 
-    loop {
-        event_queue.blocking_dispatch(&mut state).unwrap();
-        let desired_outputs_with_custom_ctm: &[WlOutput] = state
-            .focused_top_level()
-            .filter(|top_level| {
-                top_level
-                    .title
-                    .as_ref()
-                    .map_or(false, |title| args.title_match.contains(title))
+    for output in state.outputs.iter() {
+        if desired
+            .iter()
+            .any(|(handle, _, _)| handle == &output.handle)
+        {
+            continue;
+        }
+
+        let level = output_saturation(output, args)
+            .map(|saturation| (color::Ctm::saturation(saturation), LevelSource::Output))
+            .or(other_saturation)
+            .or_else(|| {
+                global_rule
+                    .filter(|rule| applies_to_output(*rule, &output.handle))
+                    .map(|rule| {
+                        let base = base_saturation(state, &output.handle, args);
+                        (state.rule_ctm(rule, base), LevelSource::Rule(rule))
+                    })
             })
-            .map_or(&[], |top_level: &TopLevelInfo| {
-                top_level.current_outputs.as_ref()
+            .or_else(|| {
+                args.auto_fullscreen
+                    .filter(|_| shows_fullscreen_top_level(state, &output.handle))
+                    .map(|saturation| (saturation, LevelSource::AutoFullscreen))
+                    .or_else(|| {
+                        workspace_saturation_for_output(state, output, args)
+                            .map(|saturation| (saturation, LevelSource::Workspace))
+                    })
+                    .or_else(|| {
+                        args.default_saturation
+                            .map(|saturation| (saturation, LevelSource::Default))
+                    })
+                    .or_else(|| {
+                        state
+                            .matrix_options
+                            .base_matrix
+                            .map(|_| (1.0, LevelSource::Base))
+                    })
+                    .map(|(saturation, source)| (color::Ctm::saturation(saturation), source))
             });
+        if let Some((ctm, source)) = level {
+            desired.push((output.handle.clone(), ctm, source));
+        }
+    }
+
+    desired
+}
+
+/// Outputs whose level has to change to go from the applied levels to
+/// the desired ones, along with their new level, `None` for the ones to
+/// clear.
+fn ctm_changes(
+    applied: &[(WlOutput, color::Ctm)],
+    desired: &[(WlOutput, color::Ctm)],
+) -> Vec<(WlOutput, Option<color::Ctm>)> {
+    let mut changed = Vec::new();
+    for (output, _) in applied.iter() {
+        if !desired.iter().any(|(handle, _)| handle == output) {
+            changed.push((output.clone(), None));
+        }
+    }
+
+    for (output, ctm) in desired.iter() {
+        if !applied.contains(&(output.clone(), *ctm)) {
+            changed.push((output.clone(), Some(*ctm)));
+        }
+    }
+    changed
+}
 
-        let (removed_outputs, unchanged_outputs, added_outputs) =
-            diff_lists(&outputs_with_custom_ctm, desired_outputs_with_custom_ctm);
+/// Moves the applied levels to the desired ones, but for the outputs
+/// whose matrix couldn't be sent, which keep the level they had.
+fn record_applied(
+    applied: &mut Vec<(WlOutput, color::Ctm)>,
+    desired: Vec<(WlOutput, color::Ctm)>,
+    skipped: &[WlOutput],
+) {
+    let previous = std::mem::replace(applied, desired);
+    applied.retain(|(output, _)| !skipped.contains(output));
+    applied.extend(
+        previous
+            .into_iter()
+            .filter(|(output, _)| skipped.contains(output)),
+    );
+}
 
-        for removed_output in removed_outputs.iter() {
-            clear_ctm_matrix_for_display(&ctm_control, removed_output);
+/// Sends the CTM changes required to go from the `applied` state to
+/// the `desired` one, committing only if something actually changed.
+/// Returns the new saturation of every output that changed, cleared
+/// outputs being reported with a saturation of 1.0.
+fn reconcile_ctms(
+    control: &HyprlandCtmControlManagerV1,
+    options: &MatrixOptions,
+    applied: &mut Vec<(WlOutput, color::Ctm)>,
+    desired: Vec<(WlOutput, color::Ctm)>,
+) -> Vec<(WlOutput, f64)> {
+    let changed = ctm_changes(applied, &desired);
+    let mut skipped = Vec::new();
+    for (output, ctm) in changed.iter() {
+        match ctm {
+            Some(ctm) => {
+                if !set_ctm_matrix_for_display(control, output, &ctm.matrix(options)) {
+                    skipped.push(output.clone());
+                }
+            }
+            None => clear_ctm_matrix_for_display(control, output),
         }
+    }
 
-        for added_output in added_outputs.iter() {
-            set_sat_ctm_for_display(&ctm_control, &added_output, SATURATION);
+    if !changed.is_empty() {
+        control.commit();
+        record_applied(applied, desired, &skipped);
+    }
+    changed
+        .into_iter()
+        .filter(|(output, _)| !skipped.contains(output))
+        .map(|(output, ctm)| (output, ctm.map_or(1.0, |ctm| ctm.saturation)))
+        .collect()
+}
+
+fn print_decision(state: &AppState, args: &Cli) {
+    match matching_top_level(state) {
+        Some((top_level, _)) => println!("match {}", format_top_level(top_level)),
+        None => println!(
+            "no match (focused: {})",
+            state
+                .focused_top_level()
+                .map_or("none".to_string(), format_top_level)
+        ),
+    }
+
+    for (output, ctm) in desired_ctms(state, args) {
+        match ctm.temperature {
+            Some(temperature) => println!(
+                "output {} saturation={} temperature={}K",
+                state.format_output(&output),
+                ctm.saturation,
+                temperature
+            ),
+            None => println!(
+                "output {} saturation={}",
+                state.format_output(&output),
+                ctm.saturation
+            ),
         }
+    }
+}
+
+/// Tells the control clients, and the D-Bus service, about the state
+/// after an event loop iteration.
+fn publish_state(state: &mut AppState) {
+    control::publish_status(state);
+    #[cfg(feature = "dbus")]
+    dbus::publish(state);
+    #[cfg(feature = "tray")]
+    tray::publish(state);
+}
+
+fn uses_hyprland_ipc(args: &Cli) -> bool {
+    !args.workspace_sat.is_empty()
+        || args.follow_cursor
+        || args.keep_under_special
+        || args.hyprland_tags
+        || args.output_filter.skip_hdr
+        || args.hdr_scale.is_some()
+}
 
-        if !removed_outputs.is_empty() || !added_outputs.is_empty() {
-            ctm_control.commit();
-            outputs_with_custom_ctm = unchanged_outputs
+fn refresh_cursor_monitor(state: &mut AppState) {
+    match hyprland::cursor_pos() {
+        Ok(pos) => {
+            let monitor = state
+                .hyprland_monitors
                 .iter()
-                .map(|&output| output.to_owned())
-                .chain(added_outputs.iter().map(|&output| output.to_owned()))
-                .collect();
+                .find(|monitor| monitor.contains(pos.x, pos.y))
+                .map(|monitor| monitor.name.clone());
+            if monitor != state.cursor_monitor {
+                debug!("Cursor moved to monitor {:?}", monitor);
+                state.cursor_monitor = monitor;
+            }
+        }
+        Err(e) => error!("Couldn't query Hyprland cursor position: {}", e),
+    }
+}
+
+/// Hyprland doesn't emit events on pointer motion, so the cursor
+/// position is polled periodically instead.
+fn watch_cursor_monitor(handle: &LoopHandle<AppState>, interval: Duration) {
+    handle
+        .insert_source(Timer::immediate(), move |_, _, state| {
+            refresh_cursor_monitor(state);
+            TimeoutAction::ToDuration(interval)
+        })
+        .unwrap();
+}
+
+/// While this file exists in `$XDG_RUNTIME_DIR`, every output is left
+/// untouched. Meant as an emergency switch that works from anywhere,
+/// even over SSH, with just `touch` and `rm`.
+const DISABLE_FILE_NAME: &str = "hyprland-vibrance.disable";
+const DISABLE_FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn disable_file_path() -> Option<std::path::PathBuf> {
+    std::env::var("XDG_RUNTIME_DIR")
+        .ok()
+        .map(|dir| std::path::PathBuf::from(dir).join(DISABLE_FILE_NAME))
+}
+
+fn refresh_disable_file(state: &mut AppState, path: &std::path::Path) {
+    let disabled = path.exists();
+    if disabled != state.disabled_by_file {
+        if disabled {
+            info!("{} exists, leaving every output untouched", path.display());
+        } else {
+            info!("{} removed, applying saturation again", path.display());
+        }
+        state.disabled_by_file = disabled;
+    }
+}
+
+fn watch_disable_file(handle: &LoopHandle<AppState>) {
+    let Some(path) = disable_file_path() else {
+        warn!("XDG_RUNTIME_DIR is not set, the disable file won't be watched");
+        return;
+    };
+
+    handle
+        .insert_source(Timer::immediate(), move |_, _, state| {
+            refresh_disable_file(state, &path);
+            TimeoutAction::ToDuration(DISABLE_FILE_POLL_INTERVAL)
+        })
+        .unwrap();
+}
+
+/// SIGINT and SIGTERM make the daemon exit the same way `ctl quit`
+/// does, clearing every output it touched before going away instead of
+/// leaving them oversaturated.
+fn watch_termination_signals(handle: &LoopHandle<AppState>) {
+    let result = signals::listen(handle, &[libc::SIGINT, libc::SIGTERM], |state, signal| {
+        let name = if signal == libc::SIGINT {
+            "SIGINT"
+        } else {
+            "SIGTERM"
+        };
+        if state.quitting {
+            debug!("Received {} while already exiting", name);
+            return;
         }
+        info!("Received {}, resetting every CTM", name);
+        state.quitting = true;
+    });
+    if let Err(e) = result {
+        error!("Couldn't handle SIGINT and SIGTERM: {}", e);
+    }
+}
+
+/// SIGUSR1 pauses and resumes, and SIGUSR2 cycles through the given
+/// levels, for binds that don't want to deal with the control socket.
+fn watch_user_signals(handle: &LoopHandle<AppState>) {
+    let result = signals::listen(
+        handle,
+        &[libc::SIGUSR1, libc::SIGUSR2],
+        move |state, signal| {
+            if signal == libc::SIGUSR1 {
+                debug!("Received SIGUSR1");
+                state.set_paused(!state.paused);
+                return;
+            }
+
+            debug!("Received SIGUSR2");
+            if !state.cycle_saturation() {
+                warn!(
+                    "Received SIGUSR2, but there are no levels to cycle through (see --cycle-sat)"
+                );
+            }
+        },
+    );
+    if let Err(e) = result {
+        error!("Couldn't handle SIGUSR1 and SIGUSR2: {}", e);
+    }
+}
+
+const OUTPUT_POWER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn refresh_hyprland_monitors(state: &mut AppState) {
+    match state.hyprland_cache.monitors() {
+        Ok(monitors) => state.hyprland_monitors = monitors.to_vec(),
+        Err(e) => error!("Couldn't query Hyprland monitors: {}", e),
+    }
+    let mut woken = Vec::new();
+    for output in state.outputs.iter_mut() {
+        let monitor = state
+            .hyprland_monitors
+            .iter()
+            .find(|monitor| output.name.as_ref() == Some(&monitor.name));
+        output.serial = monitor
+            .map(|monitor| monitor.serial.clone())
+            .filter(|serial| !serial.is_empty());
+        output.mirror_of = monitor
+            .and_then(|monitor| monitor.mirrored(&state.hyprland_monitors))
+            .map(str::to_string);
+        let hdr = monitor.is_some_and(hyprland::Monitor::is_hdr);
+        if hdr != output.hdr {
+            info!(
+                "{} {} HDR mode",
+                output.name.as_deref().unwrap_or_default(),
+                if hdr { "entered" } else { "left" }
+            );
+            output.hdr = hdr;
+        }
+
+        let asleep = monitor.is_some_and(hyprland::Monitor::is_asleep);
+        if asleep != output.asleep {
+            info!(
+                "{} {}",
+                output.name.as_deref().unwrap_or_default(),
+                if asleep { "went to sleep" } else { "woke up" }
+            );
+            output.asleep = asleep;
+            if !asleep {
+                woken.push(output.handle.clone());
+            }
+        }
+    }
+
+    for output in woken {
+        resync_output(state, &output);
+    }
+}
+
+/// Forgets what was sent to an output that just woke up, which may
+/// have lost its CTM in the meantime, so the next evaluation sends the
+/// desired one again.
+fn resync_output(state: &mut AppState, output: &WlOutput) {
+    state.applied_ctms.retain(|(handle, _)| handle != output);
+    if let Some(transitions) = state.transitions.as_mut() {
+        transitions.forget_output(output);
+    }
+    if let Some(ctm_control) = state.ctm_manager.as_ref() {
+        clear_ctm_matrix_for_display(ctm_control, output);
+        ctm_control.commit();
+    }
+}
+
+/// Leaves whatever was last sent to the outputs that are asleep, and
+/// the levels they had in the list of applied ones, until they wake up.
+fn defer_asleep_outputs(state: &AppState, desired: &mut Vec<(WlOutput, color::Ctm)>) {
+    for output in state.outputs.iter().filter(|output| output.asleep) {
+        desired.retain(|(handle, _)| handle != &output.handle);
+        if let Some(applied) = state
+            .applied_ctms
+            .iter()
+            .find(|(handle, _)| handle == &output.handle)
+        {
+            desired.push(applied.clone());
+        }
+    }
+}
+
+/// Hyprland doesn't tell when a monitor is turned off or back on, so
+/// the monitor list is queried again every once in a while.
+fn watch_output_power(handle: &LoopHandle<AppState>) {
+    handle
+        .insert_source(
+            Timer::from_duration(OUTPUT_POWER_POLL_INTERVAL),
+            |_, _, state| {
+                state.hyprland_cache.invalidate_monitors();
+                refresh_hyprland_monitors(state);
+                TimeoutAction::ToDuration(OUTPUT_POWER_POLL_INTERVAL)
+            },
+        )
+        .unwrap();
+}
+
+/// Whether some output reference needs the serial numbers of the
+/// outputs.
+fn references_serials(state: &AppState, args: &Cli) -> bool {
+    let mut references = state
+        .rules
+        .iter()
+        .flat_map(|rule| rule.outputs.iter())
+        .chain(state.output_filter.include.iter())
+        .chain(state.output_filter.exclude.iter())
+        .chain(args.output_sat.iter().map(|level| &level.output));
+    references.any(|reference| reference.starts_with("serial="))
+}
+
+/// Hyprland reports an empty active window when focus moves to an
+/// empty workspace, which can happen way before the previously focused
+/// top level gets deactivated through the wlr protocol (if ever).
+fn is_empty_active_window_event(event: &hyprland::Event) -> bool {
+    match event.name.as_str() {
+        "activewindow" => event.data == ",",
+        "activewindowv2" => event.data.is_empty(),
+        _ => false,
+    }
+}
+
+fn connect_hyprland_events(track_monitors: bool) -> Option<hyprland::EventStream> {
+    match hyprland::EventStream::connect() {
+        Ok(events) => Some(events),
+        Err(e) if track_monitors => {
+            error!(
+                "Couldn't connect to the Hyprland event socket, monitor and workspace changes won't be tracked: {}",
+                e
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Couldn't connect to the Hyprland event socket: {}", e);
+            None
+        }
+    }
+}
+
+/// Listens to the Hyprland event socket to catch focus changes the
+/// wlr protocol reports late, follow the active submap, keep the cached
+/// IPC queries fresh and, if `track_monitors` is set, to keep the
+/// monitor list (including the active workspace of each of them) up to
+/// date. When `follow_cursor` is set, the cursor position is queried
+/// again as soon as the focus moves to another monitor, without waiting
+/// for the next poll. Returns whether the events are listened to.
+fn watch_hyprland_events(
+    handle: &LoopHandle<AppState>,
+    mut events: hyprland::EventStream,
+    track_monitors: bool,
+    follow_cursor: bool,
+) -> bool {
+    let stream = match events.stream().try_clone() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Couldn't listen to Hyprland events: {}", e);
+            return false;
+        }
+    };
+    let source = Generic::new(stream, Interest::READ, Mode::Level);
+    let inserted = handle.insert_source(source, move |_, _, state| {
+        let received = match events.read_events() {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Stopped listening to Hyprland events: {}", e);
+                state.hyprland_cache.set_tracking(false);
+                return Ok(PostAction::Remove);
+            }
+        };
+        state.hyprland_cache.invalidate(&received);
+
+        if received.iter().any(is_empty_active_window_event) {
+            debug!("Focus moved to an empty workspace");
+            state.notify_no_top_level_focused();
+        }
+
+        if let Some(event) = received.iter().rev().find(|event| event.name == "submap") {
+            debug!("Hyprland submap changed to '{}'", event.data);
+            state.submap = Some(event.data.clone()).filter(|submap| !submap.is_empty());
+        }
+
+        if received.iter().any(hyprland::Event::is_client_event) {
+            state.correlate_top_levels();
+            state.refresh_window_info();
+        }
+
+        // Hyprland tells which window got the focus before the wlr
+        // protocol does.
+        if let Some(handle) = received
+            .iter()
+            .rev()
+            .find(|event| event.name == "activewindowv2")
+            .and_then(hyprland::Event::active_window_address)
+            .and_then(|address| state.top_level_for_address(address))
+            .map(|top_level| top_level.handle.clone())
+        {
+            state.notify_top_level_focus_changed(&handle, true);
+        }
+
+        if track_monitors && received.iter().any(hyprland::Event::is_monitor_event) {
+            refresh_hyprland_monitors(state);
+        }
+
+        if follow_cursor
+            && received
+                .iter()
+                .any(|event| matches!(event.name.as_str(), "focusedmon" | "focusedmonv2"))
+        {
+            refresh_cursor_monitor(state);
+        }
+        Ok(PostAction::Continue)
+    });
+    if let Err(e) = inserted {
+        error!("Couldn't listen to Hyprland events: {}", e.error);
+        return false;
+    }
+    true
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a command to the running daemon
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Interactively try saturation levels on an output
+    Calibrate(CalibrateArgs),
+    /// Interactively manage the rules of the running daemon
+    #[cfg(feature = "tui")]
+    Manage,
+    /// Live view of the outputs and windows seen by the running daemon
+    #[cfg(feature = "tui")]
+    Top,
+    /// Tint an output in sepia for a moment to check that CTMs work
+    SelfTest(SelfTestArgs),
+    /// Apply a level to some outputs, without tracking any window, and
+    /// keep it until terminated
+    Apply(ApplyArgs),
+    /// Put the native colors back on every output and exit, without
+    /// going through the daemon
+    Clear,
+    /// Run a command with every output at its native colors, restoring
+    /// the previous state once it exits
+    WithIdentity {
+        /// Command to run, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+    /// Run a command with the given level on every output, restoring
+    /// the previous state once it exits
+    With {
+        /// Saturation level, between 0.0 and 4.0 or as a percentage
+        #[arg(short, long, visible_alias = "sat", value_parser = units::parse_saturation)]
+        sat_level: f64,
+        /// Command to run, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+    /// Show the state of the running daemon
+    Status {
+        /// Print the status as JSON
+        #[arg(long, conflicts_with = "stream")]
+        json: bool,
+        /// Keep printing the status as a JSON line for a Waybar custom
+        /// module every time it changes
+        #[arg(long)]
+        stream: bool,
+    },
+    /// Replace the level of the matched rules of the running daemon
+    /// until `reset`
+    Set {
+        /// Saturation level, between 0.0 and 4.0 or as a percentage
+        #[arg(value_parser = units::parse_saturation)]
+        sat_level: f64,
+    },
+    /// Go back to the levels of the rules after `set`
+    Reset,
+    /// Clear every CTM and stop applying any saturation until `resume`
+    Pause,
+    /// Start applying saturation again after `pause`
+    Resume,
+    /// Stop applying any saturation, or start again if stopped
+    Toggle,
+    /// Make the running daemon read its rules again
+    Reload,
+    /// Show how a matrix transforms reference colors
+    Probe(ProbeArgs),
+    /// Propose rules for the programs often run fullscreen without one,
+    /// as recorded with --track-usage
+    Suggest(SuggestArgs),
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Check the config file and the rules, and exit with a non-zero
+    /// status if something is wrong
+    CheckConfig,
+    /// Convert the configuration of another tool into rules
+    Migrate {
+        /// Tool the configuration comes from
+        #[arg(long, value_enum)]
+        from: MigrateSource,
+        /// Path of the configuration to convert
+        path: std::path::PathBuf,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum MigrateSource {
+    Vibrantlinux,
+}
+
+#[derive(clap::Args, Debug)]
+struct SuggestArgs {
+    /// Only suggest programs that went fullscreen at least this many
+    /// times
+    #[arg(long, default_value_t = 3)]
+    min_count: u32,
+
+    /// Level of the suggested rules
+    #[arg(
+        short,
+        long,
+        visible_alias = "sat",
+        value_parser = units::parse_saturation,
+        default_value_t = 1.5
+    )]
+    sat_level: f64,
+}
+
+#[derive(clap::Args, Debug)]
+#[group(id = "matrix_source", required = true, multiple = false)]
+struct ProbeArgs {
+    /// Probe the saturation matrix of this level
+    #[arg(
+        short,
+        long,
+        visible_alias = "sat",
+        value_parser = units::parse_saturation,
+        group = "matrix_source"
+    )]
+    sat_level: Option<f64>,
+
+    /// Probe a raw matrix, as 9 numbers in row-major order
+    #[arg(long, value_parser = probe::parse_matrix, group = "matrix_source")]
+    matrix: Option<[f64; 9]>,
+
+    /// Probe the matrix the running daemon applies to this output
+    #[arg(short, long, group = "matrix_source")]
+    output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CalibrateArgs {
+    /// Name of the output to calibrate (e.g. DP-1)
+    #[arg(short, long)]
+    output: String,
+
+    /// Saturation level to start with
+    #[arg(short, long, default_value_t = 1.0, value_parser = units::parse_saturation)]
+    sat_level: f64,
+
+    /// Cover the output with a color test pattern while calibrating
+    #[cfg(feature = "osd")]
+    #[arg(long)]
+    pattern: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct SelfTestArgs {
+    /// Name of the output to test (e.g. DP-1)
+    #[arg(short, long)]
+    output: String,
+
+    /// How long to keep the test matrix applied, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    duration: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct ApplyArgs {
+    /// Name of an output to apply the level to (e.g. DP-1), every
+    /// output when not given
+    #[arg(short, long)]
+    output: Vec<String>,
+
+    /// Saturation level, between 0.0 and 4.0 or as a percentage
+    #[arg(short, long, visible_alias = "sat", value_parser = units::parse_saturation)]
+    sat_level: f64,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Manage the rules of the running daemon
+    Rule {
+        #[command(subcommand)]
+        command: CtlRuleCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlRuleCommand {
+    /// Enable a rule disabled previously
+    Enable { name: String },
+    /// Disable a rule until it's enabled again
+    Disable { name: String },
+    /// Add a rule, e.g. 'name=game initial_class=cs2 sat=3.2', until the
+    /// daemon exits
+    Add {
+        #[arg(value_parser = |s: &str| s.parse::<Rule>().map(Box::new))]
+        rule: Box<Rule>,
+        /// Give the rule precedence over every other rule of the same
+        /// priority, instead of the lowest one
+        #[arg(long)]
+        first: bool,
+    },
+    /// Remove the rules with the given name
+    Remove { name: String },
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, subcommand_negates_reqs = true)]
+#[command(group(clap::ArgGroup::new("window_match").multiple(true)))]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    matrix: MatrixOptions,
+
+    #[command(flatten)]
+    output_filter: OutputFilter,
+
+    /// Wayland display to connect to, e.g. wayland-1, instead of the
+    /// one in WAYLAND_DISPLAY
+    #[arg(long, value_name = "NAME", global = true)]
+    wayland_display: Option<String>,
+
+    /// Saturation level, between 0.0 and 4.0 or as a percentage (e.g.
+    /// 150%)
+    #[arg(
+        short,
+        long,
+        visible_alias = "sat",
+        value_parser = units::parse_saturation,
+        group = "level",
+        requires = "window_match"
+    )]
+    sat_level: Option<f64>,
+
+    /// Saturation level on the NVIDIA digital vibrance scale (-1024 to
+    /// 1023, 0 leaving colors untouched), as an alternative to
+    /// --sat-level
+    #[arg(
+        long,
+        value_name = "VIBRANCE",
+        value_parser = units::parse_nv_vibrance,
+        allow_negative_numbers = true,
+        group = "level",
+        requires = "window_match"
+    )]
+    nv_vibrance: Option<f64>,
+
+    /// Title match filters (can be used multiple times)
+    #[arg(
+        short,
+        long,
+        num_args = 1..,
+        value_name = "TITLE",
+        group = "window_match",
+        requires = "level"
+    )]
+    title_match: Vec<String>,
+
+    /// App id match filters, where * matches any run of characters
+    /// (can be used multiple times). When given along with
+    /// --title-match, both have to match
+    #[arg(
+        long,
+        num_args = 1..,
+        value_name = "APP_ID",
+        group = "window_match",
+        requires = "level"
+    )]
+    app_id_match: Vec<String>,
+
+    /// Config file, instead of
+    /// $XDG_CONFIG_HOME/hyprland-vibrance/config.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+
+    /// Rules of the config file, which come after every other rule.
+    #[arg(skip)]
+    config_rules: Vec<(Rule, String)>,
+
+    /// Additional rule, e.g. 'name=cs title="Counter-Strike 2" sat=3.3'
+    /// (can be used multiple times). Rules given earlier take precedence,
+    /// the one formed by --title-match/--app-id-match and --sat-level (or
+    /// --nv-vibrance) being the first
+    #[arg(short, long, value_name = "RULE")]
+    rule: Vec<Rule>,
+
+    /// File with one rule per line, taking precedence after the rules
+    /// given with --rule
+    #[arg(long, value_name = "PATH")]
+    rules_file: Option<std::path::PathBuf>,
+
+    /// Store the rules added at runtime in a section at the end of the
+    /// rules file, so they're kept across restarts
+    #[arg(long, requires = "rules_file")]
+    write_back: bool,
+
+    /// Apply the rules to every matching top level that's not
+    /// minimized, instead of only to the focused one. Note that
+    /// windows on hidden workspaces are also taken into account
+    #[arg(long, conflicts_with = "follow_cursor")]
+    aggregate: bool,
+
+    /// Don't register the `hyprland-vibrance:toggle` and
+    /// `hyprland-vibrance:cycle` global shortcuts
+    #[arg(long)]
+    no_global_shortcuts: bool,
+
+    /// Give the windows with a `vibrance:LEVEL` Hyprland tag that
+    /// level, e.g. from `windowrule = tag +vibrance:3.2, class:cs2`
+    #[arg(long)]
+    hyprland_tags: bool,
+
+    /// Keep the level of the focused window while a window on a
+    /// special workspace (a scratchpad) shown over it has the focus
+    #[arg(long)]
+    keep_under_special: bool,
+
+    /// Saturation level for the outputs showing the given Hyprland
+    /// workspace (id or name) when no focused window matches (can be
+    /// used multiple times)
+    #[arg(long, value_name = "WORKSPACE=SAT", value_parser = parse_workspace_sat)]
+    workspace_sat: Vec<WorkspaceSaturation>,
+
+    /// Saturation level the given output (connector name or
+    /// description) always has unless a rule applies to one of its
+    /// windows (can be used multiple times)
+    #[arg(long, value_name = "OUTPUT=SAT", value_parser = parse_output_sat)]
+    output_sat: Vec<OutputSaturation>,
+
+    /// Highest saturation level the given output (connector name or
+    /// description) gets, whatever the rules ask for (can be used
+    /// multiple times)
+    #[arg(long, value_name = "OUTPUT=SAT", value_parser = parse_output_sat)]
+    output_max_sat: Vec<OutputSaturation>,
+
+    /// Level SIGUSR2 or the cycle shortcut switch to, replacing the
+    /// level of the matched rules (can be used multiple times). Each
+    /// SIGUSR2 moves to the next one, and after the last back to the
+    /// levels of the rules
+    #[arg(long, value_name = "SAT", value_parser = units::parse_saturation)]
+    cycle_sat: Vec<f64>,
+
+    /// Apply the saturation of a matching window to the output
+    /// containing the pointer instead of the outputs the window is on
+    #[arg(long)]
+    follow_cursor: bool,
+
+    /// Fraction of the change of every level kept on the outputs
+    /// Hyprland drives in HDR mode (e.g. 50%)
+    #[arg(long, value_name = "FRACTION", value_parser = units::parse_fraction)]
+    hdr_scale: Option<f64>,
+
+    /// Apply the saturation of a matching window to every output, e.g.
+    /// for mirrored or multi-monitor immersive setups
+    #[arg(long, conflicts_with = "follow_cursor")]
+    all_outputs: bool,
+
+    /// Let every output keep the level of the window focused last on
+    /// it, so a game and a video on different outputs both keep their
+    /// own
+    #[arg(long, conflicts_with = "aggregate")]
+    per_output_focus: bool,
+
+    /// How often to poll the cursor position when following the
+    /// cursor, in milliseconds
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 100,
+        requires = "follow_cursor"
+    )]
+    cursor_poll_interval: u64,
+
+    /// Wait this long after the initial state is known before applying
+    /// any CTM, in milliseconds, to avoid flashes while logging in
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    warm_up: u64,
+
+    /// Keep the level of a rule this many milliseconds after its window
+    /// loses the focus, so alt-tabbing doesn't make the colors flicker
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    focus_grace: u64,
+
+    /// Fade between levels over this many milliseconds instead of
+    /// switching right away
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    transition: u64,
+
+    /// Pace of the fades between levels
+    #[arg(long, value_enum, default_value_t)]
+    transition_easing: transition::Easing,
+
+    /// Show an on-screen indicator on the affected output whenever its
+    /// saturation changes
+    #[cfg(feature = "osd")]
+    #[arg(long)]
+    osd: bool,
+
+    /// Show a desktop notification whenever saturation is applied to
+    /// an output or cleared from it
+    #[cfg(feature = "notifications")]
+    #[arg(long)]
+    notify: bool,
+
+    /// Show a tray icon to pause and resume, and to switch between the
+    /// rules and the --cycle-sat levels
+    #[cfg(feature = "tray")]
+    #[arg(long)]
+    tray: bool,
+
+    /// Register the dev.devcexx.HyprlandVibrance service on the D-Bus
+    /// session bus
+    #[cfg(feature = "dbus")]
+    #[arg(long)]
+    dbus: bool,
+
+    /// Create a named pipe at the given path taking a saturation level,
+    /// `reset`, `on`, `off` or `toggle` per line
+    #[arg(long, value_name = "PATH", conflicts_with = "once")]
+    fifo: Option<std::path::PathBuf>,
+
+    /// Evaluate the rules once against the current focus state,
+    /// print the decision and exit
+    #[arg(long)]
+    once: bool,
+
+    /// Ask an already running instance to reset its CTMs and exit,
+    /// and take over, instead of refusing to start
+    #[arg(long, conflicts_with = "once")]
+    replace: bool,
+
+    /// Together with --once, apply the decision and hold it until the
+    /// program is terminated instead of exiting
+    #[arg(long, requires = "once")]
+    hold: bool,
+
+    /// Apply this saturation level to every output showing a fullscreen
+    /// window, when no rule applies to it
+    #[arg(long, value_name = "SAT", value_parser = units::parse_saturation)]
+    auto_fullscreen: Option<f64>,
+
+    /// Apply this saturation level to every output nothing else gives
+    /// a level to, instead of leaving it untouched. Boosts are relative
+    /// to it too
+    #[arg(long, value_name = "SAT", value_parser = units::parse_saturation)]
+    default_saturation: Option<f64>,
+
+    /// Don't cap the saturation of known monitor models
+    #[arg(long)]
+    no_presets: bool,
+
+    /// Record, locally, the programs going fullscreen without any rule
+    /// matching them, for `suggest`
+    #[arg(long)]
+    track_usage: bool,
+
+    /// Accept saturation levels beyond 0.0 - 4.0, as long as the
+    /// resulting matrices can be sent to the compositor
+    #[arg(long, global = true)]
+    allow_extreme: bool,
+
+    /// Print candidates for a shell completion, one per line, and exit
+    #[arg(long, value_enum, value_name = "KIND", hide = true)]
+    complete: Option<completions::CompletionKind>,
+}
+impl Cli {
+    /// Builds the rule set, in precedence order, along with a
+    /// description of where each rule comes from.
+    fn rules(&self) -> Result<Vec<(Rule, String)>, String> {
+        self.rule_sources().load_with(self.config_rules.clone())
+    }
+
+    /// Where the rules come from, to load them again later.
+    fn rule_sources(&self) -> reload::RuleSources {
+        let title_match_rule = self.sat_level.or(self.nv_vibrance).map(|saturation| {
+            let rule = Rule {
+                titles: self.title_match.clone(),
+                app_ids: self.app_id_match.clone(),
+                saturation,
+                ..Default::default()
+            };
+            (rule, "--title-match/--app-id-match".to_string())
+        });
+
+        let mut command_line: Vec<(Rule, String)> = title_match_rule.into_iter().collect();
+        command_line.extend(
+            self.rule
+                .iter()
+                .enumerate()
+                .map(|(idx, rule)| (rule.clone(), format!("--rule #{}", idx + 1))),
+        );
+
+        reload::RuleSources {
+            command_line,
+            rules_file: self.rules_file.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    /// Takes the options not given on the command line, and the rules,
+    /// from the config file.
+    fn apply_config(
+        &mut self,
+        config: config::Config,
+        path: &std::path::Path,
+        matches: &ArgMatches,
+    ) -> Result<(), String> {
+        fn fill<T>(matches: &ArgMatches, id: &str, current: &mut T, value: Option<T>) {
+            if matches.value_source(id) != Some(ValueSource::CommandLine)
+                && let Some(value) = value
+            {
+                *current = value;
+            }
+        }
+        let in_file = |e: String| format!("{}: {}", path.display(), e);
+        let rules = config.named_rules(path)?;
+
+        fill(matches, "aggregate", &mut self.aggregate, config.aggregate);
+        fill(
+            matches,
+            "keep_under_special",
+            &mut self.keep_under_special,
+            config.keep_under_special,
+        );
+        fill(
+            matches,
+            "hyprland_tags",
+            &mut self.hyprland_tags,
+            config.hyprland_tags,
+        );
+        fill(
+            matches,
+            "no_global_shortcuts",
+            &mut self.no_global_shortcuts,
+            config.no_global_shortcuts,
+        );
+        fill(
+            matches,
+            "all_outputs",
+            &mut self.all_outputs,
+            config.all_outputs,
+        );
+        fill(
+            matches,
+            "follow_cursor",
+            &mut self.follow_cursor,
+            config.follow_cursor,
+        );
+        fill(
+            matches,
+            "per_output_focus",
+            &mut self.per_output_focus,
+            config.per_output_focus,
+        );
+        fill(
+            matches,
+            "cursor_poll_interval",
+            &mut self.cursor_poll_interval,
+            config.cursor_poll_interval,
+        );
+        fill(matches, "warm_up", &mut self.warm_up, config.warm_up);
+        fill(
+            matches,
+            "focus_grace",
+            &mut self.focus_grace,
+            config.focus_grace,
+        );
+        fill(
+            matches,
+            "transition",
+            &mut self.transition,
+            config.transition,
+        );
+        let transition_easing = config
+            .transition_easing
+            .map(|value| clap::ValueEnum::from_str(&value, true))
+            .transpose()
+            .map_err(in_file)?;
+        fill(
+            matches,
+            "transition_easing",
+            &mut self.transition_easing,
+            transition_easing,
+        );
+        fill(
+            matches,
+            "no_presets",
+            &mut self.no_presets,
+            config.no_presets,
+        );
+        fill(
+            matches,
+            "track_usage",
+            &mut self.track_usage,
+            config.track_usage,
+        );
+        if self.fifo.is_none() {
+            self.fifo = config.fifo;
+        }
+        match config.osd {
+            #[cfg(feature = "osd")]
+            osd => fill(matches, "osd", &mut self.osd, osd),
+            #[cfg(not(feature = "osd"))]
+            Some(true) => warn!("{}: osd needs the osd feature", path.display()),
+            #[cfg(not(feature = "osd"))]
+            _ => {}
+        }
+        match config.dbus {
+            #[cfg(feature = "dbus")]
+            dbus => fill(matches, "dbus", &mut self.dbus, dbus),
+            #[cfg(not(feature = "dbus"))]
+            Some(true) => warn!("{}: dbus needs the dbus feature", path.display()),
+            #[cfg(not(feature = "dbus"))]
+            _ => {}
+        }
+        match config.tray {
+            #[cfg(feature = "tray")]
+            tray => fill(matches, "tray", &mut self.tray, tray),
+            #[cfg(not(feature = "tray"))]
+            Some(true) => warn!("{}: tray needs the tray feature", path.display()),
+            #[cfg(not(feature = "tray"))]
+            _ => {}
+        }
+        match config.notify {
+            #[cfg(feature = "notifications")]
+            notify => fill(matches, "notify", &mut self.notify, notify),
+            #[cfg(not(feature = "notifications"))]
+            Some(true) => warn!("{}: notify needs the notifications feature", path.display()),
+            #[cfg(not(feature = "notifications"))]
+            _ => {}
+        }
+
+        if self.cycle_sat.is_empty() {
+            self.cycle_sat = config
+                .cycle_sat
+                .iter()
+                .map(|value| {
+                    config::value_to_string(value).and_then(|value| units::parse_saturation(&value))
+                })
+                .collect::<Result<_, _>>()
+                .map_err(in_file)?;
+        }
+        if self.workspace_sat.is_empty() {
+            self.workspace_sat = config
+                .workspace_sat
+                .iter()
+                .map(|value| parse_workspace_sat(value))
+                .collect::<Result<_, _>>()
+                .map_err(in_file)?;
+        }
+        if self.output_sat.is_empty() {
+            self.output_sat = config
+                .output_sat
+                .iter()
+                .map(|value| parse_output_sat(value))
+                .collect::<Result<_, _>>()
+                .map_err(in_file)?;
+        }
+        if self.output_max_sat.is_empty() {
+            self.output_max_sat = config
+                .output_max_sat
+                .iter()
+                .map(|value| parse_output_sat(value))
+                .collect::<Result<_, _>>()
+                .map_err(in_file)?;
+        }
+        if self.auto_fullscreen.is_none()
+            && let Some(value) = config.auto_fullscreen.as_ref()
+        {
+            let value = config::value_to_string(value).map_err(in_file)?;
+            self.auto_fullscreen = Some(units::parse_saturation(&value).map_err(in_file)?);
+        }
+        if self.default_saturation.is_none()
+            && let Some(value) = config.default_saturation.as_ref()
+        {
+            let value = config::value_to_string(value).map_err(in_file)?;
+            self.default_saturation = Some(units::parse_saturation(&value).map_err(in_file)?);
+        }
+
+        let algorithm = config
+            .algorithm
+            .map(|value| clap::ValueEnum::from_str(&value, true))
+            .transpose()
+            .map_err(in_file)?;
+        fill(matches, "algorithm", &mut self.matrix.algorithm, algorithm);
+        let gamma_compensation = config
+            .gamma_compensation
+            .map(|value| clap::ValueEnum::from_str(&value, true))
+            .transpose()
+            .map_err(in_file)?;
+        fill(
+            matches,
+            "gamma_compensation",
+            &mut self.matrix.gamma_compensation,
+            gamma_compensation,
+        );
+        if self.matrix.base_matrix.is_none()
+            && let Some(values) = config.base_matrix
+        {
+            let matrix: [f64; 9] = values.try_into().map_err(|values: Vec<f64>| {
+                in_file(format!("base_matrix takes 9 values, got {}", values.len()))
+            })?;
+            color::check_protocol_limits(&matrix).map_err(in_file)?;
+            self.matrix.base_matrix = Some(matrix);
+        }
+        fill(
+            matches,
+            "skip_hdr",
+            &mut self.output_filter.skip_hdr,
+            config.skip_hdr,
+        );
+        if self.hdr_scale.is_none()
+            && let Some(value) = config.hdr_scale.as_ref()
+        {
+            let value = config::value_to_string(value).map_err(in_file)?;
+            self.hdr_scale = Some(units::parse_fraction(&value).map_err(in_file)?);
+        }
+        if let Some(outputs) = config.outputs {
+            if self.output_filter.include.is_empty() {
+                self.output_filter.include = outputs.include;
+            }
+            if self.output_filter.exclude.is_empty() {
+                self.output_filter.exclude = outputs.exclude;
+            }
+        }
+        if self.matrix.weights.is_none()
+            && let Some(values) = config.weights
+        {
+            let weights: [f64; 3] = values.try_into().map_err(|values: Vec<f64>| {
+                in_file(format!("weights takes 3 values, got {}", values.len()))
+            })?;
+            self.matrix.weights = Some(color::check_weights(weights).map_err(in_file)?);
+        }
+
+        if self.aggregate && self.follow_cursor {
+            return Err(in_file(
+                "aggregate and follow_cursor can't be used together".to_string(),
+            ));
+        }
+        if self.all_outputs && self.follow_cursor {
+            return Err(in_file(
+                "all_outputs and follow_cursor can't be used together".to_string(),
+            ));
+        }
+        if self.aggregate && self.per_output_focus {
+            return Err(in_file(
+                "aggregate and per_output_focus can't be used together".to_string(),
+            ));
+        }
+
+        self.config_rules = rules;
+        Ok(())
+    }
+}
+
+/// Sends a request to the running daemon, logging its warnings, and
+/// exits if it fails.
+fn send_control_request(request: &control::Request) -> control::Response {
+    match control::send(request) {
+        Ok(response) if response.ok => {
+            for warning in response.warnings.iter() {
+                warn!("{}", warning);
+            }
+            response
+        }
+        Ok(response) => Error::Other(response.error.unwrap_or_default()).exit(),
+        Err(e) => Error::Other(format!("Couldn't talk to the running daemon: {}", e)).exit(),
+    }
+}
+
+fn run_status(json: bool) {
+    let response = send_control_request(&control::Request::Status);
+    let Some(status) = response.status else {
+        Error::Other("The daemon didn't send its status".to_string()).exit();
+    };
+    if json {
+        println!("{}", serde_json::to_string(&status).unwrap());
+        return;
+    }
+
+    let state = if status.disabled_by_file {
+        "disabled by file"
+    } else if status.paused {
+        "paused"
+    } else {
+        "running"
+    };
+    println!("State: {}", state);
+    if let Some(saturation) = status.saturation_override {
+        println!("Level set to {} until reset", saturation);
+    }
+    if let Some(top_level) = status.top_levels.iter().find(|top_level| top_level.focused) {
+        println!(
+            "Focused: {} ({})",
+            top_level.title.as_deref().unwrap_or("<no title>"),
+            top_level.app_id.as_deref().unwrap_or("-")
+        );
+    }
+    for output in status.outputs.iter() {
+        let temperature = output
+            .temperature
+            .map_or(String::new(), |temperature| format!("{}K", temperature));
+        println!(
+            "{:<10} {:>5.2} {:>6}  {}",
+            output.name,
+            output.saturation,
+            temperature,
+            output.source.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+fn run_ctl(command: &CtlCommand) {
+    let request = match command {
+        CtlCommand::Rule { command } => match command {
+            CtlRuleCommand::Enable { name } => control::Request::EnableRule { name: name.clone() },
+            CtlRuleCommand::Disable { name } => {
+                control::Request::DisableRule { name: name.clone() }
+            }
+            CtlRuleCommand::Add { rule, first } => control::Request::AddRule {
+                rule: rule.to_string(),
+                first: *first,
+            },
+            CtlRuleCommand::Remove { name } => control::Request::RemoveRule { name: name.clone() },
+        },
+    };
+
+    send_control_request(&request);
+}
+
+/// Applies saturation levels read from stdin to a single output until
+/// an empty line is entered, so the user can find the level that
+/// looks right.
+fn run_calibrate(
+    args: &CalibrateArgs,
+    matrix_options: MatrixOptions,
+    wayland_display: Option<&str>,
+) -> Result<(), Error> {
+    let conn = session::connect(wayland_display).map_err(Error::Connection)?;
+    let mut event_queue = conn.new_event_queue();
+    let qh: QueueHandle<AppState> = event_queue.handle();
+    let mut state = AppState {
+        init: Some(Box::new(InitAppState::default())),
+        ..Default::default()
+    };
+
+    conn.display().get_registry(&qh, ());
+    // The second roundtrip gets the names of the outputs bound during
+    // the first one.
+    for _ in 0..2 {
+        if let Err(e) = event_queue.roundtrip(&mut state) {
+            permissions::explain_dispatch_error(&e);
+            return Err(e.into());
+        }
+    }
+
+    let Some(init_state) = state.init.take() else {
+        return Err(Error::Other("The globals were bound twice".to_string()));
+    };
+    #[cfg(not(feature = "osd"))]
+    let _ = init_state;
+    let Some(ctm_control) = state.ctm_manager.clone() else {
+        permissions::explain_missing_ctm_manager();
+        return Err(missing_ctm_manager());
+    };
+
+    let output = state.output_by_name(&args.output).map_err(Error::Config)?;
+
+    #[cfg(feature = "osd")]
+    if args.pattern {
+        match (
+            init_state.compositor,
+            init_state.shm,
+            init_state.layer_shell,
+        ) {
+            (Some(compositor), Some(shm), Some(layer_shell)) => {
+                state.pattern = Some(pattern::TestPattern::show(
+                    &compositor,
+                    shm,
+                    &layer_shell,
+                    &output,
+                    qh.clone(),
+                ));
+            }
+            _ => error!(
+                "The compositor doesn't support layer shell surfaces, the test pattern won't be shown"
+            ),
+        }
+    }
+
+    set_sat_ctm_for_display(&ctm_control, &output, args.sat_level, &matrix_options);
+    ctm_control.commit();
+    println!(
+        "Applied saturation {} to {}. Type another level and press enter to try it, or an empty line to finish.",
+        args.sat_level, args.output
+    );
+
+    let mut event_loop: EventLoop<'static, AppState> = EventLoop::try_new()
+        .map_err(|e| Error::Other(format!("Couldn't create the event loop: {}", e)))?;
+    WaylandSource::new(conn.clone(), event_queue)
+        .insert(event_loop.handle())
+        .map_err(|e| {
+            Error::Other(format!(
+                "Couldn't watch the Wayland connection: {}",
+                e.error
+            ))
+        })?;
+
+    // Reading stdin blocks, so it's done in its own thread.
+    let (sender, lines) = calloop::channel::channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let signal = event_loop.get_signal();
+    let mut current_level = args.sat_level;
+    event_loop
+        .handle()
+        .insert_source(lines, move |event, _, _| {
+            let line = match &event {
+                calloop::channel::Event::Msg(line) => line.trim(),
+                calloop::channel::Event::Closed => "",
+            };
+
+            if line.is_empty() {
+                println!("Last applied saturation: {}", current_level);
+                signal.stop();
+                return;
+            }
+
+            match units::parse_saturation(line) {
+                Ok(level) => {
+                    set_sat_ctm_for_display(&ctm_control, &output, level, &matrix_options);
+                    ctm_control.commit();
+                    current_level = level;
+                    println!("Applied saturation {}", level);
+                }
+                Err(e) => println!("{}", e),
+            }
+        })
+        .map_err(|e| Error::Other(format!("Couldn't read the levels: {}", e.error)))?;
+
+    event_loop
+        .run(None, &mut state, |_| {})
+        .map_err(|e| event_loop_error(&conn, e))
+}
+
+/// Applies a sepia matrix to an output for a short time and then
+/// reverts it, going through the same bind, commit and reset steps the
+/// daemon does. Every step is checked with a roundtrip so protocol
+/// errors are reported right where they happen.
+fn run_self_test(args: &SelfTestArgs, wayland_display: Option<&str>) {
+    let fail = |step: &str, e: Error| -> ! {
+        error!("Self test failed while {}: {}", step, e);
+        std::process::exit(e.exit_code());
+    };
+
+    let conn = match session::connect(wayland_display) {
+        Ok(conn) => conn,
+        Err(e) => fail("connecting to the compositor", Error::Connection(e)),
+    };
+    let mut event_queue = conn.new_event_queue();
+    let qh: QueueHandle<AppState> = event_queue.handle();
+    let mut state = AppState {
+        init: Some(Box::new(InitAppState::default())),
+        ..Default::default()
+    };
+
+    conn.display().get_registry(&qh, ());
+    for _ in 0..2 {
+        if let Err(e) = event_queue.roundtrip(&mut state) {
+            permissions::explain_dispatch_error(&e);
+            fail("binding the globals", e.into());
+        }
+    }
+    state.init.take();
+
+    let Some(ctm_control) = state.ctm_manager.clone() else {
+        permissions::explain_missing_ctm_manager();
+        fail("binding the globals", missing_ctm_manager());
+    };
+    info!("Bound to Hyprland CTM control manager");
+
+    let output = match state.output_by_name(&args.output) {
+        Ok(output) => output,
+        Err(e) => fail("looking up the output", Error::Config(e)),
+    };
+
+    set_ctm_matrix_for_display(&ctm_control, &output, &SEPIA_CTM_MATRIX);
+    ctm_control.commit();
+    if let Err(e) = event_queue.roundtrip(&mut state) {
+        fail("committing the test matrix", e.into());
+    }
+    info!(
+        "Applied a sepia matrix to {}, it should look brownish for {}ms",
+        args.output, args.duration
+    );
+
+    std::thread::sleep(Duration::from_millis(args.duration));
+
+    clear_ctm_matrix_for_display(&ctm_control, &output);
+    ctm_control.commit();
+    if let Err(e) = event_queue.roundtrip(&mut state) {
+        fail("reverting the test matrix", e.into());
+    }
+
+    info!(
+        "Self test passed: {} should look normal again. If it never changed its colors, the compositor accepted the CTM but isn't applying it",
+        args.output
+    );
+}
+
+/// The error for a compositor not advertising the CTM manager, after
+/// [`permissions::explain_missing_ctm_manager`] told why.
+fn missing_ctm_manager() -> Error {
+    Error::MissingGlobal(format!(
+        "the compositor doesn't advertise {}",
+        HYPERLAND_CTM_CONTROL_MANAGER_IFACE
+    ))
+}
+
+/// Reports that `action` failed at some step and exits with the status
+/// of the error.
+fn exit_failed(action: &str, step: &str, e: Error) -> ! {
+    error!("Couldn't {} while {}: {}", action, step, e);
+    std::process::exit(e.exit_code());
+}
+
+/// Binds the globals and waits for the names of the outputs, for the
+/// subcommands using the CTM manager without tracking any window.
+/// Exits on failure, telling `action` couldn't be done.
+fn bind_ctm_control(
+    wayland_display: Option<&str>,
+    action: &str,
+) -> (EventQueue<AppState>, AppState, HyprlandCtmControlManagerV1) {
+    let fail = |step: &str, e: Error| -> ! { exit_failed(action, step, e) };
+    let conn = match session::connect(wayland_display) {
+        Ok(conn) => conn,
+        Err(e) => fail("connecting to the compositor", Error::Connection(e)),
+    };
+    let mut event_queue = conn.new_event_queue();
+    let qh: QueueHandle<AppState> = event_queue.handle();
+    let mut state = AppState {
+        init: Some(Box::new(InitAppState::default())),
+        ..Default::default()
+    };
+
+    conn.display().get_registry(&qh, ());
+    // The second roundtrip gets the names of the outputs bound during
+    // the first one.
+    for _ in 0..2 {
+        if let Err(e) = event_queue.roundtrip(&mut state) {
+            permissions::explain_dispatch_error(&e);
+            fail("binding the globals", e.into());
+        }
+    }
+    state.init.take();
+
+    let Some(ctm_control) = state.ctm_manager.clone() else {
+        permissions::explain_missing_ctm_manager();
+        fail("binding the globals", missing_ctm_manager());
+    };
+    (event_queue, state, ctm_control)
+}
+
+/// Sets the level on the given outputs, with nothing but a connection
+/// and the CTM manager. The compositor resets the CTMs as soon as the
+/// manager goes away, so the level is held until the program is
+/// terminated.
+fn run_apply(args: &ApplyArgs, matrix_options: &MatrixOptions, wayland_display: Option<&str>) {
+    let action = "apply the level";
+    let fail = |step: &str, e: Error| -> ! { exit_failed(action, step, e) };
+    let (mut event_queue, mut state, ctm_control) = bind_ctm_control(wayland_display, action);
+
+    let outputs = if args.output.is_empty() {
+        state
+            .outputs
+            .iter()
+            .map(|output| output.handle.clone())
+            .collect()
+    } else {
+        match args
+            .output
+            .iter()
+            .map(|name| state.output_by_name(name))
+            .collect::<Result<Vec<WlOutput>, String>>()
+        {
+            Ok(outputs) => outputs,
+            Err(e) => fail("looking up the outputs", Error::Config(e)),
+        }
+    };
+
+    let matrix = color::Ctm::saturation(args.sat_level).matrix(matrix_options);
+    if let Err(e) = color::check_protocol_limits(&matrix) {
+        fail("building the matrix", Error::Config(e));
+    }
+    for output in outputs.iter() {
+        set_ctm_matrix_for_display(&ctm_control, output, &matrix);
+    }
+    ctm_control.commit();
+    if let Err(e) = event_queue.roundtrip(&mut state) {
+        fail("committing the matrix", e.into());
+    }
+    info!(
+        "Applied saturation {} to {}, holding it until terminated",
+        args.sat_level,
+        outputs
+            .iter()
+            .map(|output| state.format_output(output))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    loop {
+        if let Err(e) = event_queue.blocking_dispatch(&mut state) {
+            fail("holding the matrix", e.into());
+        }
+    }
+}
+
+/// Commits the identity matrix to every output and exits, undoing
+/// whatever a crashed instance or another program left behind.
+fn run_clear(wayland_display: Option<&str>) {
+    let action = "clear the CTMs";
+    let (mut event_queue, mut state, ctm_control) = bind_ctm_control(wayland_display, action);
+
+    for output in state.outputs.iter() {
+        clear_ctm_matrix_for_display(&ctm_control, &output.handle);
+    }
+    ctm_control.commit();
+    if let Err(e) = event_queue.roundtrip(&mut state) {
+        exit_failed(action, "committing the identity matrices", e.into());
+    }
+    info!(
+        "Cleared the CTM of {}",
+        state
+            .outputs
+            .iter()
+            .map(|output| state.format_output(&output.handle))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+}
+
+/// Prints the converted rules as `--rule` arguments, ready to be
+/// pasted in a command line.
+fn run_migrate(from: MigrateSource, path: &std::path::Path) {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => Error::Config(format!("Couldn't read {}: {}", path.display(), e)).exit(),
+    };
+
+    let migrated = match from {
+        MigrateSource::Vibrantlinux => migrate::vibrantlinux_rules(&contents),
+    };
+
+    match migrated {
+        Ok(migrated) if migrated.is_empty() => warn!("No programs found in {}", path.display()),
+        Ok(migrated) => {
+            for entry in migrated {
+                for note in entry.notes.iter() {
+                    println!("# {}", note);
+                }
+                let spec = entry.rule.to_string();
+                println!(
+                    "--rule {}",
+                    shlex::try_quote(&spec).unwrap_or(spec.as_str().into())
+                );
+            }
+        }
+        Err(e) => Error::Config(format!("Couldn't parse {}: {}", path.display(), e)).exit(),
+    }
+}
+
+fn run_suggest(args: &SuggestArgs) {
+    let usage = match usage::Usage::load() {
+        Ok(usage) => usage,
+        Err(e) => Error::Other(format!("Couldn't load the usage record: {}", e)).exit(),
+    };
+
+    let frequent = usage.frequent(args.min_count);
+    if frequent.is_empty() {
+        warn!(
+            "No program went fullscreen without a rule at least {} times. Is the daemon running with --track-usage?",
+            args.min_count
+        );
+        return;
+    }
+
+    for (app_id, app_usage) in frequent {
+        println!(
+            "# {}: fullscreen {} times{}",
+            app_id,
+            app_usage.fullscreen_count,
+            app_usage
+                .last_title
+                .as_ref()
+                .map(|title| format!(", last as \"{}\"", title))
+                .unwrap_or_default()
+        );
+        let rule = Rule {
+            name: Some(app_id.clone()),
+            app_ids: vec![app_id.clone()],
+            saturation: args.sat_level,
+            ..Default::default()
+        };
+        let spec = rule.to_string();
+        println!(
+            "--rule {}",
+            shlex::try_quote(&spec).unwrap_or(spec.as_str().into())
+        );
+    }
+}
+
+/// Validates the config and the rules the daemon would run with,
+/// printing what's wrong. Returns whether everything is valid.
+fn run_check_config(matches: &ArgMatches) -> bool {
+    let mut args = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+    let mut valid = true;
+
+    match config::Config::load(args.config.as_deref()) {
+        Ok(Some((config, path))) => {
+            let rule_errors = config.rule_errors();
+            if rule_errors.is_empty() {
+                if let Err(e) = args.apply_config(config, &path, matches) {
+                    eprintln!("error: {}", e);
+                    valid = false;
+                }
+            } else {
+                for (line, e) in rule_errors {
+                    eprintln!("error: {}:{}: {}", path.display(), line, e);
+                }
+                valid = false;
+            }
+        }
+        Ok(None) => println!(
+            "No config file at {}",
+            config::default_path()
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "the default location".to_string())
+        ),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            valid = false;
+        }
+    }
+
+    let (rules, origins): (Vec<Rule>, Vec<String>) = match args.rules() {
+        Ok(rules) => rules.into_iter().unzip(),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    for (rule, origin) in rules.iter().zip(origins.iter()) {
+        for warning in rule.warnings() {
+            eprintln!("warning: {}: {}", origin, warning);
+        }
+    }
+    for (idx, shadowing, message) in engine::diagnose(&rules) {
+        eprintln!(
+            "error: {}: rule {} (see {})",
+            origins[idx], message, origins[shadowing]
+        );
+        valid = false;
+    }
+
+    if valid {
+        println!("{} rules, everything looks fine", rules.len());
+    }
+    valid
+}
+
+fn run_probe(args: &ProbeArgs, matrix_options: &MatrixOptions) {
+    let matrix = if let Some(matrix) = args.matrix {
+        matrix
+    } else if let Some(saturation) = args.sat_level {
+        color::saturation_matrix(saturation, matrix_options)
+    } else {
+        let name = args.output.as_deref().unwrap_or_default();
+        let status = match control::send(&control::Request::Status) {
+            Ok(response) => response.status,
+            Err(e) => Error::Other(format!("Couldn't talk to the running daemon: {}", e)).exit(),
+        };
+        let Some(output) = status
+            .into_iter()
+            .flat_map(|status| status.outputs)
+            .find(|output| output.name == name)
+        else {
+            Error::Config(format!(
+                "The running daemon doesn't know about an output named {}",
+                name
+            ))
+            .exit();
+        };
+        info!(
+            "{} is at saturation {} ({})",
+            name,
+            output.saturation,
+            output.source.as_deref().unwrap_or("no rule")
+        );
+        output.matrix.unwrap_or(color::IDENTITY_MATRIX)
+    };
+
+    if let Err(e) = probe::print(&matrix, &mut std::io::stdout()) {
+        Error::Other(e.to_string()).exit();
+    }
+}
+
+/// Runs a command while the running daemon forces a level on every
+/// output, and exits with its status. The daemon goes back to the
+/// previous state as soon as the connection is closed, so that happens
+/// even if this process is killed.
+fn run_with(saturation: f64, command: &[String]) -> ! {
+    let connection = match control::force(saturation) {
+        Ok(connection) => connection,
+        Err(e) => Error::Other(format!("Couldn't talk to the running daemon: {}", e)).exit(),
+    };
+
+    let status = std::process::Command::new(&command[0])
+        .args(&command[1..])
+        .status();
+    drop(connection);
+
+    match status {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            error!("Couldn't run {}: {}", command[0], e);
+            std::process::exit(127);
+        }
+    }
+}
+
+/// Prints the completion candidates of the given kind found in the
+/// session. Nothing is printed if there's no session to ask, so the
+/// shell just doesn't offer anything.
+fn run_complete(kind: completions::CompletionKind, wayland_display: Option<&str>) {
+    let Ok(conn) = session::connect(wayland_display) else {
+        return;
+    };
+    let mut event_queue = conn.new_event_queue();
+    let qh: QueueHandle<AppState> = event_queue.handle();
+    let mut state = AppState {
+        init: Some(Box::new(InitAppState::default())),
+        ..Default::default()
+    };
+
+    let registry = conn.display().get_registry(&qh, ());
+    for _ in 0..2 {
+        if event_queue.roundtrip(&mut state).is_err() {
+            return;
+        }
+    }
+
+    let candidates: Vec<String> = match kind {
+        completions::CompletionKind::Outputs => state
+            .outputs
+            .iter()
+            .filter_map(|output| output.name.clone())
+            .collect(),
+        completions::CompletionKind::AppIds => {
+            let Some(global) = state
+                .init
+                .as_ref()
+                .and_then(|init| init.top_level_manager_global.clone())
+            else {
+                return;
+            };
+            registry.bind::<ZwlrForeignToplevelManagerV1, _, _>(
+                global.name,
+                global.version,
+                &qh,
+                (),
+            );
+            if event_queue.roundtrip(&mut state).is_err() {
+                return;
+            }
+
+            let mut app_ids: Vec<String> = state
+                .top_levels
+                .iter()
+                .filter_map(|top_level| top_level.app_id.clone())
+                .filter(|app_id| !app_id.is_empty())
+                .collect();
+            app_ids.sort();
+            app_ids.dedup();
+            app_ids
+        }
+    };
+
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let _failsafe = failsafe::install();
+
+    // Levels are validated while the arguments are parsed, so this one
+    // has to be known beforehand.
+    if std::env::args_os().any(|arg| arg == "--allow-extreme") {
+        units::allow_extreme();
+    }
+
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(kind) = args.complete {
+        run_complete(kind, args.wayland_display.as_deref());
+        return;
+    }
+
+    match &args.command {
+        Some(Command::Ctl { command }) => {
+            run_ctl(command);
+            return;
+        }
+        Some(Command::Calibrate(calibrate_args)) => {
+            if let Err(e) =
+                run_calibrate(calibrate_args, args.matrix, args.wayland_display.as_deref())
+            {
+                e.exit();
+            }
+            return;
+        }
+        #[cfg(feature = "tui")]
+        Some(Command::Manage) => {
+            if let Err(e) = tui::run() {
+                Error::Other(format!("Couldn't run the rule manager: {}", e)).exit();
+            }
+            return;
+        }
+        #[cfg(feature = "tui")]
+        Some(Command::Top) => {
+            if let Err(e) = tui::run_top() {
+                Error::Other(format!("Couldn't run the live view: {}", e)).exit();
+            }
+            return;
+        }
+        Some(Command::SelfTest(self_test_args)) => {
+            run_self_test(self_test_args, args.wayland_display.as_deref());
+            return;
+        }
+        Some(Command::Apply(apply_args)) => {
+            run_apply(apply_args, &args.matrix, args.wayland_display.as_deref());
+            return;
+        }
+        Some(Command::Clear) => {
+            run_clear(args.wayland_display.as_deref());
+            return;
+        }
+        Some(Command::WithIdentity { command }) => run_with(1.0, command),
+        Some(Command::With { sat_level, command }) => run_with(*sat_level, command),
+        Some(Command::Suggest(suggest_args)) => {
+            run_suggest(suggest_args);
+            return;
+        }
+        Some(Command::Probe(probe_args)) => {
+            run_probe(probe_args, &args.matrix);
+            return;
+        }
+        Some(Command::Completions { shell }) => {
+            completions::print_script(*shell, &mut Cli::command(), &mut std::io::stdout());
+            return;
+        }
+        Some(Command::Migrate { from, path }) => {
+            run_migrate(*from, path);
+            return;
+        }
+        Some(Command::Status { stream: true, .. }) => waybar::stream(),
+        Some(Command::Status { json, .. }) => {
+            run_status(*json);
+            return;
+        }
+        Some(Command::Set { sat_level }) => {
+            send_control_request(&control::Request::Set {
+                saturation: *sat_level,
+            });
+            return;
+        }
+        Some(Command::Reset) => {
+            send_control_request(&control::Request::Reset);
+            return;
+        }
+        Some(Command::Pause) => {
+            send_control_request(&control::Request::Pause);
+            return;
+        }
+        Some(Command::Resume) => {
+            send_control_request(&control::Request::Resume);
+            return;
+        }
+        Some(Command::Toggle) => {
+            send_control_request(&control::Request::Toggle);
+            return;
+        }
+        Some(Command::Reload) => {
+            send_control_request(&control::Request::Reload);
+            return;
+        }
+        Some(Command::CheckConfig) => {
+            if !run_check_config(&matches) {
+                Error::Config("The config or the rules aren't valid".to_string()).exit();
+            }
+            return;
+        }
+        None => {}
+    }
+
+    match config::Config::load(args.config.as_deref()) {
+        Ok(Some((config, path))) => {
+            if let Err(e) = args.apply_config(config, &path, &matches) {
+                Error::Config(format!("Invalid config: {}", e)).exit();
+            }
+            info!("Loaded config from {}", path.display());
+        }
+        Ok(None) => {}
+        Err(e) => Error::Config(format!("Couldn't load the config: {}", e)).exit(),
+    }
+
+    // Held until exiting. Taken before loading the persisted state,
+    // which the instance being replaced may still write to.
+    let _instance_lock = if args.once {
+        None
+    } else {
+        match instance::acquire(args.replace) {
+            Ok(lock) => Some(lock),
+            Err(e) => Error::Other(e.to_string()).exit(),
+        }
+    };
+
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut retry = false;
+    loop {
+        let started = Instant::now();
+        match run_daemon(&args, retry) {
+            Ok(Session::Ended) => return,
+            Ok(Session::Lost) if args.once => {
+                Error::Connection("Lost the Wayland connection".to_string()).exit()
+            }
+            Ok(Session::Lost) => {}
+            Err(e) => e.exit(),
+        }
+        failsafe::forget_all();
+        if started.elapsed() >= RECONNECT_STABLE_AFTER {
+            delay = RECONNECT_MIN_DELAY;
+        }
+        wait_for_compositor(args.wayland_display.as_deref(), &mut delay);
+        info!("Reconnected to the compositor, starting over");
+        retry = true;
+    }
+}
+
+/// How a run of the daemon ended, short of failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Session {
+    /// Asked to exit.
+    Ended,
+    /// The Wayland connection went away, most likely because the
+    /// compositor restarted or crashed.
+    Lost,
+}
+
+/// Delays between attempts to reach the compositor again, doubling
+/// after every failed one.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// A run lasting this long resets the delay, so a compositor crashing
+/// once in a while is retried quickly, but not one crashing in a loop.
+const RECONNECT_STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Blocks until a Wayland display can be connected to again.
+fn wait_for_compositor(display: Option<&str>, delay: &mut Duration) {
+    info!("Waiting for the compositor to come back");
+    systemd::notify("STATUS=Waiting for the compositor to come back");
+    loop {
+        systemd::sleep(*delay);
+        *delay = (*delay * 2).min(RECONNECT_MAX_DELAY);
+        match session::connect(display) {
+            Ok(_) => return,
+            Err(e) => debug!("Compositor still unreachable: {}", e),
+        }
+    }
+}
+
+/// Runs the daemon on a new Wayland connection, from rediscovering the
+/// rules, outputs and windows to the event loop, until it exits or the
+/// connection is lost. On a `retry`, a compositor going away again
+/// before the globals are bound counts as losing the connection too.
+fn run_daemon(args: &Cli, retry: bool) -> Result<Session, Error> {
+    let persisted = PersistedState::load().unwrap_or_else(|e| {
+        error!("Couldn't load the persisted state, ignoring it: {}", e);
+        PersistedState::default()
+    });
+    let (mut rules, origins): (Vec<Rule>, Vec<String>) = match args.rules() {
+        Ok(rules) => rules.into_iter().unzip(),
+        Err(e) => return Err(Error::Config(format!("Couldn't load the rules: {}", e))),
+    };
+    warn_rule_diagnostics(&rules, &origins);
+    persisted.apply_to_rules(&mut rules);
+    if rules.is_empty()
+        && args.auto_fullscreen.is_none()
+        && args.default_saturation.is_none()
+        && args.output_sat.is_empty()
+        && !args.hyprland_tags
+    {
+        return Err(Error::Config(
+            "Nothing to do: give --title-match or --app-id-match and --sat-level, --rule, --rules-file, --auto-fullscreen, --default-saturation, --output-sat, --hyprland-tags or a config file with rules".to_string(),
+        ));
+    }
+
+    let presets = if args.no_presets {
+        Vec::new()
+    } else {
+        match presets::load() {
+            Ok(presets) => presets,
+            Err(e) => {
+                return Err(Error::Config(format!(
+                    "Couldn't load the monitor presets: {}",
+                    e
+                )));
+            }
+        }
+    };
+
+    let usage = if args.track_usage {
+        match usage::Usage::load() {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                warn!("Couldn't load the usage record, starting a new one: {}", e);
+                Some(usage::Usage::default())
+            }
+        }
+    } else {
+        None
+    };
+
+    let conn = match session::connect(args.wayland_display.as_deref()) {
+        Ok(conn) => conn,
+        Err(e) if retry => {
+            error!("Couldn't reconnect to the compositor: {}", e);
+            return Ok(Session::Lost);
+        }
+        Err(e) => return Err(Error::Connection(e)),
+    };
+    let display = conn.display();
+    let mut event_queue = conn.new_event_queue();
+    let qh: QueueHandle<AppState> = event_queue.handle();
+
+    // Restarting shouldn't drop the saturation of whatever is being
+    // played until everything is rediscovered, so put back what the last
+    // run applied in this same session and reconcile afterwards.
+    let restoring = persisted
+        .last_applied
+        .clone()
+        .filter(|_| !args.once)
+        .filter(|last| last.instance_signature == hyprland::instance_signature())
+        .map(|last| last.outputs)
+        .unwrap_or_default();
+
+    // Connected early so the IPC queries made while discovering the
+    // windows can already be cached.
+    let hyprland_events = connect_hyprland_events(uses_hyprland_ipc(args));
+    let mut hyprland_cache = hyprland::Cache::default();
+    hyprland_cache.set_tracking(hyprland_events.is_some());
+    // Monitors are followed whenever Hyprland can be reached, so that
+    // mirrors get the colors of the output they mirror.
+    let track_monitors = uses_hyprland_ipc(args) || hyprland_events.is_some();
+
+    let mut state = AppState {
+        init: Some(Box::new(InitAppState::default())),
+        rules,
+        persisted,
+        restoring,
+        hyprland_cache,
+        rules_write_back: args.rules_file.clone().filter(|_| args.write_back),
+        rule_sources: args.rule_sources(),
+        presets,
+        matrix_options: args.matrix,
+        output_filter: args.output_filter.clone(),
+        keep_under_special: args.keep_under_special,
+        hyprland_tags: args.hyprland_tags,
+        global_shortcuts: !args.no_global_shortcuts && !args.once,
+        cycle_levels: args.cycle_sat.clone(),
+        transitions: (args.transition > 0 && !args.once).then(|| {
+            transition::Transitions::new(
+                Duration::from_millis(args.transition),
+                args.transition_easing,
+            )
+        }),
+        usage,
+        ..Default::default()
+    };
+
+    let registry = display.get_registry(&qh, ());
+    if let Err(e) = event_queue.roundtrip(&mut state) {
+        if retry && matches!(e, DispatchError::Backend(WaylandError::Io(_))) {
+            error!("Lost the Wayland connection: {}", e);
+            return Ok(Session::Lost);
+        }
+        permissions::explain_dispatch_error(&e);
+        return Err(e.into());
+    }
+
+    let init_state = state.init.take().unwrap();
+    if state.ctm_manager.is_none() {
+        if !permissions::explain_missing_ctm_manager() {
+            return Err(missing_ctm_manager());
+        }
+        warn!("Running in degraded mode: window focus is tracked, but no CTM will be applied");
+    }
+
+    let Some(top_level_manager_global) = init_state.top_level_manager_global else {
+        return Err(Error::MissingGlobal(
+            "Couldn't find wlr top level manager interface".to_string(),
+        ));
+    };
+
+    registry.bind::<ZwlrForeignToplevelManagerV1, _, _>(
+        top_level_manager_global.name,
+        top_level_manager_global.version,
+        &qh,
+        (),
+    );
+    info!("Bound to top level manager interface");
+
+    if track_monitors || references_serials(&state, args) {
+        refresh_hyprland_monitors(&mut state);
+    }
+
+    if args.follow_cursor {
+        refresh_cursor_monitor(&mut state);
+    }
+
+    if let Some(path) = disable_file_path() {
+        refresh_disable_file(&mut state, &path);
+    }
+
+    // Let the initial state settle before evaluating anything, so no
+    // CTM is applied and cleared right away while it's discovered. The
+    // compositor sends every existing top level along with its state
+    // right after binding, so a single roundtrip is enough to know
+    // what's focused. Window addresses are resolved through requests
+    // sent when each top level is announced, which need one more
+    // roundtrip.
+    let mut settled = event_queue.roundtrip(&mut state);
+    if settled.is_ok() && state.toplevel_mapping_manager.is_some() {
+        settled = event_queue.roundtrip(&mut state);
+    }
+    if let Err(e) = settled {
+        error!("Lost the Wayland connection while starting: {}", e);
+        return Ok(Session::Lost);
+    }
+    if state.toplevel_mapping_manager.is_none() {
+        state.correlate_top_levels();
+    }
+    // Outputs of the last run that are gone by now.
+    state.restoring.clear();
+
+    if args.once {
+        print_decision(&state, args);
+        if !args.hold {
+            return Ok(Session::Ended);
+        }
+
+        let Some(ctm_control) = state.ctm_manager.clone() else {
+            return Err(Error::MissingGlobal(
+                "Can't hold a CTM without access to the CTM control manager".to_string(),
+            ));
+        };
+        let desired = desired_ctms(&state, args);
+        reconcile_ctms(&ctm_control, &args.matrix, &mut state.applied_ctms, desired);
+
+        // CTMs are reset as soon as the manager goes away, so keep
+        // the connection alive without re-evaluating anything.
+        info!("Holding CTM state until terminated");
+        loop {
+            if let Err(e) = event_queue.blocking_dispatch(&mut state) {
+                return Err(e.into());
+            }
+        }
+    }
+
+    info!("CTM control initialized successfully");
+    let mut event_loop: EventLoop<'static, AppState> = EventLoop::try_new()
+        .map_err(|e| Error::Other(format!("Couldn't create the event loop: {}", e)))?;
+    let loop_signal = event_loop.get_signal();
+    let loop_handle = event_loop.handle();
+    WaylandSource::new(conn.clone(), event_queue)
+        .insert(event_loop.handle())
+        .map_err(|e| {
+            Error::Other(format!(
+                "Couldn't watch the Wayland connection: {}",
+                e.error
+            ))
+        })?;
+
+    #[cfg(feature = "osd")]
+    if args.osd {
+        match (
+            init_state.compositor,
+            init_state.shm,
+            init_state.layer_shell,
+        ) {
+            (Some(compositor), Some(shm), Some(layer_shell)) => {
+                state.osd = Some(osd::Osd::new(
+                    compositor,
+                    shm,
+                    layer_shell,
+                    qh.clone(),
+                    event_loop.handle(),
+                ));
+            }
+            _ => error!(
+                "The compositor doesn't support layer shell surfaces, the OSD won't be shown"
+            ),
+        }
+    }
+
+    if let Some(events) = hyprland_events {
+        if !watch_hyprland_events(
+            &event_loop.handle(),
+            events,
+            track_monitors,
+            args.follow_cursor,
+        ) {
+            state.hyprland_cache.set_tracking(false);
+        }
+        watch_output_power(&event_loop.handle());
+    }
+    watch_disable_file(&event_loop.handle());
+    reload::listen(&event_loop.handle(), &state.rule_sources);
+    watch_user_signals(&event_loop.handle());
+    watch_termination_signals(&event_loop.handle());
+    if let Err(e) = control::listen(&event_loop.handle()) {
+        error!("Couldn't open the control socket: {}", e);
+    }
+    #[cfg(feature = "notifications")]
+    if args.notify {
+        state.notifier = Some(notifications::Notifier::default());
+    }
+    #[cfg(feature = "tray")]
+    if args.tray {
+        match tray::start(&event_loop.handle(), args.cycle_sat.clone()) {
+            Ok(tray) => state.tray = Some(tray),
+            Err(e) => error!("Couldn't show the tray icon: {}", e),
+        }
+    }
+    #[cfg(feature = "dbus")]
+    if args.dbus {
+        match dbus::start(&event_loop.handle()) {
+            Ok(bus) => state.dbus = Some(bus),
+            Err(e) => error!("Couldn't register the D-Bus service: {}", e),
+        }
+    }
+
+    if let Some(path) = args.fifo.as_ref()
+        && let Err(e) = fifo::listen(&event_loop.handle(), path)
+    {
+        error!("Couldn't open the FIFO {}: {}", path.display(), e);
+    }
+
+    if args.follow_cursor {
+        watch_cursor_monitor(
+            &event_loop.handle(),
+            Duration::from_millis(args.cursor_poll_interval),
+        );
+    }
+
+    if args.warm_up > 0 {
+        state.warming_up = true;
+        info!("Warming up for {}ms before applying any CTM", args.warm_up);
+        event_loop
+            .handle()
+            .insert_source(
+                Timer::from_duration(Duration::from_millis(args.warm_up)),
+                |_, _, state| {
+                    debug!("Warm-up is over");
+                    state.warming_up = false;
+                    TimeoutAction::Drop
+                },
+            )
+            .unwrap();
+    }
+
+    systemd::watch(&event_loop.handle());
+    if state.ctm_manager.is_some() {
+        systemd::notify("READY=1\nSTATUS=Applying saturation");
+    } else {
+        systemd::notify("STATUS=Waiting for the permission to use the CTM protocol");
+    }
+
+    let result = event_loop.run(None, &mut state, |state| {
+        if state.quitting {
+            systemd::notify("STOPPING=1");
+            if let Some(ctm_control) = state.ctm_manager.clone() {
+                // Stored before clearing, so that the next instance,
+                // e.g. one started with --replace, restores the levels.
+                state.persist_applied();
+                if let Some(transitions) = state.transitions.as_mut() {
+                    transitions.clear(&ctm_control);
+                }
+                reconcile_ctms(
+                    &ctm_control,
+                    &args.matrix,
+                    &mut state.applied_ctms,
+                    Vec::new(),
+                );
+            }
+            if let Err(e) = conn.flush() {
+                error!("Couldn't reset the CTMs: {}", e);
+            }
+            loop_signal.stop();
+            return;
+        }
+
+        let (mut desired, capped) = desired_levels(state, args);
+        if args.focus_grace > 0 {
+            hold_lost_levels(
+                state,
+                &loop_handle,
+                Duration::from_millis(args.focus_grace),
+                &mut desired,
+            );
+        }
+        for (output, saturation) in capped.iter() {
+            if !state.capped.contains(&(output.clone(), *saturation)) {
+                info!(
+                    "Capping saturation {} on {} to the maximum of the output",
+                    saturation,
+                    state.format_output(output)
+                );
+            }
+        }
+        state.capped = capped;
+        state.level_sources = desired
+            .iter()
+            .map(|(output, _, source)| (output.clone(), *source))
+            .collect();
+
+        let Some(ctm_control) = state.ctm_manager.clone().filter(|_| !state.warming_up) else {
+            publish_state(state);
+            return;
+        };
+        let mut desired = desired
+            .into_iter()
+            .map(|(output, ctm, _)| (output, ctm))
+            .collect();
+        defer_asleep_outputs(state, &mut desired);
+        let changed = if state.transitions.is_some() {
+            transition::reconcile(state, &loop_handle, desired)
+        } else {
+            reconcile_ctms(&ctm_control, &args.matrix, &mut state.applied_ctms, desired)
+        };
+        publish_state(state);
+        if !changed.is_empty() {
+            state.persist_applied();
+        }
+
+        #[cfg(feature = "notifications")]
+        if let Some(notifier) = state.notifier.as_ref() {
+            notifier.notify_changes(state, &changed);
+        }
+
+        #[cfg(feature = "osd")]
+        if let Some(osd) = state.osd.as_mut() {
+            for (output, saturation) in changed.iter() {
+                osd.show(output, *saturation);
+            }
+        }
+        #[cfg(not(feature = "osd"))]
+        let _ = changed;
+    });
+    match result.map_err(|e| event_loop_error(&conn, e)) {
+        Ok(()) => Ok(Session::Ended),
+        Err(Error::Connection(message)) => {
+            error!("{}", message);
+            Ok(Session::Lost)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Tells whether the event loop failing means the compositor went
+/// away, which the daemon retries, or that it rejected something, which
+/// it doesn't. The Wayland source reports protocol errors as a bare
+/// `EPROTO`, so the actual error is taken from the connection.
+fn event_loop_error(conn: &Connection, e: calloop::Error) -> Error {
+    if let Some(protocol_error) = conn.protocol_error() {
+        return DispatchError::Backend(WaylandError::Protocol(protocol_error)).into();
+    }
+    match e {
+        calloop::Error::IoError(e) => {
+            Error::Connection(format!("Lost the Wayland connection: {}", e))
+        }
+        e => Error::Other(format!("The event loop failed: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(spec: &str) -> Rule {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn reloading_keeps_runtime_rules() {
+        let mut state = AppState {
+            rules: vec![
+                rule("title=a sat=2"),
+                rule("title=b sat=2"),
+                rule("title=c sat=2"),
+            ],
+            ..Default::default()
+        };
+        state.add_rule(rule("title=added sat=3"), true).unwrap();
+        let index = state
+            .rules
+            .iter()
+            .position(|r| r.to_string() == "title=b sat=2")
+            .unwrap();
+        state.set_rule_saturation(index, 2.5).unwrap();
+        let index = state
+            .rules
+            .iter()
+            .position(|r| r.to_string() == "title=c sat=2")
+            .unwrap();
+        state.set_rule_saturation(index, 3.5).unwrap();
+
+        let mut reloaded = vec![
+            rule("title=a sat=2"),
+            rule("title=b sat=2"),
+            rule("title=c sat=1.5"),
+        ];
+        state.carry_runtime_rules(&mut reloaded);
+        let specs: Vec<String> = reloaded.iter().map(Rule::to_string).collect();
+        assert_eq!(
+            specs,
+            [
+                "title=added sat=3",
+                "title=a sat=2",
+                "title=b sat=2.5",
+                "title=c sat=1.5"
+            ]
+        );
     }
 }
@@ -0,0 +1,151 @@
+//! 3x3 matrices, row major, applied to column RGB vectors. `a * b`
+//! applies `b` first, so a chain of adjustments reads from the last one
+//! applied to the first.
+
+use std::ops::Mul;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix3(pub [f64; 9]);
+
+impl Matrix3 {
+    pub const IDENTITY: Matrix3 = Matrix3([
+        1.0, 0.0, 0.0, //
+        0.0, 1.0, 0.0, //
+        0.0, 0.0, 1.0,
+    ]);
+
+    /// Scales every channel by its own gain.
+    pub fn diagonal(gains: [f64; 3]) -> Matrix3 {
+        Matrix3([
+            gains[0], 0.0, 0.0, //
+            0.0, gains[1], 0.0, //
+            0.0, 0.0, gains[2],
+        ])
+    }
+
+    pub fn apply(&self, color: &[f64; 3]) -> [f64; 3] {
+        let m = &self.0;
+        [0, 1, 2].map(|row| (0..3).map(|k| m[row * 3 + k] * color[k]).sum())
+    }
+
+    pub fn determinant(&self) -> f64 {
+        let m = &self.0;
+        m[0] * (m[4] * m[8] - m[5] * m[7]) - m[1] * (m[3] * m[8] - m[5] * m[6])
+            + m[2] * (m[3] * m[7] - m[4] * m[6])
+    }
+
+    /// The inverse matrix, unless this one is singular.
+    pub fn inverse(&self) -> Option<Matrix3> {
+        let m = &self.0;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0 * 3 + c0] * m[r1 * 3 + c1] - m[r0 * 3 + c1] * m[r1 * 3 + c0]
+        };
+
+        let determinant = self.determinant();
+        if determinant == 0.0 || !determinant.is_finite() {
+            return None;
+        }
+        let adjugate = [
+            cofactor(1, 2, 1, 2),
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 1, 1, 2),
+            -cofactor(1, 2, 0, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 1, 0, 2),
+            cofactor(1, 2, 0, 1),
+            -cofactor(0, 2, 0, 1),
+            cofactor(0, 1, 0, 1),
+        ];
+        Some(Matrix3(adjugate.map(|value| value / determinant)))
+    }
+}
+
+impl Default for Matrix3 {
+    fn default() -> Self {
+        Matrix3::IDENTITY
+    }
+}
+
+impl Mul for Matrix3 {
+    type Output = Matrix3;
+
+    fn mul(self, rhs: Matrix3) -> Matrix3 {
+        let (a, b) = (&self.0, &rhs.0);
+        Matrix3(std::array::from_fn(|i| {
+            let (row, col) = (i / 3, i % 3);
+            (0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).sum()
+        }))
+    }
+}
+
+impl From<[f64; 9]> for Matrix3 {
+    fn from(values: [f64; 9]) -> Self {
+        Matrix3(values)
+    }
+}
+
+impl From<Matrix3> for [f64; 9] {
+    fn from(matrix: Matrix3) -> Self {
+        matrix.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: Matrix3 = Matrix3([
+        2.0, 1.0, 0.0, //
+        0.0, 1.0, 3.0, //
+        1.0, 0.0, 1.0,
+    ]);
+
+    fn assert_close(a: Matrix3, b: Matrix3) {
+        for (x, y) in a.0.iter().zip(b.0.iter()) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn identity_is_neutral() {
+        assert_eq!(Matrix3::IDENTITY * M, M);
+        assert_eq!(M * Matrix3::IDENTITY, M);
+    }
+
+    #[test]
+    fn multiplies_rows_by_columns() {
+        let scale = Matrix3::diagonal([1.0, 2.0, 3.0]);
+        // Scaling on the left scales the rows, on the right the columns.
+        assert_eq!(
+            scale * M,
+            Matrix3([
+                2.0, 1.0, 0.0, //
+                0.0, 2.0, 6.0, //
+                3.0, 0.0, 3.0,
+            ])
+        );
+        assert_eq!(
+            M * scale,
+            Matrix3([
+                2.0, 2.0, 0.0, //
+                0.0, 2.0, 9.0, //
+                1.0, 0.0, 3.0,
+            ])
+        );
+    }
+
+    #[test]
+    fn product_applies_right_operand_first() {
+        let color = [0.2, 0.5, 0.7];
+        let scale = Matrix3::diagonal([0.5, 1.0, 2.0]);
+        assert_eq!((scale * M).apply(&color), scale.apply(&M.apply(&color)));
+    }
+
+    #[test]
+    fn inverse() {
+        assert_eq!(M.determinant(), 5.0);
+        assert_close(M * M.inverse().unwrap(), Matrix3::IDENTITY);
+        assert_close(M.inverse().unwrap() * M, Matrix3::IDENTITY);
+        assert_eq!(Matrix3([1.0; 9]).inverse(), None);
+    }
+}
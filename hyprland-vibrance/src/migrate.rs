@@ -0,0 +1,215 @@
+//! Conversion of the program lists of other tools into rules of this
+//! crate.
+//!
+//! vibrantLinux keeps its programs in a Qt settings (INI) file, usually
+//! `~/.config/vibrantLinux/vibrantLinux.conf`, as an array where every
+//! entry has the path of the executable and a vibrance value per
+//! display:
+//!
+//! ```text
+//! [programs]
+//! 1\path=/home/user/.steam/steam/steamapps/common/Counter-Strike Global Offensive/game/bin/linuxsteamrt64/cs2
+//! 1\vibrance\DP-1=512
+//! size=1
+//! ```
+//!
+//! Entries written under `[General]` as `programs\1\path=...`, and
+//! per-display values stored as `displays\N\vibrance`, are understood
+//! as well. Values are on the NVIDIA digital vibrance scale.
+
+use std::collections::BTreeMap;
+
+use crate::{rules::Rule, units};
+
+#[derive(Debug, Default)]
+struct Program {
+    path: Option<String>,
+    title: Option<String>,
+    vibrance: Vec<i32>,
+}
+
+/// A rule converted from another tool, with notes about anything that
+/// couldn't be carried over exactly.
+#[derive(Debug)]
+pub struct MigratedRule {
+    pub rule: Rule,
+    pub notes: Vec<String>,
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value.replace("\\\\", "\\")
+}
+
+/// Rule names only allow a restricted set of characters to stay easy
+/// to type in `ctl` commands.
+fn rule_name_for(path: &str) -> String {
+    let basename = path.rsplit('/').next().unwrap_or(path);
+    basename
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_lowercase()
+}
+
+pub fn vibrantlinux_rules(contents: &str) -> Result<Vec<MigratedRule>, String> {
+    let mut programs: BTreeMap<u32, Program> = BTreeMap::new();
+    let mut section = String::new();
+
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.to_string();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY=VALUE", line_no + 1));
+        };
+
+        let full_key = if section.is_empty() || section == "General" {
+            key.trim().to_string()
+        } else {
+            format!("{}\\{}", section, key.trim())
+        };
+
+        let parts: Vec<&str> = full_key.split('\\').collect();
+        let ["programs", index, rest @ ..] = parts.as_slice() else {
+            continue;
+        };
+        let Ok(index) = index.parse::<u32>() else {
+            continue;
+        };
+
+        let program = programs.entry(index).or_default();
+        match rest {
+            ["path"] | ["program"] | ["exe"] => program.path = Some(unquote(value)),
+            ["title"] | ["windowTitle"] => program.title = Some(unquote(value)),
+            [.., last] if last.eq_ignore_ascii_case("vibrance") => {
+                program.vibrance.push(parse_vibrance(value, line_no)?)
+            }
+            ["vibrance", _display] => program.vibrance.push(parse_vibrance(value, line_no)?),
+            _ => {}
+        }
+    }
+
+    let mut migrated = Vec::new();
+    for (index, program) in programs {
+        let mut notes = Vec::new();
+        let Some(vibrance) = program.vibrance.iter().copied().max() else {
+            continue;
+        };
+
+        if program.vibrance.iter().any(|v| *v != vibrance) {
+            notes.push(format!(
+                "displays had different vibrance values ({:?}), using the highest one",
+                program.vibrance
+            ));
+        }
+
+        let mut rule = Rule {
+            // Two decimals are more than enough to tell levels apart.
+            saturation: (units::nv_vibrance_to_saturation(vibrance) * 100.0).round() / 100.0,
+            ..Default::default()
+        };
+        rule.name = Some(
+            program
+                .path
+                .as_deref()
+                .map(rule_name_for)
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| format!("program-{}", index)),
+        );
+
+        if let Some(title) = program.title {
+            rule.titles.push(title);
+        } else if let Some(path) = &program.path {
+            // vibrantLinux matches the executable of the focused window,
+            // which isn't known here. The window class usually is the
+            // name of the executable, at least for X11 games.
+            let basename = path.rsplit('/').next().unwrap_or(path);
+            rule.initial_classes.push(basename.to_string());
+            notes.push(format!(
+                "matched by executable {}, assuming its window class is {}",
+                path, basename
+            ));
+        } else {
+            continue;
+        }
+
+        notes.push(format!("vibrance {}", vibrance));
+        migrated.push(MigratedRule { rule, notes });
+    }
+
+    Ok(migrated)
+}
+
+fn parse_vibrance(value: &str, line_no: usize) -> Result<i32, String> {
+    let vibrance: i32 = unquote(value)
+        .parse()
+        .map_err(|_| format!("line {}: invalid vibrance '{}'", line_no + 1, value))?;
+    Ok(vibrance.clamp(units::NV_VIBRANCE_MIN, units::NV_VIBRANCE_MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_vibrantlinux_programs() {
+        let migrated = vibrantlinux_rules(
+            "[programs]\n\
+             1\\path=/home/user/.steam/steam/steamapps/common/Counter-Strike Global Offensive/game/bin/linuxsteamrt64/cs2\n\
+             1\\vibrance\\DP-1=512\n\
+             1\\vibrance\\HDMI-A-1=0\n\
+             2\\path=/usr/bin/mpv\n\
+             2\\title=\"mpv video\"\n\
+             2\\vibrance\\DP-1=-512\n\
+             size=2\n",
+        )
+        .unwrap();
+        assert_eq!(migrated.len(), 2);
+
+        let cs2 = &migrated[0];
+        assert_eq!(cs2.rule.name.as_deref(), Some("cs2"));
+        assert_eq!(cs2.rule.initial_classes, ["cs2"]);
+        assert_eq!(cs2.rule.saturation, 2.5);
+        assert!(cs2.notes[0].starts_with("displays had different vibrance values"));
+
+        let mpv = &migrated[1];
+        assert_eq!(mpv.rule.titles, ["mpv video"]);
+        assert_eq!(mpv.rule.saturation, 0.5);
+        // The converted rules have to be valid once written out.
+        for entry in migrated.iter() {
+            assert_eq!(
+                entry.rule.to_string().parse::<Rule>(),
+                Ok(entry.rule.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn reads_general_section_keys() {
+        let migrated = vibrantlinux_rules(
+            "[General]\nprograms\\1\\exe=/opt/game\nprograms\\1\\vibrance=1023\n",
+        )
+        .unwrap();
+        assert_eq!(migrated[0].rule.saturation, 4.0);
+    }
+
+    #[test]
+    fn reports_the_faulty_line() {
+        let error = vibrantlinux_rules("[programs]\n1\\vibrance=lots\n").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        assert!(vibrantlinux_rules("garbage\n").is_err());
+    }
+}
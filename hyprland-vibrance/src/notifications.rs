@@ -0,0 +1,95 @@
+//! Desktop notifications telling when a level is applied to an output or
+//! cleared from it, along with the focused window and what decided the
+//! level, which helps while tuning new rules.
+//!
+//! Sending a notification is a synchronous D-Bus call, so each one is
+//! sent from its own thread to keep the event loop responsive. Every
+//! output gets a single notification, replaced on every change instead
+//! of piling up.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use log::warn;
+use notify_rust::{Hint, Notification, Timeout};
+use wayland_client::protocol::wl_output::WlOutput;
+
+use crate::{AppState, LevelSource, units};
+
+const APP_NAME: &str = "hyprland-vibrance";
+const TIMEOUT_MS: u32 = 3000;
+
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// Id of the last notification of every output, by output name.
+    ids: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl Notifier {
+    /// Notifies about the outputs whose level just changed.
+    pub fn notify_changes(&self, state: &AppState, changed: &[(WlOutput, f64)]) {
+        let focused = state
+            .focused_top_level()
+            .and_then(|top_level| top_level.title.clone());
+
+        for (output, saturation) in changed {
+            let name = state.format_output(output);
+            let summary = if *saturation == 1.0 {
+                format!("Saturation cleared on {}", name)
+            } else {
+                format!(
+                    "Saturation {:.0}% on {}",
+                    units::saturation_to_percent(*saturation),
+                    name
+                )
+            };
+
+            let source = state
+                .level_sources
+                .iter()
+                .find(|(handle, _)| handle == output)
+                .map(|(_, source)| match source {
+                    LevelSource::Rule(rule) | LevelSource::OtherSaturation(rule) => {
+                        match state.rules.get(*rule).and_then(|rule| rule.name.as_deref()) {
+                            Some(rule_name) => format!("{} '{}'", source, rule_name),
+                            None => source.to_string(),
+                        }
+                    }
+                    source => source.to_string(),
+                });
+            let body = [focused.clone(), source]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            self.send(name, summary, body);
+        }
+    }
+
+    fn send(&self, output: String, summary: String, body: String) {
+        let ids = self.ids.clone();
+        thread::spawn(move || {
+            let mut notification = Notification::new();
+            notification
+                .appname(APP_NAME)
+                .summary(&summary)
+                .body(&body)
+                .hint(Hint::Transient(true))
+                .timeout(Timeout::Milliseconds(TIMEOUT_MS));
+            if let Some(id) = ids.lock().unwrap().get(&output) {
+                notification.id(*id);
+            }
+
+            match notification.show() {
+                Ok(handle) => {
+                    ids.lock().unwrap().insert(output, handle.id());
+                }
+                Err(e) => warn!("Couldn't show a notification: {}", e),
+            }
+        });
+    }
+}
@@ -0,0 +1,447 @@
+//! On-screen indicator shown on an output whenever its saturation
+//! changes, similar to the volume OSDs most desktops have. It's drawn
+//! on a wlr-layer-shell overlay surface using a shared memory buffer,
+//! so no rendering library is needed: just a background, a bar and the
+//! saturation value written with a tiny bitmap font.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    os::fd::AsFd,
+    path::PathBuf,
+    time::Duration,
+};
+
+use calloop::{
+    LoopHandle,
+    timer::{TimeoutAction, Timer},
+};
+use log::{debug, error};
+use wayland_client::{
+    Connection, Dispatch, QueueHandle,
+    protocol::{
+        wl_buffer::{self, WlBuffer},
+        wl_compositor::{self, WlCompositor},
+        wl_output::WlOutput,
+        wl_region::{self, WlRegion},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::{self, WlShmPool},
+        wl_surface::{self, WlSurface},
+    },
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{self, Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{self, Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+
+use crate::AppState;
+
+pub const WL_COMPOSITOR_IFACE: &str = "wl_compositor";
+pub const WL_SHM_IFACE: &str = "wl_shm";
+pub const ZWLR_LAYER_SHELL_IFACE: &str = "zwlr_layer_shell_v1";
+
+const WIDTH: u32 = 260;
+const HEIGHT: u32 = 72;
+const BOTTOM_MARGIN: i32 = 120;
+const VISIBLE_FOR: Duration = Duration::from_millis(1500);
+
+/// Maps the saturation onto the bar. The bar is full at the maximum
+/// level accepted from the command line.
+const MAX_SATURATION: f64 = 4.0;
+
+const BACKGROUND: u32 = 0xd0202020;
+const BAR_TRACK: u32 = 0xff505050;
+const FOREGROUND: u32 = 0xffeeeeee;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const GLYPH_SCALE: usize = 4;
+
+/// 5x7 glyphs, one byte per row with the leftmost pixel in bit 4.
+fn glyph(c: char) -> Option<[u8; GLYPH_HEIGHT]> {
+    Some(match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        _ => return None,
+    })
+}
+
+pub(crate) struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+impl Canvas {
+    pub(crate) fn new(width: usize, height: usize, color: u32) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![color; width * height],
+        }
+    }
+
+    pub(crate) fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: u32,
+    ) {
+        for row in y..(y + height).min(self.height) {
+            for col in x..(x + width).min(self.width) {
+                self.pixels[row * self.width + col] = color;
+            }
+        }
+    }
+
+    fn draw_text(&mut self, text: &str, y: usize, color: u32) {
+        let glyphs: Vec<[u8; GLYPH_HEIGHT]> = text.chars().filter_map(glyph).collect();
+        let advance = (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+        let text_width = glyphs.len() * advance;
+        let mut x = self.width.saturating_sub(text_width) / 2;
+        for rows in glyphs {
+            for (row, bits) in rows.iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                        self.fill_rect(
+                            x + col * GLYPH_SCALE,
+                            y + row * GLYPH_SCALE,
+                            GLYPH_SCALE,
+                            GLYPH_SCALE,
+                            color,
+                        );
+                    }
+                }
+            }
+            x += advance;
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+}
+
+fn render(width: usize, height: usize, saturation: f64) -> Canvas {
+    let mut canvas = Canvas::new(width, height, BACKGROUND);
+    canvas.draw_text(&format!("{:.2}", saturation), 10, FOREGROUND);
+
+    let bar_x = 16;
+    let bar_y = height - 20;
+    let bar_width = width - 2 * bar_x;
+    canvas.fill_rect(bar_x, bar_y, bar_width, 8, BAR_TRACK);
+    let filled = (saturation / MAX_SATURATION).clamp(0.0, 1.0) * bar_width as f64;
+    canvas.fill_rect(bar_x, bar_y, filled.round() as usize, 8, FOREGROUND);
+    canvas
+}
+
+/// Creates an already unlinked file to back a shared memory pool.
+pub(crate) fn create_shm_file(contents: &[u8]) -> std::io::Result<File> {
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| "/tmp".to_string());
+    let path = PathBuf::from(runtime_dir).join(format!(
+        "hyprland-vibrance-osd-{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    ));
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    file.write_all(contents)?;
+    Ok(file)
+}
+
+struct OsdSurface {
+    id: u64,
+    output: WlOutput,
+    saturation: f64,
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    buffer: Option<WlBuffer>,
+}
+
+impl OsdSurface {
+    fn destroy(self) {
+        self.layer_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer {
+            buffer.destroy();
+        }
+    }
+}
+
+pub struct Osd {
+    compositor: WlCompositor,
+    shm: WlShm,
+    layer_shell: ZwlrLayerShellV1,
+    qh: QueueHandle<AppState>,
+    loop_handle: LoopHandle<'static, AppState>,
+    surfaces: Vec<OsdSurface>,
+    next_id: u64,
+}
+
+impl std::fmt::Debug for Osd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Osd")
+            .field("visible", &self.surfaces.len())
+            .finish()
+    }
+}
+
+impl Osd {
+    pub fn new(
+        compositor: WlCompositor,
+        shm: WlShm,
+        layer_shell: ZwlrLayerShellV1,
+        qh: QueueHandle<AppState>,
+        loop_handle: LoopHandle<'static, AppState>,
+    ) -> Osd {
+        Osd {
+            compositor,
+            shm,
+            layer_shell,
+            qh,
+            loop_handle,
+            surfaces: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Shows the saturation on the given output, replacing any
+    /// indicator already visible there.
+    pub fn show(&mut self, output: &WlOutput, saturation: f64) {
+        if let Some(idx) = self.surfaces.iter().position(|s| &s.output == output) {
+            self.surfaces.remove(idx).destroy();
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let surface = self.compositor.create_surface(&self.qh, ());
+        // An empty input region lets clicks go through the indicator.
+        let region = self.compositor.create_region(&self.qh, ());
+        surface.set_input_region(Some(&region));
+        region.destroy();
+
+        let layer_surface = self.layer_shell.get_layer_surface(
+            &surface,
+            Some(output),
+            Layer::Overlay,
+            "hyprland-vibrance-osd".to_string(),
+            &self.qh,
+            id,
+        );
+        layer_surface.set_size(WIDTH, HEIGHT);
+        layer_surface.set_anchor(Anchor::Bottom);
+        layer_surface.set_margin(0, 0, BOTTOM_MARGIN, 0);
+        layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
+        surface.commit();
+
+        self.surfaces.push(OsdSurface {
+            id,
+            output: output.clone(),
+            saturation,
+            surface,
+            layer_surface,
+            buffer: None,
+        });
+
+        let inserted = self.loop_handle.insert_source(
+            Timer::from_duration(VISIBLE_FOR),
+            move |_, _, state| {
+                if let Some(osd) = state.osd.as_mut() {
+                    osd.hide(id);
+                }
+                TimeoutAction::Drop
+            },
+        );
+        if let Err(e) = inserted {
+            error!("Couldn't schedule OSD hiding: {}", e.error);
+        }
+    }
+
+    /// Drops the indicator shown on an output that's gone.
+    pub fn forget_output(&mut self, output: &WlOutput) {
+        if let Some(idx) = self.surfaces.iter().position(|s| &s.output == output) {
+            self.surfaces.remove(idx).destroy();
+        }
+    }
+
+    fn hide(&mut self, id: u64) {
+        if let Some(idx) = self.surfaces.iter().position(|s| s.id == id) {
+            self.surfaces.remove(idx).destroy();
+        }
+    }
+
+    fn draw(&mut self, id: u64, width: u32, height: u32) {
+        let Some(osd_surface) = self.surfaces.iter_mut().find(|s| s.id == id) else {
+            return;
+        };
+
+        let width = if width == 0 { WIDTH } else { width };
+        let height = if height == 0 { HEIGHT } else { height };
+        let canvas = render(width as usize, height as usize, osd_surface.saturation);
+        let bytes = canvas.to_bytes();
+        let file = match create_shm_file(&bytes) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Couldn't create OSD buffer: {}", e);
+                return;
+            }
+        };
+
+        let pool = self
+            .shm
+            .create_pool(file.as_fd(), bytes.len() as i32, &self.qh, ());
+        let buffer = pool.create_buffer(
+            0,
+            width as i32,
+            height as i32,
+            width as i32 * 4,
+            wl_shm::Format::Argb8888,
+            &self.qh,
+            (),
+        );
+        pool.destroy();
+
+        osd_surface.surface.attach(Some(&buffer), 0, 0);
+        osd_surface
+            .surface
+            .damage_buffer(0, 0, width as i32, height as i32);
+        osd_surface.surface.commit();
+        if let Some(old_buffer) = osd_surface.buffer.replace(buffer) {
+            old_buffer.destroy();
+        }
+    }
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, u64> for AppState {
+    fn event(
+        this: &mut Self,
+        layer_surface: &ZwlrLayerSurfaceV1,
+        event: zwlr_layer_surface_v1::Event,
+        id: &u64,
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+        let Some(osd) = this.osd.as_mut() else {
+            return;
+        };
+
+        match event {
+            zwlr_layer_surface_v1::Event::Configure {
+                serial,
+                width,
+                height,
+            } => {
+                layer_surface.ack_configure(serial);
+                osd.draw(*id, width, height);
+            }
+            zwlr_layer_surface_v1::Event::Closed => {
+                debug!("OSD surface {} closed by the compositor", id);
+                osd.hide(*id);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlCompositor, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlCompositor,
+        _: wl_compositor::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSurface, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlSurface,
+        _: wl_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegion, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlRegion,
+        _: wl_region::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<WlShm, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlShm,
+        _: wl_shm::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<WlShmPool, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlShmPool,
+        _: wl_shm_pool::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &WlBuffer,
+        _: wl_buffer::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrLayerShellV1, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrLayerShellV1,
+        _: zwlr_layer_shell_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
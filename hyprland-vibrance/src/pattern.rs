@@ -0,0 +1,233 @@
+//! Color test pattern covering a whole output, shown while calibrating
+//! so saturation levels can be judged against known references rather
+//! than against whatever happens to be on screen. From top to bottom
+//! it has saturation ramps for the primary and secondary colors, a row
+//! of skin tone patches and a row of gray steps.
+
+use std::os::fd::AsFd;
+
+use log::{debug, error};
+use wayland_client::{
+    Connection, Dispatch, QueueHandle,
+    protocol::{
+        wl_buffer::WlBuffer, wl_compositor::WlCompositor, wl_output::WlOutput, wl_shm,
+        wl_shm::WlShm, wl_surface::WlSurface,
+    },
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{self, Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+
+use crate::{
+    AppState,
+    osd::{Canvas, create_shm_file},
+};
+
+/// Hues of the saturation ramps: red, yellow, green, cyan, blue and
+/// magenta.
+const RAMP_HUES: [(u8, u8, u8); 6] = [
+    (255, 0, 0),
+    (255, 255, 0),
+    (0, 255, 0),
+    (0, 255, 255),
+    (0, 0, 255),
+    (255, 0, 255),
+];
+
+/// Light to dark skin tone references, the first two being the
+/// ColorChecker "light skin" and "dark skin" patches.
+const SKIN_TONES: [(u8, u8, u8); 6] = [
+    (194, 150, 130),
+    (115, 82, 68),
+    (255, 224, 189),
+    (234, 192, 134),
+    (198, 134, 66),
+    (141, 85, 36),
+];
+
+const GRAY_STEPS: usize = 11;
+
+fn argb(r: u8, g: u8, b: u8) -> u32 {
+    0xff000000 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+fn lerp(from: u8, to: u8, t: f64) -> u8 {
+    (from as f64 + (to as f64 - from as f64) * t).round() as u8
+}
+
+fn render(width: usize, height: usize) -> Canvas {
+    let mut canvas = Canvas::new(width, height, argb(0, 0, 0));
+
+    // Ramps take the top half, skin tones and gray steps a quarter
+    // each.
+    let ramp_height = height / 2 / RAMP_HUES.len();
+    for (row, (r, g, b)) in RAMP_HUES.iter().enumerate() {
+        for x in 0..width {
+            // Goes from a gray of the same average level to the fully
+            // saturated hue.
+            let t = x as f64 / (width - 1).max(1) as f64;
+            let gray = ((*r as u32 + *g as u32 + *b as u32) / 3) as u8;
+            let color = argb(lerp(gray, *r, t), lerp(gray, *g, t), lerp(gray, *b, t));
+            canvas.fill_rect(x, row * ramp_height, 1, ramp_height, color);
+        }
+    }
+
+    let skin_y = ramp_height * RAMP_HUES.len();
+    let skin_height = (height - skin_y) / 2;
+    let skin_width = width / SKIN_TONES.len();
+    for (idx, (r, g, b)) in SKIN_TONES.iter().enumerate() {
+        canvas.fill_rect(
+            idx * skin_width,
+            skin_y,
+            skin_width,
+            skin_height,
+            argb(*r, *g, *b),
+        );
+    }
+
+    let gray_y = skin_y + skin_height;
+    let gray_width = width / GRAY_STEPS;
+    for step in 0..GRAY_STEPS {
+        let level = (step * 255 / (GRAY_STEPS - 1)) as u8;
+        canvas.fill_rect(
+            step * gray_width,
+            gray_y,
+            gray_width,
+            height - gray_y,
+            argb(level, level, level),
+        );
+    }
+
+    canvas
+}
+
+/// Marker for the layer surface of the pattern, so its events aren't
+/// mixed up with the ones of the OSD.
+pub struct PatternSurfaceData;
+
+pub struct TestPattern {
+    shm: WlShm,
+    qh: QueueHandle<AppState>,
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    buffer: Option<WlBuffer>,
+}
+
+impl std::fmt::Debug for TestPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TestPattern")
+            .field("surface", &self.surface)
+            .finish()
+    }
+}
+
+impl TestPattern {
+    /// Covers the given output with the test pattern. It's drawn once
+    /// the compositor tells the size of the output.
+    pub fn show(
+        compositor: &WlCompositor,
+        shm: WlShm,
+        layer_shell: &ZwlrLayerShellV1,
+        output: &WlOutput,
+        qh: QueueHandle<AppState>,
+    ) -> TestPattern {
+        let surface = compositor.create_surface(&qh, ());
+        let layer_surface = layer_shell.get_layer_surface(
+            &surface,
+            Some(output),
+            Layer::Overlay,
+            "hyprland-vibrance-pattern".to_string(),
+            &qh,
+            PatternSurfaceData,
+        );
+        layer_surface.set_anchor(Anchor::Top | Anchor::Bottom | Anchor::Left | Anchor::Right);
+        layer_surface.set_exclusive_zone(-1);
+        layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
+        surface.commit();
+
+        TestPattern {
+            shm,
+            qh,
+            surface,
+            layer_surface,
+            buffer: None,
+        }
+    }
+
+    fn draw(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let bytes = render(width as usize, height as usize).to_bytes();
+        let file = match create_shm_file(&bytes) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Couldn't create test pattern buffer: {}", e);
+                return;
+            }
+        };
+
+        let pool = self
+            .shm
+            .create_pool(file.as_fd(), bytes.len() as i32, &self.qh, ());
+        let buffer = pool.create_buffer(
+            0,
+            width as i32,
+            height as i32,
+            width as i32 * 4,
+            wl_shm::Format::Argb8888,
+            &self.qh,
+            (),
+        );
+        pool.destroy();
+
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface
+            .damage_buffer(0, 0, width as i32, height as i32);
+        self.surface.commit();
+        if let Some(old_buffer) = self.buffer.replace(buffer) {
+            old_buffer.destroy();
+        }
+    }
+}
+
+impl Drop for TestPattern {
+    fn drop(&mut self) {
+        self.layer_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+    }
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, PatternSurfaceData> for AppState {
+    fn event(
+        this: &mut Self,
+        layer_surface: &ZwlrLayerSurfaceV1,
+        event: zwlr_layer_surface_v1::Event,
+        _: &PatternSurfaceData,
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+        match event {
+            zwlr_layer_surface_v1::Event::Configure {
+                serial,
+                width,
+                height,
+            } => {
+                layer_surface.ack_configure(serial);
+                if let Some(pattern) = this.pattern.as_mut() {
+                    pattern.draw(width, height);
+                }
+            }
+            zwlr_layer_surface_v1::Event::Closed => {
+                debug!("Test pattern surface closed by the compositor");
+                this.pattern.take();
+            }
+            _ => {}
+        }
+    }
+}
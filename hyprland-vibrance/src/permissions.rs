@@ -0,0 +1,99 @@
+//! Diagnostics for the Hyprland permission system. With
+//! `ecosystem:enforce_permissions` enabled, Hyprland hides sensitive
+//! globals from clients that weren't granted access to them, or kills
+//! the connection of clients that try to bind them anyway. Both look
+//! like plain missing support from our side, so explain what happened
+//! and how to fix it instead.
+
+use std::env;
+
+use log::{error, warn};
+use wayland_client::{DispatchError, backend::WaylandError};
+
+use crate::{HYPERLAND_CTM_CONTROL_MANAGER_IFACE, hyprland};
+
+/// Permission Hyprland checks before exposing the CTM control
+/// protocol to a client.
+const CTM_PERMISSION: &str = "ctm";
+
+/// Whether Hyprland is running and enforcing its permission system.
+/// `None` when Hyprland can't be queried at all.
+fn permissions_enforced() -> Option<bool> {
+    hyprland::option_int("ecosystem:enforce_permissions")
+        .ok()
+        .map(|value| value.unwrap_or(0) != 0)
+}
+
+/// Hyprland matches permission rules against the path of the binary
+/// with a regex, so escape anything with a special meaning.
+fn executable_regex() -> String {
+    let path = env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "/usr/bin/hyprland-vibrance".to_string());
+
+    let mut regex = String::from("^");
+    for c in path.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex.push('$');
+    regex
+}
+
+fn print_grant_guidance() {
+    error!(
+        "Hyprland denied access to {}. Grant it by adding this line to your hyprland.conf and restarting hyprland-vibrance:\n\n    permission = {}, {}, allow\n",
+        HYPERLAND_CTM_CONTROL_MANAGER_IFACE,
+        executable_regex(),
+        CTM_PERMISSION
+    );
+}
+
+/// Explains why the CTM control manager global is missing. Returns
+/// whether Hyprland is running, in which case it's worth to keep
+/// running until access is granted.
+pub fn explain_missing_ctm_manager() -> bool {
+    match permissions_enforced() {
+        Some(true) => {
+            print_grant_guidance();
+            true
+        }
+        Some(false) => {
+            warn!(
+                "Hyprland doesn't expose {}. It may be too old, or a plugin or security context may be hiding it",
+                HYPERLAND_CTM_CONTROL_MANAGER_IFACE
+            );
+            true
+        }
+        None => {
+            error!(
+                "Couldn't find Hyprland CTM control manager interface. Are you actually running Hyprland?"
+            );
+            false
+        }
+    }
+}
+
+/// Recognizes the protocol error Hyprland raises when a client binds
+/// a global it doesn't have permission for, and explains it. Returns
+/// whether the error was a permission denial.
+pub fn explain_dispatch_error(error: &DispatchError) -> bool {
+    let DispatchError::Backend(WaylandError::Protocol(protocol_error)) = error else {
+        return false;
+    };
+
+    if protocol_error.object_interface != HYPERLAND_CTM_CONTROL_MANAGER_IFACE
+        && !protocol_error.message.contains("permission")
+    {
+        return false;
+    }
+
+    error!(
+        "The compositor closed the connection: {} (code {} on {})",
+        protocol_error.message, protocol_error.code, protocol_error.object_interface
+    );
+    print_grant_guidance();
+    true
+}
@@ -0,0 +1,99 @@
+//! State that survives restarts, stored as JSON in
+//! `$XDG_STATE_HOME/hyprland-vibrance/state.json`. It only holds what
+//! was changed at runtime, and never replaces what the user passed on
+//! the command line: it's applied on top of it. It also records the
+//! levels applied last, so a restart doesn't undo them for a moment.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{color::Ctm, rules::Rule};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PersistedState {
+    /// Enabled state of the rules toggled at runtime, by rule name.
+    /// Entries for rules that don't exist anymore are kept, in case
+    /// they come back.
+    #[serde(default)]
+    pub rule_enabled: BTreeMap<String, bool>,
+    /// Outputs without an identity CTM as of the last evaluation, so a
+    /// restart in the same session can restore them right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_applied: Option<LastApplied>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LastApplied {
+    /// Hyprland instance the CTMs were applied in. They're only
+    /// restored within the same one.
+    pub instance_signature: Option<String>,
+    pub outputs: Vec<AppliedOutput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppliedOutput {
+    pub output: String,
+    #[serde(flatten)]
+    pub ctm: Ctm,
+    pub matrix: [f64; 9],
+    /// What decided the level, as shown by `status`.
+    pub source: Option<String>,
+}
+
+/// Directory where the state of the daemon is kept.
+pub fn state_dir() -> io::Result<PathBuf> {
+    let state_home = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var("HOME")
+                .map_err(|_| io::Error::new(ErrorKind::NotFound, "HOME is not set"))?;
+            PathBuf::from(home).join(".local/state")
+        }
+    };
+    Ok(state_home.join("hyprland-vibrance"))
+}
+
+fn state_path() -> io::Result<PathBuf> {
+    Ok(state_dir()?.join("state.json"))
+}
+
+impl PersistedState {
+    /// Loads the persisted state, returning an empty one if it was
+    /// never saved.
+    pub fn load() -> io::Result<PersistedState> {
+        match fs::read_to_string(state_path()?) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(io::Error::other),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(PersistedState::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = state_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write and rename so a crash never leaves a truncated file.
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(
+            &tmp_path,
+            serde_json::to_vec_pretty(self).map_err(io::Error::other)?,
+        )?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn apply_to_rules(&self, rules: &mut [Rule]) {
+        for rule in rules.iter_mut() {
+            if let Some(enabled) = rule.name.as_ref().and_then(|n| self.rule_enabled.get(n)) {
+                rule.enabled = *enabled;
+            }
+        }
+    }
+}
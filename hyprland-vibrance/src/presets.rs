@@ -0,0 +1,122 @@
+//! Defaults for known monitor models, matched by the make and model
+//! the compositor reports from their EDID. They only cap the
+//! saturation applied to those outputs, and log a suggested color
+//! temperature, so a badly tuned rule can't make an already vivid
+//! panel look absurd.
+//!
+//! Additional presets can be given in
+//! `$XDG_CONFIG_HOME/hyprland-vibrance/presets`, one per line, taking
+//! precedence over the built-in ones:
+//!
+//! ```text
+//! make="Samsung Display Corp." model=ATNA56 max_sat=1.3 temperature=6200
+//! ```
+//!
+//! `model` matches any model starting with the given value.
+
+use std::{env, fs, io, path::PathBuf, str::FromStr};
+
+use crate::units;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub make: String,
+    pub model_prefix: String,
+    pub max_saturation: Option<f64>,
+    /// Suggested white point, in Kelvin.
+    pub temperature: Option<u32>,
+}
+
+impl Preset {
+    fn matches(&self, make: &str, model: &str) -> bool {
+        make.eq_ignore_ascii_case(&self.make) && model.starts_with(&self.model_prefix)
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = shlex::split(s).ok_or_else(|| format!("'{}' has unbalanced quotes", s))?;
+        let mut make = None;
+        let mut model_prefix = None;
+        let mut max_saturation = None;
+        let mut temperature = None;
+
+        for token in tokens {
+            let Some((key, value)) = token.split_once('=') else {
+                return Err(format!("'{}' doesn't follow the KEY=VALUE format", token));
+            };
+
+            match key {
+                "make" => make = Some(value.to_string()),
+                "model" => model_prefix = Some(value.to_string()),
+                "max_sat" => max_saturation = Some(units::parse_saturation(value)?),
+                "temperature" => {
+                    temperature = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("'{}' isn't a valid temperature", value))?,
+                    )
+                }
+                _ => return Err(format!("unknown preset key '{}'", key)),
+            }
+        }
+
+        Ok(Preset {
+            make: make.ok_or("a preset needs a make")?,
+            model_prefix: model_prefix.ok_or("a preset needs a model")?,
+            max_saturation,
+            temperature,
+        })
+    }
+}
+
+/// Panels whose native gamut is much wider than sRGB, so unmanaged
+/// content already looks oversaturated on them.
+const BUILTIN_PRESETS: &[&str] = &[
+    // Samsung OLED laptop panels.
+    r#"make="Samsung Display Corp." model=ATNA max_sat=1.3"#,
+];
+
+fn user_presets_path() -> Option<PathBuf> {
+    let config_dir = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(config_dir.join("hyprland-vibrance").join("presets"))
+}
+
+/// Loads the user presets followed by the built-in ones.
+pub fn load() -> Result<Vec<Preset>, String> {
+    let mut presets = Vec::new();
+    if let Some(path) = user_presets_path() {
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for (idx, line) in contents.lines().enumerate() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    presets.push(
+                        line.parse()
+                            .map_err(|e| format!("{}:{}: {}", path.display(), idx + 1, e))?,
+                    );
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    presets.extend(
+        BUILTIN_PRESETS
+            .iter()
+            .map(|preset| preset.parse::<Preset>().expect("invalid built-in preset")),
+    );
+    Ok(presets)
+}
+
+pub fn find<'a>(presets: &'a [Preset], make: &str, model: &str) -> Option<&'a Preset> {
+    presets.iter().find(|preset| preset.matches(make, model))
+}
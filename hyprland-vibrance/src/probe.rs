@@ -0,0 +1,113 @@
+//! Shows what a CTM does to a few reference colors, to understand why a
+//! level looks blown out: once a channel goes beyond the range of the
+//! output it's clipped, and colors that differed end up the same.
+
+use std::io::{self, Write};
+
+use crate::color;
+
+/// Colors printed by `probe`, as 8-bit sRGB values.
+const PROBE_COLORS: &[(&str, [u8; 3])] = &[
+    ("red", [255, 0, 0]),
+    ("green", [0, 255, 0]),
+    ("blue", [0, 0, 255]),
+    ("cyan", [0, 255, 255]),
+    ("magenta", [255, 0, 255]),
+    ("yellow", [255, 255, 0]),
+    ("light skin", [194, 150, 130]),
+    ("dark skin", [115, 82, 68]),
+    ("pale skin", [255, 224, 189]),
+    ("tan skin", [198, 134, 66]),
+    ("white", [255, 255, 255]),
+    ("light gray", [192, 192, 192]),
+    ("mid gray", [128, 128, 128]),
+    ("dark gray", [64, 64, 64]),
+    ("black", [0, 0, 0]),
+];
+
+const CHANNELS: [char; 3] = ['R', 'G', 'B'];
+
+/// Excess below which a channel isn't considered clipped, half an 8-bit
+/// step, so rounding errors aren't reported.
+const CLIP_TOLERANCE: f64 = 0.5 / 255.0;
+
+/// Parses a matrix given as 9 numbers in row-major order, separated by
+/// commas or whitespace.
+pub fn parse_matrix(value: &str) -> Result<[f64; 9], String> {
+    let values = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("invalid matrix value '{}'", value))
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+
+    values
+        .try_into()
+        .map_err(|values: Vec<f64>| format!("expected 9 values, got {}", values.len()))
+}
+
+/// Prints how the matrix transforms every probe color, flagging the
+/// channels that end up out of range.
+pub fn print(matrix: &[f64; 9], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "matrix:")?;
+    for row in matrix.chunks(3) {
+        writeln!(out, "  {:8.4} {:8.4} {:8.4}", row[0], row[1], row[2])?;
+    }
+    writeln!(out)?;
+
+    let mut clipped_colors = 0;
+    for (name, rgb) in PROBE_COLORS {
+        let input = rgb.map(|value| value as f64 / 255.0);
+        let output = color::apply(matrix, &input);
+
+        let clipped: Vec<String> = output
+            .iter()
+            .zip(CHANNELS)
+            .filter_map(|(value, channel)| {
+                if *value > 1.0 + CLIP_TOLERANCE {
+                    Some(format!("{}+{:.0}", channel, (value - 1.0) * 255.0))
+                } else if *value < -CLIP_TOLERANCE {
+                    Some(format!("{}-{:.0}", channel, -value * 255.0))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if !clipped.is_empty() {
+            clipped_colors += 1;
+        }
+
+        let shown = output.map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8);
+        let line = format!(
+            "{:<11} #{:02x}{:02x}{:02x} -> #{:02x}{:02x}{:02x}  {}",
+            name,
+            rgb[0],
+            rgb[1],
+            rgb[2],
+            shown[0],
+            shown[1],
+            shown[2],
+            if clipped.is_empty() {
+                String::new()
+            } else {
+                format!("clipped {}", clipped.join(" "))
+            }
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    writeln!(out)?;
+    if clipped_colors == 0 {
+        writeln!(out, "No channel is clipped")
+    } else {
+        writeln!(
+            out,
+            "{} of {} colors have clipped channels, shown as the excess in 8-bit steps",
+            clipped_colors,
+            PROBE_COLORS.len()
+        )
+    }
+}
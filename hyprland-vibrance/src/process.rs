@@ -0,0 +1,92 @@
+//! What `/proc` tells about the process behind a window, for rules
+//! matching launch wrappers whose windows have generic titles, like the
+//! Wine preloader, or sandboxed apps.
+
+use std::fs;
+
+/// Name of the executable of the process, as in `/proc/<pid>/comm`.
+/// The kernel truncates it to 15 characters.
+pub fn name(pid: i32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end_matches('\n').to_string())
+}
+
+/// Id of the parent of the process, from `/proc/<pid>/stat`.
+pub fn parent(pid: i32) -> Option<i32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name is in parentheses and may contain spaces, the parent id
+    // is the second field after it.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Whether the process is gamescope, or was started by it.
+pub fn runs_under_gamescope(pid: i32) -> bool {
+    let mut pid = pid;
+    while pid > 1 {
+        if name(pid).is_some_and(|name| name.starts_with("gamescope")) {
+            return true;
+        }
+        let Some(parent) = parent(pid) else {
+            return false;
+        };
+        pid = parent;
+    }
+    false
+}
+
+/// Gamescope windows get the `gamescope` class, whatever runs in them.
+pub fn is_gamescope_class(class: &str) -> bool {
+    class.eq_ignore_ascii_case("gamescope")
+}
+
+/// Command line of the process, with its arguments separated by spaces.
+pub fn command_line(pid: i32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let arguments: Vec<String> = cmdline
+        .split(|byte| *byte == 0)
+        .filter(|argument| !argument.is_empty())
+        .map(|argument| String::from_utf8_lossy(argument).into_owned())
+        .collect();
+    Some(arguments.join(" ")).filter(|cmdline| !cmdline.is_empty())
+}
+
+/// Path of the cgroup of the process in the unified hierarchy, e.g.
+/// `/user.slice/user-1000.slice/user@1000.service/app.slice/app-flatpak-com.valvesoftware.Steam-1234.scope`.
+pub fn cgroup(pid: i32) -> Option<String> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_string)
+}
+
+/// Flatpak application id of the sandbox the process runs in, from the
+/// `.flatpak-info` file Flatpak puts at the root of every sandbox, or
+/// failing that from the name of the scope systemd runs it in.
+pub fn flatpak_id(pid: i32) -> Option<String> {
+    if let Ok(info) = fs::read_to_string(format!("/proc/{}/root/.flatpak-info", pid)) {
+        let mut in_application = false;
+        for line in info.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                in_application = line == "[Application]";
+            } else if in_application && let Some(name) = line.strip_prefix("name=") {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    flatpak_id_from_cgroup(&cgroup(pid)?)
+}
+
+/// Scopes of Flatpak apps are named `app-flatpak-<id>-<number>.scope`.
+fn flatpak_id_from_cgroup(cgroup: &str) -> Option<String> {
+    let scope = cgroup.rsplit('/').next()?;
+    let id = scope
+        .strip_prefix("app-flatpak-")?
+        .strip_suffix(".scope")?
+        .rsplit_once('-')?
+        .0;
+    Some(id.to_string())
+}
@@ -0,0 +1,192 @@
+//! Reloading the rule set without restarting, on SIGHUP and whenever
+//! the config file or the rules file changes. Files are watched with
+//! inotify through their directory, since most editors save by
+//! replacing the file rather than writing to it.
+//!
+//! Only the rules are reloaded. Options keep the value they had at
+//! startup. Rules added or changed at runtime are kept, see
+//! [`crate::AppState::reload_rules`].
+
+use std::{
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic};
+use log::{debug, error, info, warn};
+
+use crate::{AppState, config, rules, rules::Rule, signals};
+
+/// Where the rule set comes from, in precedence order.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSources {
+    /// Rules given with `--title-match` and `--rule`.
+    pub command_line: Vec<(Rule, String)>,
+    pub rules_file: Option<PathBuf>,
+    /// Config file given with `--config`, instead of the default one.
+    pub config: Option<PathBuf>,
+}
+
+impl RuleSources {
+    /// Builds the rule set from the rules file, read again, and the
+    /// given config rules, along with a description of where each rule
+    /// comes from.
+    pub fn load_with(
+        &self,
+        config_rules: Vec<(Rule, String)>,
+    ) -> Result<Vec<(Rule, String)>, String> {
+        let mut rules = self.command_line.clone();
+        if let Some(path) = self.rules_file.as_ref() {
+            rules.extend(
+                rules::load_file(path)?
+                    .into_iter()
+                    .map(|(rule, line)| (rule, format!("{}:{}", path.display(), line))),
+            );
+        }
+        rules.extend(config_rules);
+        Ok(rules)
+    }
+
+    /// Builds the rule set reading every file again.
+    pub fn load(&self) -> Result<Vec<(Rule, String)>, String> {
+        let config_rules = match config::Config::load(self.config.as_deref())? {
+            Some((config, path)) => config.named_rules(&path)?,
+            None => Vec::new(),
+        };
+        self.load_with(config_rules)
+    }
+
+    /// The files the rules are read from, whether they exist or not.
+    fn files(&self) -> Vec<PathBuf> {
+        self.rules_file
+            .iter()
+            .cloned()
+            .chain(self.config.clone().or_else(config::default_path))
+            .collect()
+    }
+}
+
+/// Splits the events read from an inotify descriptor, returning the
+/// name each of them refers to.
+fn event_names(buffer: &[u8]) -> Vec<&OsStr> {
+    const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset + HEADER <= buffer.len() {
+        // SAFETY: the header is within the buffer, and inotify_event is
+        // plain data.
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+        let name_start = offset + HEADER;
+        let name_end = (name_start + event.len as usize).min(buffer.len());
+        // The name is padded with NULs.
+        let name = &buffer[name_start..name_end];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        names.push(OsStr::from_bytes(name));
+        offset = name_end;
+    }
+    names
+}
+
+fn watch_files(handle: &LoopHandle<'_, AppState>, files: Vec<PathBuf>) -> io::Result<()> {
+    // SAFETY: plain syscall without pointers.
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and isn't owned elsewhere.
+    let inotify = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let mut watched: Vec<&Path> = Vec::new();
+    for dir in files.iter().filter_map(|file| file.parent()) {
+        if watched.contains(&dir) {
+            continue;
+        }
+        let c_dir = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+        // SAFETY: c_dir is a valid NUL terminated string.
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                fd,
+                c_dir.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE,
+            )
+        };
+        if wd < 0 {
+            warn!(
+                "Couldn't watch {} for changes: {}",
+                dir.display(),
+                io::Error::last_os_error()
+            );
+            continue;
+        }
+        debug!("Watching {} for rule changes", dir.display());
+        watched.push(dir);
+    }
+
+    handle
+        .insert_source(
+            Generic::new(inotify, Interest::READ, Mode::Level),
+            move |_, inotify, state| {
+                let mut inotify: &File = inotify;
+                let mut buffer = vec![0u8; 4096];
+                let mut changed: Vec<&Path> = Vec::new();
+                loop {
+                    match inotify.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(read) => {
+                            for name in event_names(&buffer[..read]) {
+                                for file in files.iter() {
+                                    if file.file_name() == Some(name)
+                                        && !changed.contains(&file.as_path())
+                                    {
+                                        changed.push(file);
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            error!("Stopped watching the rule files: {}", e);
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+
+                // Writing rules back to the rules file shouldn't reload
+                // them, which would move the ones added first to the end.
+                if !changed.is_empty()
+                    && !changed
+                        .iter()
+                        .all(|file| state.rules_file_written_by_us(file))
+                {
+                    info!("Rule files changed, reloading the rules");
+                    let _ = state.reload_rules();
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Reloads the rules on SIGHUP and when their files change.
+pub fn listen(handle: &LoopHandle<'_, AppState>, sources: &RuleSources) {
+    if let Err(e) = signals::listen(handle, &[libc::SIGHUP], |state, _| {
+        info!("Received SIGHUP, reloading the rules");
+        let _ = state.reload_rules();
+    }) {
+        error!("Couldn't handle SIGHUP: {}", e);
+    }
+
+    if let Err(e) = watch_files(handle, sources.files()) {
+        error!("Couldn't watch the rule files for changes: {}", e);
+    }
+}
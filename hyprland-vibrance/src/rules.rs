@@ -0,0 +1,1276 @@
+//! Rules deciding which saturation gets applied to a top level. Rules
+//! can be written as a whitespace separated list of `key=value` pairs,
+//! with shell-like quoting for values containing spaces:
+//!
+//! ```text
+//! name=games title="Counter-Strike 2" title=Deadlock sat=3.3 other_sat=0.8
+//! ```
+//!
+//! Levels can be given as a percentage (`sat=150%`), or on the NVIDIA
+//! digital vibrance scale with `nv=512` instead of `sat=...`. They can
+//! also be relative to the level the output would have otherwise, its
+//! workspace level, the default level or 1.0, with `boost=+0.8` or
+//! `boost=x1.3`.
+//!
+//! `temperature=5000K` also shifts the white point of the outputs the
+//! rule applies to, warmer below 6500K and cooler above, and
+//! `brightness=80%` scales every channel, and `contrast=120%` the luma
+//! of colors from black. A rule can adjust them without changing the
+//! saturation.
+//!
+//! Instead of a level, `effect=grayscale` or `effect=invert` turns the
+//! rule into a filter, see [`crate::color::Effect`], and
+//! `matrix="1.2 -0.1 -0.1 ..."` applies the given matrix as is, 9
+//! values in row-major order.
+//!
+//! A rule matches when the top level matches any of the values given
+//! for each of the keys used. `initial_title` and `initial_class` refer
+//! to the identity Hyprland recorded when the window was opened, which
+//! is only known once the top level is tied to its Hyprland window,
+//! and so is `class`, the class the window has now. That's done through
+//! the toplevel mapping protocol, or by title and class on Hyprland
+//! versions without it. `initialClass` is
+//! accepted for `initial_class`, as Hyprland spells it.
+//!
+//! `process` matches the executable name of the process owning the
+//! window, as in `/proc/<pid>/comm`, and `cmdline` its command line,
+//! a glob pattern like `app_id` (`cmdline=*eldenring.exe*`), for
+//! launch wrappers with generic window titles. `flatpak` matches the
+//! Flatpak app id of the sandbox the process runs in
+//! (`flatpak=com.valvesoftware.Steam`), and `cgroup` its systemd
+//! cgroup, a glob pattern too (`cgroup=*app-gamescope*`). The process
+//! id comes from the Hyprland IPC.
+//!
+//! `tag=games` requires the window to have a Hyprland tag, given with
+//! `windowrule = tag +games, ...` in `hyprland.conf`. With
+//! `--hyprland-tags`, a window tagged `vibrance:3.2` gets that level
+//! with no rule needed, see [`tag_rule`].
+//!
+//! `app_id` refers to the app id the compositor reports for the top
+//! level, which unlike titles doesn't change while a game runs, and is
+//! a glob pattern unless another match mode is set
+//! (`app_id=steam_app_*`).
+//!
+//! Values are compared exactly unless the rule sets another match mode
+//! with `match=...`, which applies to its `title`, `initial_title`,
+//! `initial_class`, `class`, `process`, `cmdline`, `flatpak`, `cgroup`,
+//! `tag` and `app_id` values: `contains`, `starts_with`,
+//! `ends_with` or `glob`, where `*` matches any run of characters and
+//! `?` any single one (`match=contains title=Steam`).
+//!
+//! Besides window properties, rules can require the active Hyprland
+//! keybind `submap`, or the active `workspace` of the focused monitor,
+//! by name or id. A rule without any window condition (e.g. just
+//! `submap=gaming sat=2.5`) doesn't depend on the focused window: it
+//! applies to every output while its conditions hold.
+//!
+//! `min_width` and `min_height` require the window to be at least that
+//! big, in logical pixels, and `min_coverage` requires it to cover at
+//! least some part of its output (`min_coverage=80%` or `0.8`), so
+//! small floating windows of a program don't trigger it. The window
+//! geometry comes from the Hyprland IPC.
+//!
+//! With `group=yes`, a window that's part of a Hyprland group (tabbed
+//! windows) also matches when any other window of its group does, so
+//! cycling through the tabs of a group containing a game doesn't make
+//! the level flicker.
+//!
+//! `floating=yes` or `no` requires the window to be floating or tiled,
+//! and `xwayland=yes` or `no` to be an XWayland or a native Wayland
+//! window, both as reported by the Hyprland IPC. `gamescope=yes` or
+//! `no` requires the window to be a gamescope one, told from its class
+//! or its process and the ancestors of it.
+//!
+//! With `require_fullscreen=yes`, a matching window only counts while
+//! it's fullscreen, so a game running windowed on the desktop keeps the
+//! usual colors. `require_maximized=yes` does the same for maximized
+//! windows, which is what some borderless windowed games report
+//! instead. A rule with both accepts either state.
+//!
+//! With `all_outputs=yes`, the level of a matching window goes to every
+//! output instead of the ones showing it.
+//!
+//! With `visible=yes`, the rule applies to the outputs a matching window
+//! is on, as long as it isn't minimized, whether it's focused or not, so
+//! overlays and chat windows taking the focus don't flip the colors.
+//! The focused window still wins on its own outputs.
+//!
+//! `output=DP-1` limits the rule to the given outputs, by connector
+//! name or by description, or by EDID with `output="model=LG ULTRAGEAR"`
+//! (also `make=` and `serial=`), any of them when repeated. The other
+//! outputs get the level they'd have without the rule.
+//!
+//! `priority=N` (0 by default, possibly negative) decides which rule
+//! wins when several match, see [`crate::engine`].
+//!
+//! Rules can also be kept in a file given with `--rules-file`, one per
+//! line, where empty lines and lines starting with `#` are ignored.
+//! With `--write-back`, rules added at runtime are stored at the end of
+//! that file, between a pair of marker comments. Only that section is
+//! ever rewritten.
+//!
+//! Named rules can be disabled and re-enabled at runtime through the
+//! control socket. Disabled rules never match.
+
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+use crate::{
+    TopLevelInfo,
+    color::{self, Effect},
+    hyprland, units,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: Option<String>,
+    pub enabled: bool,
+    /// Whether the rule lives in the section of the rules file written
+    /// by the daemon.
+    pub managed: bool,
+    /// Whether the rule was made up for a Hyprland tag, see
+    /// [`tag_rule`].
+    pub from_tag: bool,
+    /// Whether the rule was added at runtime without being written
+    /// back, which reloading the rules keeps it through.
+    pub added_at_runtime: bool,
+    /// The rule as it was loaded, for a loaded rule changed at runtime.
+    /// The change is kept through a reload as long as the source of the
+    /// rule still has it as it was.
+    pub loaded_as: Option<Box<Rule>>,
+    /// Rules with a higher priority win over the ones with a lower one,
+    /// regardless of their order.
+    pub priority: i32,
+    /// Titles the top level must match (any of them), as given by
+    /// `match_mode`.
+    pub titles: Vec<String>,
+    pub initial_titles: Vec<String>,
+    pub initial_classes: Vec<String>,
+    /// Current Hyprland classes, which games rarely change.
+    pub classes: Vec<String>,
+    /// Executable names of the process owning the top level.
+    pub processes: Vec<String>,
+    /// Command line patterns of the process owning the top level, in
+    /// the same mode as app ids.
+    pub cmdlines: Vec<String>,
+    /// Flatpak app ids of the sandbox the top level runs in.
+    pub flatpaks: Vec<String>,
+    /// Cgroup patterns of the process owning the top level, in the same
+    /// mode as app ids.
+    pub cgroups: Vec<String>,
+    /// Hyprland tags, one of which the top level must have.
+    pub tags: Vec<String>,
+    /// App ids or app id patterns, like `steam_app_*`.
+    pub app_ids: Vec<String>,
+    /// Hyprland submaps, one of which must be active.
+    pub submaps: Vec<String>,
+    /// Names or ids of Hyprland workspaces, one of which must be the
+    /// active one of the focused monitor.
+    pub workspaces: Vec<String>,
+    pub min_width: Option<i32>,
+    pub min_height: Option<i32>,
+    /// Fraction of the output the window must cover, from 0.0 to 1.0.
+    pub min_coverage: Option<f64>,
+    /// How title, class and app id values are compared.
+    pub match_mode: MatchMode,
+    /// Whether the windows grouped with a matching one match too.
+    pub match_group: bool,
+    /// Whether the top level must be floating, or tiled.
+    pub floating: Option<bool>,
+    /// Whether the top level must be an XWayland window, or a native
+    /// Wayland one.
+    pub xwayland: Option<bool>,
+    /// Whether the top level must be a gamescope window.
+    pub gamescope: Option<bool>,
+    /// Whether the top level must be fullscreen.
+    pub require_fullscreen: bool,
+    /// Whether the top level must be maximized, or fullscreen too if
+    /// `require_fullscreen` is set.
+    pub require_maximized: bool,
+    /// Whether the level goes to every output instead of the ones
+    /// showing the matched top level.
+    pub all_outputs: bool,
+    /// Whether the rule applies while a matching top level is on an
+    /// output, even when it isn't focused.
+    pub visible: bool,
+    pub saturation: f64,
+    /// When set, the level is relative to the base level of the output
+    /// and `saturation` is ignored.
+    pub boost: Option<Boost>,
+    /// Saturation for the outputs not showing the matched top level
+    /// while the rule is active.
+    pub other_saturation: Option<f64>,
+    /// White point applied along with the level, in Kelvin.
+    pub temperature: Option<f64>,
+    pub brightness: Option<f64>,
+    pub contrast: Option<f64>,
+    /// Filter applied instead of a saturation level.
+    pub effect: Option<Effect>,
+    /// Connector names or descriptions of the outputs the rule applies
+    /// to, all of them when empty.
+    pub outputs: Vec<String>,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            name: None,
+            enabled: true,
+            managed: false,
+            from_tag: false,
+            added_at_runtime: false,
+            loaded_as: None,
+            priority: 0,
+            titles: Vec::new(),
+            initial_titles: Vec::new(),
+            initial_classes: Vec::new(),
+            classes: Vec::new(),
+            processes: Vec::new(),
+            cmdlines: Vec::new(),
+            flatpaks: Vec::new(),
+            cgroups: Vec::new(),
+            tags: Vec::new(),
+            app_ids: Vec::new(),
+            submaps: Vec::new(),
+            workspaces: Vec::new(),
+            min_width: None,
+            min_height: None,
+            min_coverage: None,
+            match_mode: MatchMode::Exact,
+            match_group: false,
+            floating: None,
+            xwayland: None,
+            gamescope: None,
+            require_fullscreen: false,
+            require_maximized: false,
+            all_outputs: false,
+            visible: false,
+            saturation: 1.0,
+            boost: None,
+            other_saturation: None,
+            temperature: None,
+            brightness: None,
+            contrast: None,
+            effect: None,
+            outputs: Vec::new(),
+        }
+    }
+}
+
+/// How the values of a rule are compared to the properties of a top
+/// level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    Exact,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Glob,
+}
+
+impl MatchMode {
+    pub fn matches(self, pattern: &str, value: &str) -> bool {
+        match self {
+            MatchMode::Exact => value == pattern,
+            MatchMode::Contains => value.contains(pattern),
+            MatchMode::StartsWith => value.starts_with(pattern),
+            MatchMode::EndsWith => value.ends_with(pattern),
+            MatchMode::Glob => matches_glob(pattern, value),
+        }
+    }
+
+    /// The only value the pattern matches, if it matches just one.
+    fn literal(self, pattern: &str) -> Option<&str> {
+        match self {
+            MatchMode::Exact => Some(pattern),
+            MatchMode::Glob if !pattern.contains(['*', '?']) => Some(pattern),
+            _ => None,
+        }
+    }
+
+    /// Whether the broad pattern matches every value the narrow one
+    /// does, as far as can be told without enumerating them.
+    fn covers(self, broad: &str, narrow_mode: MatchMode, narrow: &str) -> bool {
+        if let Some(literal) = narrow_mode.literal(narrow) {
+            return self.matches(broad, literal);
+        }
+        match (self, narrow_mode) {
+            (MatchMode::Contains, MatchMode::Contains)
+            | (MatchMode::Contains, MatchMode::StartsWith)
+            | (MatchMode::Contains, MatchMode::EndsWith) => narrow.contains(broad),
+            (MatchMode::StartsWith, MatchMode::StartsWith) => narrow.starts_with(broad),
+            (MatchMode::EndsWith, MatchMode::EndsWith) => narrow.ends_with(broad),
+            _ => self == narrow_mode && broad == narrow,
+        }
+    }
+}
+
+impl FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(MatchMode::Exact),
+            "contains" => Ok(MatchMode::Contains),
+            "starts_with" => Ok(MatchMode::StartsWith),
+            "ends_with" => Ok(MatchMode::EndsWith),
+            "glob" => Ok(MatchMode::Glob),
+            _ => Err(format!(
+                "'{}' isn't a valid match mode, use exact, contains, starts_with, ends_with or glob",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MatchMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchMode::Exact => "exact",
+            MatchMode::Contains => "contains",
+            MatchMode::StartsWith => "starts_with",
+            MatchMode::EndsWith => "ends_with",
+            MatchMode::Glob => "glob",
+        })
+    }
+}
+
+/// Whether the value matches the pattern, where `*` matches any run of
+/// characters, possibly empty, and `?` any single character.
+fn matches_glob(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` seen, and of the value when it was seen,
+    // to backtrack to when the rest doesn't match.
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, v));
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_v)) => {
+                    p = star_p;
+                    v = star_v + 1;
+                    backtrack = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Level relative to the base level of an output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boost {
+    /// Added to the base level (`+0.8`, `-0.2`).
+    Delta(f64),
+    /// Multiplies the base level (`x1.3`).
+    Factor(f64),
+}
+
+impl Boost {
+    pub fn apply(self, base: f64) -> f64 {
+        let level = match self {
+            Boost::Delta(delta) => base + delta,
+            Boost::Factor(factor) => base * factor,
+        };
+        let range = units::saturation_range();
+        level.clamp(*range.start(), *range.end())
+    }
+}
+
+impl FromStr for Boost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' isn't a valid boost, use e.g. +0.8 or x1.3", s);
+        if let Some(factor) = ["x", "×", "*"]
+            .iter()
+            .find_map(|prefix| s.strip_prefix(prefix))
+        {
+            let factor: f64 = factor.parse().map_err(|_| invalid())?;
+            if factor < 0.0 {
+                return Err(invalid());
+            }
+            Ok(Boost::Factor(factor))
+        } else if s.starts_with('+') || s.starts_with('-') {
+            s.parse().map(Boost::Delta).map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl fmt::Display for Boost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Boost::Delta(delta) => write!(f, "{:+}", delta),
+            Boost::Factor(factor) => write!(f, "x{}", factor),
+        }
+    }
+}
+
+/// State of the session, beyond the top level being evaluated, that
+/// rules can depend on.
+#[derive(Debug, Default)]
+pub struct Context<'a> {
+    pub submap: Option<&'a str>,
+    /// Active workspace of the focused monitor.
+    pub workspace: Option<&'a hyprland::WorkspaceRef>,
+    /// Every known top level, to find the other windows of a group.
+    pub top_levels: &'a [TopLevelInfo],
+}
+
+fn matches_any(values: &[String], value: Option<&str>) -> bool {
+    matches_any_with(MatchMode::Exact, values, value)
+}
+
+fn matches_any_with(mode: MatchMode, patterns: &[String], value: Option<&str>) -> bool {
+    patterns.is_empty()
+        || value.is_some_and(|value| patterns.iter().any(|pattern| mode.matches(pattern, value)))
+}
+
+impl Rule {
+    /// Whether the rule has conditions on the top level at all.
+    pub fn is_window_rule(&self) -> bool {
+        !self.titles.is_empty()
+            || !self.initial_titles.is_empty()
+            || !self.initial_classes.is_empty()
+            || !self.classes.is_empty()
+            || !self.processes.is_empty()
+            || !self.cmdlines.is_empty()
+            || !self.flatpaks.is_empty()
+            || !self.cgroups.is_empty()
+            || !self.tags.is_empty()
+            || !self.app_ids.is_empty()
+            || self.xwayland.is_some()
+            || self.gamescope.is_some()
+            || self.floating.is_some()
+            || self.require_fullscreen
+            || self.require_maximized
+            || self.uses_geometry()
+    }
+
+    /// Whether the rule needs the geometry of the windows, which has to
+    /// be queried from Hyprland.
+    pub fn uses_geometry(&self) -> bool {
+        self.min_width.is_some() || self.min_height.is_some() || self.min_coverage.is_some()
+    }
+
+    /// Whether the rule needs anything from the Hyprland clients list:
+    /// the geometry or the groups of the windows.
+    pub fn uses_clients(&self) -> bool {
+        self.uses_geometry()
+            || self.match_group
+            || self.floating.is_some()
+            || !self.classes.is_empty()
+            || !self.tags.is_empty()
+    }
+
+    /// App ids are patterns already in the default mode.
+    fn app_id_mode(&self) -> MatchMode {
+        match self.match_mode {
+            MatchMode::Exact => MatchMode::Glob,
+            mode => mode,
+        }
+    }
+
+    /// Whether both rules have exactly the same conditions, in which
+    /// case only the one taking precedence can ever apply.
+    pub fn same_conditions(&self, other: &Rule) -> bool {
+        let sorted = |values: &[String]| {
+            let mut values = values.to_vec();
+            values.sort();
+            values.dedup();
+            values
+        };
+
+        sorted(&self.titles) == sorted(&other.titles)
+            && sorted(&self.initial_titles) == sorted(&other.initial_titles)
+            && sorted(&self.initial_classes) == sorted(&other.initial_classes)
+            && sorted(&self.classes) == sorted(&other.classes)
+            && sorted(&self.processes) == sorted(&other.processes)
+            && sorted(&self.cmdlines) == sorted(&other.cmdlines)
+            && sorted(&self.flatpaks) == sorted(&other.flatpaks)
+            && sorted(&self.cgroups) == sorted(&other.cgroups)
+            && sorted(&self.tags) == sorted(&other.tags)
+            && sorted(&self.app_ids) == sorted(&other.app_ids)
+            && sorted(&self.submaps) == sorted(&other.submaps)
+            && sorted(&self.workspaces) == sorted(&other.workspaces)
+            && sorted(&self.outputs) == sorted(&other.outputs)
+            && self.min_width == other.min_width
+            && self.min_height == other.min_height
+            && self.min_coverage == other.min_coverage
+            && self.match_mode == other.match_mode
+            && self.match_group == other.match_group
+            && self.floating == other.floating
+            && self.xwayland == other.xwayland
+            && self.gamescope == other.gamescope
+            && self.require_fullscreen == other.require_fullscreen
+            && self.require_maximized == other.require_maximized
+    }
+
+    /// Whether every top level matching `other` also matches this rule,
+    /// so `other` never applies if this one takes precedence.
+    pub fn covers(&self, other: &Rule) -> bool {
+        // Window and global rules are evaluated separately.
+        if self.is_window_rule() != other.is_window_rule() {
+            return false;
+        }
+
+        let values_cover = |broad: &[String], narrow: &[String]| {
+            broad.is_empty() || (!narrow.is_empty() && narrow.iter().all(|v| broad.contains(v)))
+        };
+        let patterns_cover =
+            |broad_mode: MatchMode, broad: &[String], narrow_mode: MatchMode, narrow: &[String]| {
+                broad.is_empty()
+                    || (!narrow.is_empty()
+                        && narrow
+                            .iter()
+                            .all(|n| broad.iter().any(|b| broad_mode.covers(b, narrow_mode, n))))
+            };
+        let min_covers = |broad: Option<i32>, narrow: Option<i32>| {
+            broad.is_none_or(|broad| narrow.is_some_and(|narrow| narrow >= broad))
+        };
+
+        let (mode, other_mode) = (self.match_mode, other.match_mode);
+        patterns_cover(mode, &self.titles, other_mode, &other.titles)
+            && patterns_cover(
+                mode,
+                &self.initial_titles,
+                other_mode,
+                &other.initial_titles,
+            )
+            && patterns_cover(
+                mode,
+                &self.initial_classes,
+                other_mode,
+                &other.initial_classes,
+            )
+            && patterns_cover(mode, &self.classes, other_mode, &other.classes)
+            && patterns_cover(mode, &self.processes, other_mode, &other.processes)
+            && patterns_cover(
+                self.app_id_mode(),
+                &self.cmdlines,
+                other.app_id_mode(),
+                &other.cmdlines,
+            )
+            && patterns_cover(mode, &self.flatpaks, other_mode, &other.flatpaks)
+            && patterns_cover(
+                self.app_id_mode(),
+                &self.cgroups,
+                other.app_id_mode(),
+                &other.cgroups,
+            )
+            && patterns_cover(mode, &self.tags, other_mode, &other.tags)
+            && patterns_cover(
+                self.app_id_mode(),
+                &self.app_ids,
+                other.app_id_mode(),
+                &other.app_ids,
+            )
+            && values_cover(&self.submaps, &other.submaps)
+            && values_cover(&self.workspaces, &other.workspaces)
+            && values_cover(&self.outputs, &other.outputs)
+            && min_covers(self.min_width, other.min_width)
+            && min_covers(self.min_height, other.min_height)
+            && self
+                .min_coverage
+                .is_none_or(|broad| other.min_coverage.is_some_and(|narrow| narrow >= broad))
+            && (self.match_group || !other.match_group)
+            && self
+                .floating
+                .is_none_or(|floating| other.floating == Some(floating))
+            && self
+                .xwayland
+                .is_none_or(|xwayland| other.xwayland == Some(xwayland))
+            && self
+                .gamescope
+                .is_none_or(|gamescope| other.gamescope == Some(gamescope))
+            && self.state_covers(other)
+    }
+
+    /// Whether every window state accepted by `other` is accepted by
+    /// this rule too.
+    fn state_covers(&self, other: &Rule) -> bool {
+        [(false, false), (true, false), (false, true), (true, true)]
+            .into_iter()
+            .all(|(fullscreen, maximized)| {
+                !other.accepts_state(fullscreen, maximized)
+                    || self.accepts_state(fullscreen, maximized)
+            })
+    }
+
+    fn accepts_state(&self, fullscreen: bool, maximized: bool) -> bool {
+        !(self.require_fullscreen || self.require_maximized)
+            || (self.require_fullscreen && fullscreen)
+            || (self.require_maximized && maximized)
+    }
+
+    fn matches_geometry(&self, top_level: &TopLevelInfo) -> bool {
+        if !self.uses_geometry() {
+            return true;
+        }
+
+        let Some(geometry) = top_level.geometry.as_ref() else {
+            return false;
+        };
+        self.min_width.is_none_or(|min| geometry.width >= min)
+            && self.min_height.is_none_or(|min| geometry.height >= min)
+            && self
+                .min_coverage
+                .is_none_or(|min| geometry.coverage.is_some_and(|coverage| coverage >= min))
+    }
+
+    /// Things that are allowed but are most likely a mistake.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (key, values, mode) in [
+            ("title", &self.titles, self.match_mode),
+            ("initial_title", &self.initial_titles, self.match_mode),
+            ("initial_class", &self.initial_classes, self.match_mode),
+            ("class", &self.classes, self.match_mode),
+            ("process", &self.processes, self.match_mode),
+            ("cmdline", &self.cmdlines, self.app_id_mode()),
+            ("flatpak", &self.flatpaks, self.match_mode),
+            ("cgroup", &self.cgroups, self.app_id_mode()),
+            ("tag", &self.tags, self.match_mode),
+            ("app_id", &self.app_ids, self.app_id_mode()),
+        ] {
+            for value in values {
+                if value.is_empty()
+                    && matches!(
+                        mode,
+                        MatchMode::Contains | MatchMode::StartsWith | MatchMode::EndsWith
+                    )
+                {
+                    warnings.push(format!("empty {} matches every window", key));
+                } else if mode == MatchMode::Exact && value.contains(['*', '?']) {
+                    warnings.push(format!(
+                        "{} '{}' is compared exactly, use match=glob for a pattern",
+                        key, value
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Remembers the rule as loaded before changing it at runtime, see
+    /// [`Rule::loaded_as`]. Managed rules don't need it, the change is
+    /// written back.
+    pub fn keep_loaded(&mut self) {
+        if !self.managed && !self.from_tag && !self.added_at_runtime && self.loaded_as.is_none() {
+            self.loaded_as = Some(Box::new(self.clone()));
+        }
+    }
+
+    /// Level applied by the rule on an output with the given base
+    /// level.
+    pub fn level(&self, base: f64) -> f64 {
+        self.boost
+            .map(|boost| boost.apply(base))
+            .unwrap_or(self.saturation)
+    }
+
+    pub fn describe_level(&self) -> String {
+        let mut level = match (self.effect, self.boost) {
+            (Some(effect @ Effect::Matrix(_)), _) => format!("matrix=\"{}\"", effect),
+            (Some(effect), _) => format!("effect={}", effect),
+            (None, Some(boost)) => format!("boost={}", boost),
+            (None, None) => format!("sat={}", self.saturation),
+        };
+        if let Some(temperature) = self.temperature {
+            level.push_str(&format!(" temperature={}K", temperature));
+        }
+        if let Some(brightness) = self.brightness {
+            level.push_str(&format!(
+                " brightness={}",
+                units::format_percent(brightness)
+            ));
+        }
+        if let Some(contrast) = self.contrast {
+            level.push_str(&format!(" contrast={}", units::format_percent(contrast)));
+        }
+        level
+    }
+
+    pub fn matches_context(&self, context: &Context) -> bool {
+        self.enabled
+            && matches_any(&self.submaps, context.submap)
+            && (self.workspaces.is_empty()
+                || context.workspace.is_some_and(|workspace| {
+                    self.workspaces
+                        .iter()
+                        .any(|reference| workspace.is(reference))
+                }))
+    }
+
+    fn matches_window(&self, top_level: &TopLevelInfo) -> bool {
+        let mode = self.match_mode;
+        matches_any_with(mode, &self.titles, top_level.title.as_deref())
+            && matches_any_with(
+                mode,
+                &self.initial_titles,
+                top_level.initial_title.as_deref(),
+            )
+            && matches_any_with(
+                mode,
+                &self.initial_classes,
+                top_level.initial_class.as_deref(),
+            )
+            && matches_any_with(mode, &self.classes, top_level.class.as_deref())
+            && matches_any_with(mode, &self.processes, top_level.process.as_deref())
+            && matches_any_with(
+                self.app_id_mode(),
+                &self.cmdlines,
+                top_level.cmdline.as_deref(),
+            )
+            && matches_any_with(mode, &self.flatpaks, top_level.flatpak.as_deref())
+            && matches_any_with(
+                self.app_id_mode(),
+                &self.cgroups,
+                top_level.cgroup.as_deref(),
+            )
+            && (self.tags.is_empty()
+                || top_level
+                    .tags
+                    .iter()
+                    .any(|tag| matches_any_with(mode, &self.tags, Some(tag))))
+            && matches_any_with(
+                self.app_id_mode(),
+                &self.app_ids,
+                top_level.app_id.as_deref(),
+            )
+            && self.matches_geometry(top_level)
+            && self
+                .floating
+                .is_none_or(|floating| top_level.floating == floating)
+            && self
+                .xwayland
+                .is_none_or(|xwayland| top_level.xwayland == xwayland)
+            && self
+                .gamescope
+                .is_none_or(|gamescope| top_level.gamescope == gamescope)
+            && self.accepts_state(top_level.fullscreen, top_level.maximized)
+    }
+
+    fn matches_group(&self, top_level: &TopLevelInfo, context: &Context) -> bool {
+        self.match_group
+            && context.top_levels.iter().any(|peer| {
+                peer.handle != top_level.handle
+                    && peer
+                        .address
+                        .is_some_and(|address| top_level.group.contains(&address))
+                    && self.matches_window(peer)
+            })
+    }
+
+    /// Whether the rule applies to an output, given whether the output
+    /// is the one a reference points to.
+    pub fn applies_to_output(&self, is_output: impl Fn(&str) -> bool) -> bool {
+        self.outputs.is_empty() || self.outputs.iter().any(|output| is_output(output))
+    }
+
+    pub fn matches(&self, top_level: &TopLevelInfo, context: &Context) -> bool {
+        self.is_window_rule()
+            && self.matches_context(context)
+            && (self.matches_window(top_level) || self.matches_group(top_level, context))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = shlex::split(s).ok_or_else(|| format!("'{}' has unbalanced quotes", s))?;
+        let pairs = tokens
+            .iter()
+            .map(|token| {
+                token
+                    .split_once('=')
+                    .ok_or_else(|| format!("'{}' doesn't follow the KEY=VALUE format", token))
+            })
+            .collect::<Result<Vec<(&str, &str)>, String>>()?;
+        Rule::from_pairs(pairs)
+    }
+}
+
+impl Rule {
+    /// Builds a rule from its `key=value` pairs, wherever they come
+    /// from.
+    pub fn from_pairs<'a>(
+        pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Rule, String> {
+        let mut rule = Rule::default();
+        let mut saturation = None;
+        let mut matrix: Vec<f64> = Vec::new();
+
+        for (key, value) in pairs {
+            match key {
+                "name" => rule.name = Some(value.to_string()),
+                "priority" => {
+                    rule.priority = value
+                        .parse()
+                        .map_err(|_| format!("'{}' isn't a valid priority", value))?
+                }
+                "title" => rule.titles.push(value.to_string()),
+                "initial_title" => rule.initial_titles.push(value.to_string()),
+                // Also accepted as Hyprland spells it.
+                "initial_class" | "initialClass" => rule.initial_classes.push(value.to_string()),
+                "class" => rule.classes.push(value.to_string()),
+                "process" => rule.processes.push(value.to_string()),
+                "cmdline" => rule.cmdlines.push(value.to_string()),
+                "flatpak" => rule.flatpaks.push(value.to_string()),
+                "cgroup" => rule.cgroups.push(value.to_string()),
+                "tag" => rule.tags.push(value.to_string()),
+                "app_id" => rule.app_ids.push(value.to_string()),
+                "submap" => rule.submaps.push(value.to_string()),
+                "workspace" => rule.workspaces.push(value.to_string()),
+                "output" => rule.outputs.push(value.to_string()),
+                "min_width" => rule.min_width = Some(parse_size(value)?),
+                "min_height" => rule.min_height = Some(parse_size(value)?),
+                "min_coverage" => rule.min_coverage = Some(parse_coverage(value)?),
+                "match" => rule.match_mode = value.parse()?,
+                "group" => rule.match_group = parse_flag(value)?,
+                "floating" => rule.floating = Some(parse_flag(value)?),
+                "xwayland" => rule.xwayland = Some(parse_flag(value)?),
+                "gamescope" => rule.gamescope = Some(parse_flag(value)?),
+                "require_fullscreen" => rule.require_fullscreen = parse_flag(value)?,
+                "require_maximized" => rule.require_maximized = parse_flag(value)?,
+                "all_outputs" => rule.all_outputs = parse_flag(value)?,
+                "visible" => rule.visible = parse_flag(value)?,
+                "sat" => saturation = Some(units::parse_saturation(value)?),
+                "nv" => saturation = Some(units::parse_nv_vibrance(value)?),
+                "boost" => rule.boost = Some(value.parse()?),
+                "other_sat" => rule.other_saturation = Some(units::parse_saturation(value)?),
+                "temperature" => rule.temperature = Some(units::parse_temperature(value)?),
+                "brightness" => rule.brightness = Some(units::parse_brightness(value)?),
+                "effect" => rule.effect = Some(value.parse()?),
+                // Lists in the config file come as one pair per value.
+                "matrix" => matrix.extend(parse_matrix_values(value)?),
+                "contrast" => rule.contrast = Some(units::parse_contrast(value)?),
+                _ => return Err(format!("unknown rule key '{}'", key)),
+            }
+        }
+
+        if !rule.is_window_rule() && rule.submaps.is_empty() && rule.workspaces.is_empty() {
+            return Err(
+                "a rule needs at least one title, initial_title, initial_class, class, process, cmdline, flatpak, cgroup, tag, app_id, xwayland, gamescope, floating, require_fullscreen, require_maximized, geometry condition, submap or workspace"
+                    .to_string(),
+            );
+        }
+
+        if !matrix.is_empty() {
+            if rule.effect.is_some() {
+                return Err("matrix can't be used along with effect".to_string());
+            }
+            let matrix: [f64; 9] = matrix.try_into().map_err(|values: Vec<f64>| {
+                format!("matrix takes 9 values, got {}", values.len())
+            })?;
+            color::check_protocol_limits(&matrix)?;
+            rule.effect = Some(Effect::Matrix(matrix));
+        }
+        if rule.effect.is_some() && (saturation.is_some() || rule.boost.is_some()) {
+            return Err(
+                "effect replaces the saturation, it can't be used along with sat, nv or boost"
+                    .to_string(),
+            );
+        }
+        match (saturation, rule.boost) {
+            (Some(_), Some(_)) => {
+                return Err("boost can't be used along with sat or nv".to_string());
+            }
+            (Some(saturation), None) => rule.saturation = saturation,
+            (None, Some(_)) => {}
+            (None, None)
+                if rule.effect.is_some()
+                    || rule.temperature.is_some()
+                    || rule.brightness.is_some()
+                    || rule.contrast.is_some() => {}
+            (None, None) => {
+                return Err(
+                    "a rule needs a saturation level (sat=..., nv=... or boost=...), an effect, a temperature, a brightness or a contrast"
+                        .to_string(),
+                );
+            }
+        }
+        Ok(rule)
+    }
+}
+
+fn parse_matrix_values(s: &str) -> Result<Vec<f64>, String> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("invalid matrix value '{}'", value))
+        })
+        .collect()
+}
+
+fn parse_flag(s: &str) -> Result<bool, String> {
+    match s {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(format!("'{}' isn't valid, use yes or no", s)),
+    }
+}
+
+fn parse_size(s: &str) -> Result<i32, String> {
+    s.parse()
+        .ok()
+        .filter(|size| *size >= 0)
+        .ok_or_else(|| format!("'{}' isn't a valid size in pixels", s))
+}
+
+/// Accepts either a fraction (`0.8`) or a percentage (`80%`).
+fn parse_coverage(s: &str) -> Result<f64, String> {
+    let coverage = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|percent| percent / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|_| format!("'{}' isn't a valid coverage", s))?;
+
+    if !(0.0..=1.0).contains(&coverage) {
+        return Err(format!("coverage '{}' must be between 0% and 100%", s));
+    }
+    Ok(coverage)
+}
+
+/// Writes the rule back in the same format it's parsed from.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pairs: Vec<(&str, String)> = Vec::new();
+        if let Some(name) = &self.name {
+            pairs.push(("name", name.clone()));
+        }
+        if self.priority != 0 {
+            pairs.push(("priority", self.priority.to_string()));
+        }
+        for (key, values) in [
+            ("title", &self.titles),
+            ("initial_title", &self.initial_titles),
+            ("initial_class", &self.initial_classes),
+            ("class", &self.classes),
+            ("process", &self.processes),
+            ("cmdline", &self.cmdlines),
+            ("flatpak", &self.flatpaks),
+            ("cgroup", &self.cgroups),
+            ("tag", &self.tags),
+            ("app_id", &self.app_ids),
+            ("submap", &self.submaps),
+            ("workspace", &self.workspaces),
+            ("output", &self.outputs),
+        ] {
+            pairs.extend(values.iter().map(|value| (key, value.clone())));
+        }
+        if let Some(min_width) = self.min_width {
+            pairs.push(("min_width", min_width.to_string()));
+        }
+        if let Some(min_height) = self.min_height {
+            pairs.push(("min_height", min_height.to_string()));
+        }
+        if let Some(min_coverage) = self.min_coverage {
+            pairs.push(("min_coverage", units::format_percent(min_coverage)));
+        }
+        if self.match_mode != MatchMode::Exact {
+            pairs.push(("match", self.match_mode.to_string()));
+        }
+        if self.match_group {
+            pairs.push(("group", "yes".to_string()));
+        }
+        for (key, value) in [
+            ("floating", self.floating),
+            ("xwayland", self.xwayland),
+            ("gamescope", self.gamescope),
+        ] {
+            if let Some(value) = value {
+                pairs.push((key, if value { "yes" } else { "no" }.to_string()));
+            }
+        }
+        if self.require_fullscreen {
+            pairs.push(("require_fullscreen", "yes".to_string()));
+        }
+        if self.require_maximized {
+            pairs.push(("require_maximized", "yes".to_string()));
+        }
+        if self.all_outputs {
+            pairs.push(("all_outputs", "yes".to_string()));
+        }
+        if self.visible {
+            pairs.push(("visible", "yes".to_string()));
+        }
+        match (self.effect, self.boost) {
+            (Some(Effect::Matrix(matrix)), _) => {
+                pairs.push(("matrix", Effect::Matrix(matrix).to_string()))
+            }
+            (Some(effect), _) => pairs.push(("effect", effect.to_string())),
+            (None, Some(boost)) => pairs.push(("boost", boost.to_string())),
+            (None, None) => pairs.push(("sat", self.saturation.to_string())),
+        }
+        if let Some(other_saturation) = self.other_saturation {
+            pairs.push(("other_sat", other_saturation.to_string()));
+        }
+        if let Some(temperature) = self.temperature {
+            pairs.push(("temperature", format!("{}K", temperature)));
+        }
+        if let Some(brightness) = self.brightness {
+            pairs.push(("brightness", units::format_percent(brightness)));
+        }
+        if let Some(contrast) = self.contrast {
+            pairs.push(("contrast", units::format_percent(contrast)));
+        }
+
+        let tokens: Vec<String> = pairs
+            .into_iter()
+            .map(|(key, value)| match shlex::try_quote(&value) {
+                Ok(quoted) => format!("{}={}", key, quoted),
+                Err(_) => format!("{}={}", key, value),
+            })
+            .collect();
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+/// Prefix of the Hyprland tags carrying a level.
+pub const TAG_PREFIX: &str = "vibrance:";
+
+/// The rule giving the windows with a `vibrance:LEVEL` tag that level,
+/// if the tag carries one. It comes after every other rule, so it only
+/// applies to windows no rule matches.
+pub fn tag_rule(tag: &str) -> Option<Rule> {
+    let level = tag.strip_prefix(TAG_PREFIX)?;
+    Some(Rule {
+        name: Some(tag.to_string()),
+        from_tag: true,
+        tags: vec![tag.to_string()],
+        saturation: units::parse_saturation(level).ok()?,
+        ..Default::default()
+    })
+}
+
+/// Parses a rules file, returning every rule along with the line it's
+/// defined at.
+pub fn load_file(path: &Path) -> Result<Vec<(Rule, usize)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut rules = Vec::new();
+    let mut managed = false;
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        match line {
+            MANAGED_BEGIN => managed = true,
+            MANAGED_END => managed = false,
+            _ => {}
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut rule: Rule = line
+            .parse()
+            .map_err(|e| format!("{}:{}: {}", path.display(), idx + 1, e))?;
+        rule.managed = managed;
+        rules.push((rule, idx + 1));
+    }
+    Ok(rules)
+}
+
+const MANAGED_BEGIN: &str = "# BEGIN rules managed by hyprland-vibrance";
+const MANAGED_END: &str = "# END rules managed by hyprland-vibrance";
+
+/// Replaces the managed section of the rules file with the managed
+/// rules given, leaving every other line as it was.
+pub fn write_managed(path: &Path, rules: &[Rule]) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines: Vec<&str> = Vec::new();
+    let mut in_managed = false;
+    for line in contents.lines() {
+        match line.trim() {
+            MANAGED_BEGIN => in_managed = true,
+            MANAGED_END => in_managed = false,
+            _ if !in_managed => lines.push(line),
+            _ => {}
+        }
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let mut output: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let managed: Vec<&Rule> = rules.iter().filter(|rule| rule.managed).collect();
+    if !managed.is_empty() {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(MANAGED_BEGIN);
+        output.push('\n');
+        for rule in managed {
+            output.push_str(&format!("{}\n", rule));
+        }
+        output.push_str(MANAGED_END);
+        output.push('\n');
+    }
+
+    // Write and rename so a crash never leaves a truncated file.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, output)?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(spec: &str) -> Rule {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for spec in [
+            "title=a sat=2",
+            "name=games priority=-2 title='Counter-Strike 2' title=Deadlock sat=3.3 other_sat=0.8",
+            "app_id=steam_app_* require_fullscreen=yes require_maximized=yes boost=+0.8",
+            "match=contains class=mpv min_coverage=57% visible=yes boost=x1.3",
+            "initial_class=gamescope min_width=1920 min_height=1080 floating=no xwayland=yes sat=150%",
+            "submap=gaming workspace=3 output=DP-1 temperature=5000K brightness=55% contrast=120%",
+            "process=cs2 cmdline=*eldenring.exe* flatpak=com.valvesoftware.Steam effect=grayscale",
+            "tag=games group=yes all_outputs=yes matrix='1.2 -0.1 -0.1 -0.1 1.2 -0.1 -0.1 -0.1 1.2'",
+        ] {
+            let parsed = rule(spec);
+            let displayed = parsed.to_string();
+            assert_eq!(rule(&displayed), parsed, "{} became {}", spec, displayed);
+        }
+    }
+
+    #[test]
+    fn percentages_are_displayed_rounded() {
+        let displayed = rule("title=a brightness=55% min_coverage=7% sat=2").to_string();
+        assert!(displayed.contains("brightness='55%'"), "{}", displayed);
+        assert!(displayed.contains("min_coverage='7%'"), "{}", displayed);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for spec in [
+            "sat=2",
+            "title=a",
+            "title=a sat=lots",
+            "title=a colour=red sat=2",
+            "title=a sat=2 boost=+1",
+            "title=a effect=invert sat=2",
+            "title=a matrix='1 0 0' ",
+            "title=a match=regex sat=2",
+            "title",
+            "title='a sat=2",
+        ] {
+            assert!(spec.parse::<Rule>().is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn match_modes() {
+        assert!(MatchMode::Exact.matches("mpv", "mpv"));
+        assert!(!MatchMode::Exact.matches("mpv", "mpv2"));
+        assert!(MatchMode::Contains.matches("Steam", "Big Steam window"));
+        assert!(MatchMode::StartsWith.matches("steam_app", "steam_app_42"));
+        assert!(MatchMode::EndsWith.matches(".exe", "game.exe"));
+        assert!(!MatchMode::EndsWith.matches(".exe", "game.exe2"));
+    }
+
+    #[test]
+    fn globs_match_runs_and_single_characters() {
+        assert!(matches_glob("steam_app_*", "steam_app_1091500"));
+        assert!(matches_glob("steam_app_*", "steam_app_"));
+        assert!(matches_glob("*a*b", "xxaxxab"));
+        assert!(matches_glob("game?.exe", "game2.exe"));
+        assert!(!matches_glob("game?.exe", "game.exe"));
+        assert!(!matches_glob("steam_app_*", "steam"));
+        assert!(matches_glob("", ""));
+        assert!(!matches_glob("", "a"));
+    }
+
+    #[test]
+    fn covers_narrower_rules() {
+        assert!(rule("title=a sat=2").covers(&rule("title=a xwayland=yes sat=3")));
+        assert!(!rule("title=a xwayland=yes sat=2").covers(&rule("title=a sat=3")));
+        assert!(rule("match=glob title=a* sat=2").covers(&rule("title=abc sat=3")));
+        assert!(
+            rule("match=contains title=b sat=2").covers(&rule("match=starts_with title=abc sat=3"))
+        );
+        assert!(!rule("match=contains title=b sat=2").covers(&rule("match=glob title=a* sat=3")));
+        assert!(rule("title=a sat=2").covers(&rule("title=a output=DP-1 sat=3")));
+        assert!(!rule("title=a output=DP-1 sat=2").covers(&rule("title=a sat=3")));
+        assert!(!rule("submap=gaming sat=2").covers(&rule("title=a submap=gaming sat=3")));
+        assert!(rule("title=a sat=2").covers(&rule("title=a require_fullscreen=yes sat=3")));
+        assert!(!rule("title=a require_fullscreen=yes sat=2").covers(&rule("title=a sat=3")));
+    }
+
+    #[test]
+    fn same_conditions_ignore_order_and_level() {
+        assert!(rule("title=a title=b sat=2").same_conditions(&rule("title=b title=a sat=3")));
+        assert!(!rule("title=a sat=2").same_conditions(&rule("title=a output=DP-1 sat=2")));
+        assert!(!rule("title=a sat=2").same_conditions(&rule("match=glob title=a sat=2")));
+    }
+
+    #[test]
+    fn matches_windows() {
+        let mut top_level = TopLevelInfo::inert();
+        top_level.title = Some("Counter-Strike 2".to_string());
+        top_level.app_id = Some("steam_app_730".to_string());
+        let context = Context::default();
+
+        assert!(rule("title='Counter-Strike 2' sat=2").matches(&top_level, &context));
+        assert!(rule("app_id=steam_app_* sat=2").matches(&top_level, &context));
+        assert!(!rule("title=Deadlock sat=2").matches(&top_level, &context));
+        assert!(!rule("match=contains title=Strike class=cs2 sat=2").matches(&top_level, &context));
+        assert!(!rule("submap=gaming sat=2").matches(&top_level, &context));
+        assert!(
+            !rule("title='Counter-Strike 2' submap=gaming sat=2").matches(&top_level, &context)
+        );
+    }
+
+    #[test]
+    fn tags_carry_levels() {
+        assert_eq!(
+            tag_rule("vibrance:150%").map(|rule| rule.saturation),
+            Some(1.5)
+        );
+        assert!(tag_rule("vibrance:lots").is_none());
+        assert!(tag_rule("games").is_none());
+    }
+
+    #[test]
+    fn write_managed_keeps_other_lines() {
+        let path =
+            std::env::temp_dir().join(format!("hyprland-vibrance-rules-{}", std::process::id()));
+        fs::write(&path, "# games\ntitle=a sat=2\n").unwrap();
+
+        let mut added = rule("title=b brightness=57% sat=3");
+        added.managed = true;
+        write_managed(&path, &[rule("title=a sat=2"), added.clone()]).unwrap();
+        let loaded = load_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0], (rule("title=a sat=2"), 2));
+        assert_eq!(loaded[1].0, added);
+    }
+}
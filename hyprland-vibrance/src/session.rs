@@ -0,0 +1,163 @@
+//! Selection of the compositor to talk to. Nested Hyprland instances,
+//! or several seats, each have their own Wayland socket and Hyprland
+//! instance signature, and the environment doesn't always point to the
+//! intended one.
+
+use std::{
+    env, fs,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use wayland_client::Connection;
+
+use crate::hyprland;
+
+/// A running Hyprland instance, as described by its lock file.
+#[derive(Debug, Clone)]
+pub struct HyprlandInstance {
+    pub signature: String,
+    pub wayland_display: String,
+}
+
+fn runtime_dir() -> Result<PathBuf, String> {
+    env::var("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .map_err(|_| "XDG_RUNTIME_DIR is not set".to_string())
+}
+
+/// Hyprland writes its pid and the name of its Wayland socket in
+/// `hyprland.lock`, inside the directory of its IPC sockets.
+fn read_instance(dir: &Path) -> Option<HyprlandInstance> {
+    let lock = fs::read_to_string(dir.join("hyprland.lock")).ok()?;
+    let mut lines = lock.lines();
+    let pid = lines.next()?.trim();
+    let wayland_display = lines.next()?.trim().to_string();
+
+    // Crashed instances leave their directory behind.
+    if !Path::new("/proc").join(pid).exists() {
+        return None;
+    }
+
+    Some(HyprlandInstance {
+        signature: dir.file_name()?.to_string_lossy().into_owned(),
+        wayland_display,
+    })
+}
+
+pub fn hyprland_instances() -> Vec<HyprlandInstance> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    if let Ok(runtime_dir) = runtime_dir() {
+        dirs.push(runtime_dir.join("hypr"));
+    }
+    dirs.push(PathBuf::from("/tmp/hypr"));
+
+    let mut instances: Vec<HyprlandInstance> = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if let Some(instance) = read_instance(&entry.path())
+                && !instances.iter().any(|i| i.signature == instance.signature)
+            {
+                instances.push(instance);
+            }
+        }
+    }
+    instances
+}
+
+fn available_displays() -> Vec<String> {
+    let Ok(entries) = runtime_dir().and_then(|dir| fs::read_dir(dir).map_err(|e| e.to_string()))
+    else {
+        return Vec::new();
+    };
+
+    let mut displays: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("wayland-") && !name.ends_with(".lock"))
+        .collect();
+    displays.sort();
+    displays
+}
+
+fn describe_available() -> String {
+    let displays = available_displays();
+    if displays.is_empty() {
+        "no Wayland sockets found".to_string()
+    } else {
+        format!("available sockets: {}", displays.join(", "))
+    }
+}
+
+/// Connects to the given Wayland display, a socket name relative to
+/// `$XDG_RUNTIME_DIR` or an absolute path, or to the one in the
+/// environment. Hyprland IPC requests are sent to the instance owning
+/// that display.
+pub fn connect(display: Option<&str>) -> Result<Connection, String> {
+    let instances = hyprland_instances();
+    let display_name = display
+        .map(str::to_string)
+        .or_else(|| env::var("WAYLAND_DISPLAY").ok());
+
+    if let Some(name) = display_name.as_deref()
+        && let Some(instance) = instances.iter().find(|i| {
+            i.wayland_display == name
+                || Path::new(name)
+                    .file_name()
+                    .is_some_and(|f| *f == *i.wayland_display)
+        })
+    {
+        if env::var("HYPRLAND_INSTANCE_SIGNATURE").ok().as_deref() != Some(&instance.signature) {
+            info!(
+                "Using Hyprland instance {}, which owns {}",
+                instance.signature, name
+            );
+        }
+        hyprland::set_instance_signature(instance.signature.clone());
+    }
+
+    if display.is_none() && instances.len() > 1 {
+        warn!(
+            "{} Hyprland sessions are running ({}), using {}. Choose another one with --wayland-display",
+            instances.len(),
+            instances
+                .iter()
+                .map(|i| i.wayland_display.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            display_name.as_deref().unwrap_or("the default display")
+        );
+    }
+
+    let Some(display) = display else {
+        return Connection::connect_to_env().map_err(|e| {
+            format!(
+                "Couldn't connect to the Wayland display ({}), {}",
+                e,
+                describe_available()
+            )
+        });
+    };
+
+    let path = if Path::new(display).is_absolute() {
+        PathBuf::from(display)
+    } else {
+        runtime_dir()?.join(display)
+    };
+    if !path.exists() {
+        return Err(format!(
+            "There's no Wayland socket at {}, {}",
+            path.display(),
+            describe_available()
+        ));
+    }
+
+    let stream = UnixStream::connect(&path)
+        .map_err(|e| format!("Couldn't connect to {}: {}", path.display(), e))?;
+    Connection::from_socket(stream)
+        .map_err(|e| format!("Couldn't connect to {}: {}", path.display(), e))
+}
@@ -0,0 +1,109 @@
+//! Global shortcuts registered through the Hyprland global shortcuts
+//! protocol, so they can be bound in `hyprland.conf` without any script:
+//!
+//! ```text
+//! bind = SUPER, F10, global, hyprland-vibrance:toggle
+//! bind = SUPER, F11, global, hyprland-vibrance:cycle
+//! ```
+//!
+//! `toggle` pauses and resumes the daemon, like SIGUSR1, and `cycle`
+//! moves to the next level given with `--cycle-sat`, like SIGUSR2.
+
+use log::{info, warn};
+use wayland_client::{Connection, Dispatch, QueueHandle};
+use wayland_protocols_hyprland::global_shortcuts::v1::client::{
+    hyprland_global_shortcut_v1::{self, HyprlandGlobalShortcutV1},
+    hyprland_global_shortcuts_manager_v1::{self, HyprlandGlobalShortcutsManagerV1},
+};
+
+use crate::AppState;
+
+pub const GLOBAL_SHORTCUTS_MANAGER_IFACE: &str = "hyprland_global_shortcuts_manager_v1";
+pub const GLOBAL_SHORTCUTS_MANAGER_VERSION: u32 = 1;
+
+/// App id the shortcuts are registered with, the part before the colon
+/// in `hyprland.conf`.
+const APP_ID: &str = "hyprland-vibrance";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shortcut {
+    Toggle,
+    Cycle,
+}
+
+impl Shortcut {
+    const ALL: [Shortcut; 2] = [Shortcut::Toggle, Shortcut::Cycle];
+
+    fn id(self) -> &'static str {
+        match self {
+            Shortcut::Toggle => "toggle",
+            Shortcut::Cycle => "cycle",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Shortcut::Toggle => "Pause or resume vibrance",
+            Shortcut::Cycle => "Switch to the next vibrance level",
+        }
+    }
+}
+
+/// Registers every shortcut. The shortcuts live as long as the
+/// connection.
+pub fn register(manager: &HyprlandGlobalShortcutsManagerV1, qh: &QueueHandle<AppState>) {
+    for shortcut in Shortcut::ALL {
+        manager.register_shortcut(
+            shortcut.id().to_string(),
+            APP_ID.to_string(),
+            shortcut.description().to_string(),
+            String::new(),
+            qh,
+            shortcut,
+        );
+    }
+    info!(
+        "Registered the global shortcuts {}",
+        Shortcut::ALL
+            .map(|shortcut| format!("{}:{}", APP_ID, shortcut.id()))
+            .join(", ")
+    );
+}
+
+impl Dispatch<HyprlandGlobalShortcutsManagerV1, ()> for AppState {
+    fn event(
+        _: &mut Self,
+        _: &HyprlandGlobalShortcutsManagerV1,
+        _: hyprland_global_shortcuts_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+    }
+}
+
+impl Dispatch<HyprlandGlobalShortcutV1, Shortcut> for AppState {
+    fn event(
+        state: &mut Self,
+        _: &HyprlandGlobalShortcutV1,
+        event: hyprland_global_shortcut_v1::Event,
+        shortcut: &Shortcut,
+        _: &Connection,
+        _: &QueueHandle<AppState>,
+    ) {
+        if !matches!(event, hyprland_global_shortcut_v1::Event::Pressed { .. }) {
+            return;
+        }
+
+        match shortcut {
+            Shortcut::Toggle => state.set_paused(!state.paused),
+            Shortcut::Cycle => {
+                if !state.cycle_saturation() {
+                    warn!(
+                        "The cycle shortcut was pressed, but there are no levels to cycle through (see --cycle-sat)"
+                    );
+                }
+            }
+        }
+    }
+}
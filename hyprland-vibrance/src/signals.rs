@@ -0,0 +1,74 @@
+//! Signals handled by the daemon. They're blocked and read from a
+//! signalfd instead of being handled asynchronously, so they're
+//! dispatched by the event loop like every other source.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::fd::{FromRawFd, OwnedFd},
+};
+
+use calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic};
+use log::error;
+
+use crate::AppState;
+
+/// Blocks the given signals and calls `callback` with every one of them
+/// received.
+pub fn listen<'l>(
+    handle: &LoopHandle<'l, AppState>,
+    signals: &[libc::c_int],
+    mut callback: impl FnMut(&mut AppState, libc::c_int) + 'l,
+) -> io::Result<()> {
+    // SAFETY: the set is initialized by sigemptyset before being used,
+    // and every pointer given refers to it.
+    let fd = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in signals {
+            libc::sigaddset(&mut set, *signal);
+        }
+        // Children reset their signal mask, so blocking them here
+        // doesn't leak to the processes spawned.
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and isn't owned elsewhere.
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    handle
+        .insert_source(
+            Generic::new(file, Interest::READ, Mode::Level),
+            move |_, file, state| {
+                let mut file: &File = file;
+                let mut info = [0u8; mem::size_of::<libc::signalfd_siginfo>()];
+                loop {
+                    match file.read(&mut info) {
+                        Ok(read) if read == info.len() => {
+                            // SAFETY: the kernel wrote a whole
+                            // signalfd_siginfo, which is plain data.
+                            let info: libc::signalfd_siginfo =
+                                unsafe { std::ptr::read_unaligned(info.as_ptr().cast()) };
+                            callback(state, info.ssi_signo as libc::c_int);
+                        }
+                        Ok(_) => break,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            error!("Stopped reading signals: {}", e);
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| e.error)?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//! Integration with systemd for `Type=notify` services: readiness is
+//! reported once the CTM manager and the top level manager are bound,
+//! and the watchdog is pinged from the event loop when `WatchdogSec` is
+//! set, so a wedged daemon gets restarted. Without `NOTIFY_SOCKET`,
+//! i.e. when not run by systemd, none of this does anything.
+
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+use calloop::{LoopHandle, timer::TimeoutAction, timer::Timer};
+use log::{debug, info};
+
+use crate::AppState;
+
+/// Sends a state update, e.g. `READY=1`, to the service manager.
+pub fn notify(message: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    // Paths starting with @ refer to the abstract namespace.
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let result =
+        addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(message.as_bytes(), &addr));
+    if let Err(e) = result {
+        debug!("Couldn't notify systemd of {}: {}", message, e);
+    }
+}
+
+/// Watchdog timeout asked for by the service manager, if any.
+fn watchdog_timeout() -> Option<Duration> {
+    // The watchdog may be meant for another process of the unit.
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Sleeps outside of the event loop, still pinging the watchdog at half
+/// its timeout meanwhile.
+pub fn sleep(duration: Duration) {
+    let Some(timeout) = watchdog_timeout() else {
+        std::thread::sleep(duration);
+        return;
+    };
+
+    let mut left = duration;
+    while !left.is_zero() {
+        notify("WATCHDOG=1");
+        let step = left.min(timeout / 2);
+        std::thread::sleep(step);
+        left -= step;
+    }
+    notify("WATCHDOG=1");
+}
+
+/// Pings the watchdog from the event loop at half its timeout, as
+/// recommended by sd_watchdog_enabled(3).
+pub fn watch(handle: &LoopHandle<AppState>) {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    info!("Pinging the systemd watchdog every {:?}", timeout / 2);
+
+    handle
+        .insert_source(Timer::immediate(), move |_, _, _| {
+            notify("WATCHDOG=1");
+            TimeoutAction::ToDuration(timeout / 2)
+        })
+        .unwrap();
+}
@@ -0,0 +1,225 @@
+//! Animated transitions between CTMs. Instead of jumping to the new
+//! matrix, every changed output is interpolated from whatever it shows
+//! to its target over `--transition` milliseconds, one frame at a time
+//! from a timer in the event loop. A change arriving mid-transition
+//! starts from the matrix being shown, so colors never jump.
+//!
+//! Hyprland can animate CTM changes itself (`render:ctm_animation`),
+//! which some setups can't keep up with. These frames are plain CTM
+//! updates, so they're cheap for the compositor.
+
+use std::time::{Duration, Instant};
+
+use calloop::{
+    LoopHandle, RegistrationToken,
+    timer::{TimeoutAction, Timer},
+};
+use log::error;
+use wayland_client::{Proxy, protocol::wl_output::WlOutput};
+use wayland_protocols_hyprland::ctm_control::v1::client::hyprland_ctm_control_manager_v1::HyprlandCtmControlManagerV1;
+
+use crate::{
+    AppState,
+    color::{self, IDENTITY_MATRIX},
+};
+
+/// Time between frames, about 60 per second.
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+/// How the progress of a transition is paced.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    /// Slow at both ends.
+    #[default]
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps the elapsed fraction of the duration onto the fraction of
+    /// the way to the target, both from 0.0 to 1.0.
+    fn apply(self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut if t < 0.5 => 4.0 * t * t * t,
+            Easing::EaseInOut => 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0,
+        }
+    }
+}
+
+fn interpolate(from: &[f64; 9], to: &[f64; 9], progress: f64) -> [f64; 9] {
+    std::array::from_fn(|i| from[i] + (to[i] - from[i]) * progress)
+}
+
+#[derive(Debug)]
+struct Transition {
+    output: WlOutput,
+    from: [f64; 9],
+    to: [f64; 9],
+    started: Instant,
+}
+
+#[derive(Debug)]
+pub struct Transitions {
+    duration: Duration,
+    easing: Easing,
+    /// Matrix last sent to every output not showing the identity.
+    shown: Vec<(WlOutput, [f64; 9])>,
+    running: Vec<Transition>,
+    timer: Option<RegistrationToken>,
+}
+
+impl Transitions {
+    pub fn new(duration: Duration, easing: Easing) -> Transitions {
+        Transitions {
+            duration,
+            easing,
+            shown: Vec::new(),
+            running: Vec::new(),
+            timer: None,
+        }
+    }
+
+    fn shown(&self, output: &WlOutput) -> [f64; 9] {
+        self.shown
+            .iter()
+            .find(|(handle, _)| handle == output)
+            .map_or(IDENTITY_MATRIX, |(_, matrix)| *matrix)
+    }
+
+    /// Starts moving the output towards the given matrix, from the one
+    /// it currently shows.
+    pub fn retarget(
+        &mut self,
+        handle: &LoopHandle<'static, AppState>,
+        output: &WlOutput,
+        to: [f64; 9],
+    ) {
+        let from = self.shown(output);
+        self.running
+            .retain(|transition| &transition.output != output);
+        self.running.push(Transition {
+            output: output.clone(),
+            from,
+            to,
+            started: Instant::now(),
+        });
+
+        if self.timer.is_none() {
+            self.timer = handle
+                .insert_source(Timer::immediate(), |_, _, state| {
+                    let Some(control) = state.ctm_manager.clone() else {
+                        return TimeoutAction::Drop;
+                    };
+                    let Some(transitions) = state.transitions.as_mut() else {
+                        return TimeoutAction::Drop;
+                    };
+                    if transitions.step(&control) {
+                        TimeoutAction::ToDuration(FRAME_INTERVAL)
+                    } else {
+                        transitions.timer = None;
+                        TimeoutAction::Drop
+                    }
+                })
+                .ok();
+        }
+    }
+
+    /// Sends the next frame of every running transition, telling
+    /// whether any of them is still running.
+    fn step(&mut self, control: &HyprlandCtmControlManagerV1) -> bool {
+        let now = Instant::now();
+        for transition in self.running.iter() {
+            let elapsed = now.duration_since(transition.started).as_secs_f64();
+            let t = if self.duration.is_zero() {
+                1.0
+            } else {
+                elapsed / self.duration.as_secs_f64()
+            };
+            let matrix = if t >= 1.0 {
+                transition.to
+            } else {
+                interpolate(&transition.from, &transition.to, self.easing.apply(t))
+            };
+            if !crate::set_ctm_matrix_for_display(control, &transition.output, &matrix) {
+                continue;
+            }
+
+            self.shown
+                .retain(|(handle, _)| handle != &transition.output);
+            if matrix != IDENTITY_MATRIX {
+                self.shown.push((transition.output.clone(), matrix));
+            }
+        }
+        if !self.running.is_empty() {
+            control.commit();
+        }
+
+        self.running
+            .retain(|transition| now.duration_since(transition.started) < self.duration);
+        !self.running.is_empty()
+    }
+
+    /// Stops every transition, clearing the outputs they left with a
+    /// matrix on.
+    pub fn clear(&mut self, control: &HyprlandCtmControlManagerV1) {
+        self.running.clear();
+        for (output, _) in self.shown.drain(..) {
+            crate::clear_ctm_matrix_for_display(control, &output);
+        }
+        control.commit();
+    }
+
+    /// Drops everything about an output that's gone.
+    pub fn forget_output(&mut self, output: &WlOutput) {
+        self.running
+            .retain(|transition| &transition.output != output);
+        self.shown.retain(|(handle, _)| handle != output);
+    }
+
+    /// Records a matrix set on the output without a transition.
+    pub fn set_shown(&mut self, output: &WlOutput, matrix: [f64; 9]) {
+        self.forget_output(output);
+        self.shown.push((output.clone(), matrix));
+    }
+}
+
+/// Same as [`crate::reconcile_ctms`], animating the outputs that
+/// change instead of setting their CTM right away.
+pub fn reconcile(
+    state: &mut AppState,
+    handle: &LoopHandle<'static, AppState>,
+    desired: Vec<(WlOutput, color::Ctm)>,
+) -> Vec<(WlOutput, f64)> {
+    let changed = crate::ctm_changes(&state.applied_ctms, &desired);
+    let Some(transitions) = state.transitions.as_mut() else {
+        return Vec::new();
+    };
+    let mut skipped = Vec::new();
+    for (output, ctm) in changed.iter() {
+        let to = match ctm {
+            Some(ctm) => ctm.matrix(&state.matrix_options),
+            None => IDENTITY_MATRIX,
+        };
+        if let Err(e) = color::check_protocol_limits(&to) {
+            error!("Not applying matrix {:?} to {}: {}", to, output.id(), e);
+            skipped.push(output.clone());
+            continue;
+        }
+        transitions.retarget(handle, output, to);
+    }
+
+    if !changed.is_empty() {
+        crate::record_applied(&mut state.applied_ctms, desired, &skipped);
+    }
+    changed
+        .into_iter()
+        .filter(|(output, _)| !skipped.contains(output))
+        .map(|(output, ctm)| (output, ctm.map_or(1.0, |ctm| ctm.saturation)))
+        .collect()
+}
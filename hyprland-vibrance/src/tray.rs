@@ -0,0 +1,279 @@
+//! StatusNotifierItem tray icon, for those who'd rather use the mouse.
+//! Clicking it pauses and resumes the daemon, and its menu switches
+//! between the rules and the levels given with `--cycle-sat`, and
+//! enables and disables the named rules. Menu entries are forwarded to
+//! the event loop as control requests, like the D-Bus methods.
+
+use calloop::{LoopHandle, channel};
+use ksni::{
+    MenuItem, ToolTip,
+    blocking::{Handle, TrayMethods},
+    menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu},
+};
+use log::{info, warn};
+
+use crate::{
+    AppState,
+    control::{self, Request},
+    units,
+};
+
+const ID: &str = "hyprland-vibrance";
+const ICON_ACTIVE: &str = "preferences-desktop-display";
+const ICON_INACTIVE: &str = "video-display";
+
+/// What the icon and its menu show, as of the last event loop
+/// iteration.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Snapshot {
+    paused: bool,
+    disabled_by_file: bool,
+    /// Highest level applied to an output, if any.
+    level: Option<f64>,
+    saturation_override: Option<f64>,
+    /// Named rules, along with whether they're enabled.
+    rules: Vec<(String, bool)>,
+}
+
+impl Snapshot {
+    fn from_state(state: &AppState) -> Snapshot {
+        Snapshot {
+            paused: state.paused,
+            disabled_by_file: state.disabled_by_file,
+            level: state
+                .applied_ctms
+                .iter()
+                .map(|(_, ctm)| ctm.saturation)
+                .max_by(f64::total_cmp),
+            saturation_override: state.saturation_override,
+            rules: state
+                .rules
+                .iter()
+                .filter_map(|rule| Some((rule.name.clone()?, rule.enabled)))
+                .collect(),
+        }
+    }
+}
+
+fn format_level(saturation: f64) -> String {
+    format!("{:.0}%", units::saturation_to_percent(saturation))
+}
+
+struct Indicator {
+    requests: channel::Sender<Request>,
+    /// Levels offered besides the rules.
+    profiles: Vec<f64>,
+    snapshot: Snapshot,
+}
+
+impl Indicator {
+    fn send(&self, request: Request) {
+        if self.requests.send(request).is_err() {
+            warn!("The daemon is exiting, ignoring the tray request");
+        }
+    }
+
+    fn description(&self) -> String {
+        let snapshot = &self.snapshot;
+        if snapshot.disabled_by_file {
+            "Disabled by file".to_string()
+        } else if snapshot.paused {
+            "Paused".to_string()
+        } else if let Some(level) = snapshot.level {
+            format!("Saturation {}", format_level(level))
+        } else {
+            "No saturation applied".to_string()
+        }
+    }
+}
+
+impl ksni::Tray for Indicator {
+    fn id(&self) -> String {
+        ID.to_string()
+    }
+
+    fn title(&self) -> String {
+        "Vibrance".to_string()
+    }
+
+    fn icon_name(&self) -> String {
+        if self.snapshot.level.is_some() {
+            ICON_ACTIVE
+        } else {
+            ICON_INACTIVE
+        }
+        .to_string()
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        ToolTip {
+            title: "Vibrance".to_string(),
+            description: self.description(),
+            ..Default::default()
+        }
+    }
+
+    fn activate(&mut self, _x: i32, _y: i32) {
+        self.send(Request::Toggle);
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        let mut options = vec![RadioItem {
+            label: "Rules".to_string(),
+            ..Default::default()
+        }];
+        options.extend(self.profiles.iter().map(|saturation| RadioItem {
+            label: format_level(*saturation),
+            ..Default::default()
+        }));
+        let selected = self
+            .snapshot
+            .saturation_override
+            .and_then(|saturation| self.profiles.iter().position(|p| *p == saturation))
+            .map_or(0, |profile| profile + 1);
+
+        let mut menu: Vec<MenuItem<Self>> = vec![
+            CheckmarkItem {
+                label: "Paused".to_string(),
+                checked: self.snapshot.paused,
+                activate: Box::new(|tray: &mut Self| {
+                    tray.send(if tray.snapshot.paused {
+                        Request::Resume
+                    } else {
+                        Request::Pause
+                    })
+                }),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            RadioGroup {
+                selected,
+                select: Box::new(|tray: &mut Self, selected| {
+                    tray.send(match selected.checked_sub(1) {
+                        Some(profile) => Request::Set {
+                            saturation: tray.profiles[profile],
+                        },
+                        None => Request::Reset,
+                    })
+                }),
+                options,
+            }
+            .into(),
+        ];
+
+        if !self.snapshot.rules.is_empty() {
+            menu.push(MenuItem::Separator);
+            menu.push(
+                SubMenu {
+                    label: "Rules".to_string(),
+                    submenu: self
+                        .snapshot
+                        .rules
+                        .iter()
+                        .map(|(name, enabled)| {
+                            let name = name.clone();
+                            CheckmarkItem {
+                                label: name.clone(),
+                                checked: *enabled,
+                                activate: Box::new(move |tray: &mut Self| {
+                                    let enabled = tray
+                                        .snapshot
+                                        .rules
+                                        .iter()
+                                        .any(|(rule, enabled)| *rule == name && *enabled);
+                                    tray.send(if enabled {
+                                        Request::DisableRule { name: name.clone() }
+                                    } else {
+                                        Request::EnableRule { name: name.clone() }
+                                    })
+                                }),
+                                ..Default::default()
+                            }
+                            .into()
+                        })
+                        .collect(),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+
+        menu.push(MenuItem::Separator);
+        menu.push(
+            StandardItem {
+                label: "Reload rules".to_string(),
+                activate: Box::new(|tray: &mut Self| tray.send(Request::Reload)),
+                ..Default::default()
+            }
+            .into(),
+        );
+        menu
+    }
+}
+
+pub struct Tray {
+    handle: Handle<Indicator>,
+    last: Snapshot,
+}
+
+/// Takes the icon down with the daemon state, so starting over after
+/// losing the compositor doesn't show a second one.
+impl Drop for Tray {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+    }
+}
+
+impl std::fmt::Debug for Tray {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tray").field("last", &self.last).finish()
+    }
+}
+
+/// Shows the tray icon, handling its menu entries in the event loop.
+/// `profiles` are the levels offered besides the rules.
+pub fn start(handle: &LoopHandle<'_, AppState>, profiles: Vec<f64>) -> Result<Tray, String> {
+    let (requests, receiver) = channel::channel::<Request>();
+    let client = control::new_client_id();
+    handle
+        .insert_source(receiver, move |event, _, state| {
+            if let channel::Event::Msg(request) = event {
+                let response = control::handle_request(state, client, request);
+                if let Some(error) = response.error {
+                    warn!("Tray request failed: {}", error);
+                }
+            }
+        })
+        .map_err(|e| e.error.to_string())?;
+
+    let indicator = Indicator {
+        requests,
+        profiles,
+        snapshot: Snapshot::default(),
+    };
+    let handle = indicator.spawn().map_err(|e| e.to_string())?;
+    info!("Showing the tray icon");
+    Ok(Tray {
+        handle,
+        last: Snapshot::default(),
+    })
+}
+
+/// Refreshes the icon and its menu if anything they show changed.
+pub fn publish(state: &mut AppState) {
+    let Some(tray) = state.tray.as_ref() else {
+        return;
+    };
+    let snapshot = Snapshot::from_state(state);
+    if snapshot == tray.last {
+        return;
+    }
+
+    let shown = snapshot.clone();
+    tray.handle
+        .update(move |indicator| indicator.snapshot = shown);
+    if let Some(tray) = state.tray.as_mut() {
+        tray.last = snapshot;
+    }
+}
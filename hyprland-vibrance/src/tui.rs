@@ -0,0 +1,426 @@
+//! Terminal views of the running daemon. Both are just control socket
+//! clients: the rule manager polls the rules and sends a request for
+//! every change made, while `top` subscribes to the status of the
+//! daemon and redraws on every change.
+
+use std::{
+    io::{self, Write},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+
+use crate::{
+    control::{self, Request, RuleStatus, Status},
+    units,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+const LEVEL_STEP: f64 = 0.1;
+
+const HELP: &str = "↑/↓ select  space toggle  ←/→ level  K/J move  q quit";
+
+/// Restores the terminal when dropped, so it's left usable even if
+/// drawing fails halfway.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> io::Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        queue!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        stdout.flush()?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = queue!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = stdout.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Default)]
+struct Manager {
+    rules: Vec<RuleStatus>,
+    selected: usize,
+    status: String,
+}
+
+impl Manager {
+    fn send(&mut self, request: Request) {
+        match control::send(&request) {
+            Ok(response) if response.ok => {
+                self.status = response.warnings.join("; ");
+                if let Some(rules) = response.rules {
+                    self.rules = rules;
+                    self.selected = self.selected.min(self.rules.len().saturating_sub(1));
+                }
+            }
+            Ok(response) => self.status = response.error.unwrap_or_default(),
+            Err(e) => self.status = format!("Couldn't talk to the running daemon: {}", e),
+        }
+    }
+
+    fn refresh(&mut self) {
+        let status = std::mem::take(&mut self.status);
+        self.send(Request::ListRules);
+        // Keep showing the outcome of the last action.
+        if self.status.is_empty() {
+            self.status = status;
+        }
+    }
+
+    fn toggle_selected(&mut self) {
+        let Some(rule) = self.rules.get(self.selected) else {
+            return;
+        };
+
+        let request = match (rule.name.clone(), rule.enabled) {
+            (Some(name), true) => Request::DisableRule { name },
+            (Some(name), false) => Request::EnableRule { name },
+            (None, _) => {
+                self.status = "Only rules with a name can be disabled".to_string();
+                return;
+            }
+        };
+        self.send(request);
+    }
+
+    fn adjust_selected(&mut self, delta: f64) {
+        let Some(rule) = self.rules.get(self.selected) else {
+            return;
+        };
+
+        let saturation = ((rule.saturation + delta) * 100.0).round() / 100.0;
+        self.send(Request::SetRuleSaturation {
+            index: self.selected,
+            saturation: saturation.clamp(
+                *units::saturation_range().start(),
+                *units::saturation_range().end(),
+            ),
+        });
+    }
+
+    fn move_selected(&mut self, up: bool) {
+        let to = if up {
+            self.selected.checked_sub(1)
+        } else {
+            Some(self.selected + 1).filter(|to| *to < self.rules.len())
+        };
+
+        if let Some(to) = to {
+            self.send(Request::MoveRule {
+                index: self.selected,
+                to,
+            });
+            self.selected = to;
+        }
+    }
+
+    /// Returns false once the user asks to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up if shift => self.move_selected(true),
+            KeyCode::Down if shift => self.move_selected(false),
+            KeyCode::Char('K') => self.move_selected(true),
+            KeyCode::Char('J') => self.move_selected(false),
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.rules.len().saturating_sub(1))
+            }
+            KeyCode::Char(' ') | KeyCode::Enter => self.toggle_selected(),
+            KeyCode::Right | KeyCode::Char('+') | KeyCode::Char('l') => {
+                self.adjust_selected(LEVEL_STEP)
+            }
+            KeyCode::Left | KeyCode::Char('-') | KeyCode::Char('h') => {
+                self.adjust_selected(-LEVEL_STEP)
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+        let fit = |line: String| -> String { line.chars().take(width).collect() };
+
+        queue!(
+            out,
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0),
+            SetAttribute(Attribute::Bold),
+            Print(fit(format!(
+                "hyprland-vibrance rules ({} total, ▶ focused, ● open)",
+                self.rules.len()
+            ))),
+            SetAttribute(Attribute::Reset),
+        )?;
+
+        let rows = (height as usize).saturating_sub(4);
+        let first = self.selected.saturating_sub(rows.saturating_sub(1));
+        for (row, (idx, rule)) in self
+            .rules
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .enumerate()
+        {
+            let indicator = if rule.focused {
+                '▶'
+            } else if rule.open {
+                '●'
+            } else {
+                ' '
+            };
+            let line = format!(
+                "{} [{}] #{:<3} {:>5.2}  {}",
+                indicator,
+                if rule.enabled { 'x' } else { ' ' },
+                idx,
+                rule.saturation,
+                rule.rule
+            );
+
+            queue!(out, cursor::MoveTo(0, row as u16 + 2))?;
+            if idx == self.selected {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(out, Print(fit(line)), SetAttribute(Attribute::Reset))?;
+        }
+
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(2)),
+            Print(fit(self.status.clone())),
+            cursor::MoveTo(0, height.saturating_sub(1)),
+            SetAttribute(Attribute::Dim),
+            Print(fit(HELP.to_string())),
+            SetAttribute(Attribute::Reset),
+        )?;
+        out.flush()
+    }
+}
+
+pub fn run() -> io::Result<()> {
+    // Fail before taking over the terminal if there's no daemon.
+    control::send(&Request::ListRules)?;
+
+    let _guard = TerminalGuard::enter()?;
+    let mut stdout = io::stdout();
+    let mut manager = Manager::default();
+    manager.refresh();
+
+    loop {
+        manager.draw(&mut stdout)?;
+        if event::poll(REFRESH_INTERVAL)? {
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    if !manager.handle_key(key) {
+                        return Ok(());
+                    }
+                }
+                _ => continue,
+            }
+        }
+        manager.refresh();
+    }
+}
+
+/// How often `top` checks for key presses.
+const TOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn format_matrix(matrix: &Option<[f64; 9]>) -> String {
+    match matrix {
+        Some(matrix) => matrix
+            .chunks(3)
+            .map(|row| {
+                row.iter()
+                    .map(|value| format!("{:6.3}", value))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join(" │ "),
+        None => "identity".to_string(),
+    }
+}
+
+fn draw_top(
+    out: &mut impl Write,
+    status: &Option<Status>,
+    error: &Option<String>,
+) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let width = width as usize;
+    let fit = |line: String| -> String { line.chars().take(width).collect() };
+
+    let mut lines: Vec<(String, Attribute)> = Vec::new();
+    match status {
+        Some(status) => {
+            let mut header = "hyprland-vibrance top".to_string();
+            if status.paused {
+                header.push_str(" (paused)");
+            }
+            if status.disabled_by_file {
+                header.push_str(" (disabled by file)");
+            }
+            lines.push((header, Attribute::Bold));
+            lines.push((String::new(), Attribute::Reset));
+
+            lines.push((
+                format!(
+                    "{:<16} {:>5}  {:<24} {}",
+                    "OUTPUT", "SAT", "SOURCE", "MATRIX"
+                ),
+                Attribute::Underlined,
+            ));
+            for output in status.outputs.iter() {
+                lines.push((
+                    format!(
+                        "{:<16} {:>5.2}  {:<24} {}",
+                        output.name,
+                        output.saturation,
+                        output.source.as_deref().unwrap_or("-"),
+                        format_matrix(&output.matrix)
+                    ),
+                    Attribute::Reset,
+                ));
+            }
+            lines.push((String::new(), Attribute::Reset));
+
+            lines.push((
+                format!(
+                    "  {:<40} {:<24} {:<8} {}",
+                    "TITLE", "APP ID", "STATE", "OUTPUTS"
+                ),
+                Attribute::Underlined,
+            ));
+            for top_level in status.top_levels.iter() {
+                let state = if top_level.minimized {
+                    "min".to_string()
+                } else if top_level.fullscreen {
+                    "full".to_string()
+                } else if top_level.maximized {
+                    "max".to_string()
+                } else if let Some(tab) = &top_level.group_tab {
+                    format!("tab {}", tab)
+                } else {
+                    "-".to_string()
+                };
+                let title: String = top_level
+                    .title
+                    .as_deref()
+                    .unwrap_or("")
+                    .chars()
+                    .take(40)
+                    .collect();
+                lines.push((
+                    format!(
+                        "{} {:<40} {:<24} {:<8} {}",
+                        if top_level.focused { '▶' } else { ' ' },
+                        title,
+                        top_level.app_id.as_deref().unwrap_or("-"),
+                        state,
+                        top_level.outputs.join(", ")
+                    ),
+                    if top_level.focused {
+                        Attribute::Bold
+                    } else {
+                        Attribute::Reset
+                    },
+                ));
+            }
+        }
+        None => lines.push(("Waiting for the daemon…".to_string(), Attribute::Reset)),
+    }
+
+    queue!(out, terminal::Clear(ClearType::All))?;
+    let rows = (height as usize).saturating_sub(2);
+    for (row, (line, attribute)) in lines.into_iter().take(rows).enumerate() {
+        queue!(
+            out,
+            cursor::MoveTo(0, row as u16),
+            SetAttribute(attribute),
+            Print(fit(line)),
+            SetAttribute(Attribute::Reset),
+        )?;
+    }
+
+    queue!(
+        out,
+        cursor::MoveTo(0, height.saturating_sub(2)),
+        Print(fit(error.clone().unwrap_or_default())),
+        cursor::MoveTo(0, height.saturating_sub(1)),
+        SetAttribute(Attribute::Dim),
+        Print(fit("q quit".to_string())),
+        SetAttribute(Attribute::Reset),
+    )?;
+    out.flush()
+}
+
+pub fn run_top() -> io::Result<()> {
+    // Fail before taking over the terminal if there's no daemon.
+    control::send(&Request::Status)?;
+
+    let (sender, receiver) = mpsc::channel::<Result<Status, String>>();
+    thread::spawn(move || {
+        let result = control::subscribe(|status| sender.send(Ok(status)).is_ok());
+        let message = match result {
+            Ok(()) => "The daemon closed the connection".to_string(),
+            Err(e) => format!("Lost connection to the daemon: {}", e),
+        };
+        let _ = sender.send(Err(message));
+    });
+
+    let _guard = TerminalGuard::enter()?;
+    let mut stdout = io::stdout();
+    let mut status: Option<Status> = None;
+    let mut error: Option<String> = None;
+    let mut dirty = true;
+
+    loop {
+        for update in receiver.try_iter() {
+            match update {
+                Ok(new_status) => status = Some(new_status),
+                Err(message) => error = Some(message),
+            }
+            dirty = true;
+        }
+
+        if dirty {
+            draw_top(&mut stdout, &status, &error)?;
+            dirty = false;
+        }
+
+        if event::poll(TOP_POLL_INTERVAL)? {
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        return Ok(());
+                    }
+                    _ => {}
+                },
+                Event::Resize(_, _) => dirty = true,
+                _ => {}
+            }
+        }
+    }
+}
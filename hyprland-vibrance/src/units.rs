@@ -0,0 +1,293 @@
+//! Conversions from the scales other tools use to express saturation
+//! into the 0.0 - 4.0 saturation level the matrix generator takes,
+//! where 1.0 leaves colors untouched, and parsing of the other values
+//! a CTM is built from, like color temperatures.
+
+use std::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+
+use crate::color::{self, MatrixOptions};
+
+/// Range of the saturation level taken by the matrix generator.
+pub const SATURATION_MIN: f64 = 0.0;
+pub const SATURATION_MAX: f64 = 4.0;
+
+/// Whether levels outside of the usual range are accepted, set with
+/// `--allow-extreme`.
+static EXTREME_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+pub fn allow_extreme() {
+    EXTREME_ALLOWED.store(true, Ordering::Relaxed);
+}
+
+/// Levels accepted anywhere a level is given. Beyond the usual range
+/// only the limits of the protocol apply, see [`check_protocol_level`].
+pub fn saturation_range() -> RangeInclusive<f64> {
+    if EXTREME_ALLOWED.load(Ordering::Relaxed) {
+        f64::MIN..=f64::MAX
+    } else {
+        SATURATION_MIN..=SATURATION_MAX
+    }
+}
+
+/// Checks that a level is within [`saturation_range`], warning about
+/// the clipping extreme levels cause.
+pub fn check_saturation(saturation: f64) -> Result<f64, String> {
+    if !saturation_range().contains(&saturation) {
+        return Err(format!(
+            "saturation must be between {} and {} ({}% to {}%), got {} (see --allow-extreme)",
+            SATURATION_MIN,
+            SATURATION_MAX,
+            saturation_to_percent(SATURATION_MIN),
+            saturation_to_percent(SATURATION_MAX),
+            saturation
+        ));
+    }
+
+    if !(SATURATION_MIN..=SATURATION_MAX).contains(&saturation) {
+        check_protocol_level(saturation)?;
+    }
+
+    if saturation > SATURATION_MAX {
+        warn!(
+            "Saturation {} is extreme: most colors will be clipped to the primaries",
+            saturation
+        );
+    } else if saturation < SATURATION_MIN {
+        warn!(
+            "Saturation {} is negative: hues will be inverted",
+            saturation
+        );
+    }
+    Ok(saturation)
+}
+
+/// Checks that the matrix of an extreme level can be sent to the
+/// compositor at all. The options of the rule it ends up in may still
+/// push it over later, which is checked again on the final matrix.
+fn check_protocol_level(saturation: f64) -> Result<(), String> {
+    color::check_protocol_limits(&color::saturation_matrix(
+        saturation,
+        &MatrixOptions::default(),
+    ))
+    .map_err(|e| format!("saturation {} is too extreme: {}", saturation, e))
+}
+
+/// Range of the NVIDIA digital vibrance setting, 0 being the default.
+pub const NV_VIBRANCE_MIN: i32 = -1024;
+pub const NV_VIBRANCE_MAX: i32 = 1023;
+
+/// Maps an NVIDIA digital vibrance value onto a saturation level the
+/// same way libvibrant maps them the other way around: the negative
+/// half of the range covers 0.0 (grayscale) to 1.0, and the positive
+/// one 1.0 to 4.0.
+pub fn nv_vibrance_to_saturation(vibrance: i32) -> f64 {
+    if vibrance < 0 {
+        1.0 + vibrance as f64 / -(NV_VIBRANCE_MIN as f64)
+    } else {
+        1.0 + 3.0 * vibrance as f64 / NV_VIBRANCE_MAX as f64
+    }
+}
+
+pub fn parse_nv_vibrance(s: &str) -> Result<f64, String> {
+    let vibrance: i32 = s
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid integer", s))?;
+    if (NV_VIBRANCE_MIN..=NV_VIBRANCE_MAX).contains(&vibrance) {
+        Ok(nv_vibrance_to_saturation(vibrance))
+    } else {
+        Err(format!(
+            "NVIDIA vibrance must be between {} and {}, got {}",
+            NV_VIBRANCE_MIN, NV_VIBRANCE_MAX, vibrance
+        ))
+    }
+}
+
+/// Maps a percentage onto a saturation level, 100% leaving colors
+/// untouched, as GPU control panels do. 0% is grayscale and 400% the
+/// maximum level.
+pub fn percent_to_saturation(percent: f64) -> f64 {
+    percent / 100.0
+}
+
+pub fn saturation_to_percent(saturation: f64) -> f64 {
+    saturation * 100.0
+}
+
+/// Parses a saturation level, given either as is (`1.5`) or as a
+/// percentage (`150%`).
+pub fn parse_saturation(s: &str) -> Result<f64, String> {
+    let saturation = match s.trim().strip_suffix('%') {
+        Some(percent) => percent
+            .trim()
+            .parse::<f64>()
+            .map(percent_to_saturation)
+            .map_err(|_| format!("'{}' is not a valid percentage", s))?,
+        None => s
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("'{}' is not a valid float", s))?,
+    };
+
+    check_saturation(saturation)
+}
+
+/// Range of the white point temperatures, in Kelvin, where 6500K
+/// leaves colors untouched.
+pub const TEMPERATURE_MIN: f64 = 1000.0;
+pub const TEMPERATURE_MAX: f64 = 20000.0;
+pub const TEMPERATURE_NEUTRAL: f64 = 6500.0;
+
+/// Parses a color temperature, in Kelvin, with or without its unit
+/// (`5000K` or `5000`).
+pub fn parse_temperature(s: &str) -> Result<f64, String> {
+    let value = s.trim();
+    let value = value
+        .strip_suffix(['K', 'k'])
+        .unwrap_or(value)
+        .trim_end()
+        .parse::<f64>()
+        .map_err(|_| format!("'{}' is not a valid temperature", s))?;
+
+    if !(TEMPERATURE_MIN..=TEMPERATURE_MAX).contains(&value) {
+        return Err(format!(
+            "temperature must be between {}K and {}K, got {}",
+            TEMPERATURE_MIN, TEMPERATURE_MAX, s
+        ));
+    }
+    Ok(value)
+}
+
+/// Largest brightness gain, beyond which most of the range is clipped
+/// to white.
+pub const BRIGHTNESS_MAX: f64 = 2.0;
+
+/// Largest contrast gain, for the same reason.
+pub const CONTRAST_MAX: f64 = 2.0;
+
+/// Parses a brightness gain, given as is (`0.8`) or as a percentage
+/// (`80%`), 1.0 leaving colors untouched.
+pub fn parse_brightness(s: &str) -> Result<f64, String> {
+    parse_gain(s, "brightness", BRIGHTNESS_MAX)
+}
+
+/// Parses a contrast gain, the same way as a brightness.
+pub fn parse_contrast(s: &str) -> Result<f64, String> {
+    parse_gain(s, "contrast", CONTRAST_MAX)
+}
+
+fn parse_gain(s: &str, what: &str, max: f64) -> Result<f64, String> {
+    let gain = match s.trim().strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+        None => s.trim().parse::<f64>(),
+    }
+    .map_err(|_| format!("'{}' is not a valid {}", s, what))?;
+
+    if !(0.0..=max).contains(&gain) {
+        return Err(format!(
+            "{} must be between 0% and {}%, got {}",
+            what,
+            max * 100.0,
+            s
+        ));
+    }
+    Ok(gain)
+}
+
+/// Formats a fraction as a percentage, rounding off the error of the
+/// conversion so that `55%` doesn't come back as `55.00000000000001%`.
+pub fn format_percent(fraction: f64) -> String {
+    format!("{}%", (fraction * 100.0 * 1e9).round() / 1e9)
+}
+
+/// Parses a fraction between 0.0 and 1.0, given as is (`0.5`) or as a
+/// percentage (`50%`).
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction = match s.trim().strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+        None => s.trim().parse::<f64>(),
+    }
+    .map_err(|_| format!("'{}' is not a valid fraction", s))?;
+
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("'{}' must be between 0% and 100%", s));
+    }
+    Ok(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_maps_linearly_around_identity() {
+        assert_eq!(percent_to_saturation(100.0), 1.0);
+        assert_eq!(percent_to_saturation(150.0), 1.5);
+        assert_eq!(percent_to_saturation(0.0), SATURATION_MIN);
+        assert_eq!(percent_to_saturation(400.0), SATURATION_MAX);
+        assert_eq!(saturation_to_percent(percent_to_saturation(250.0)), 250.0);
+    }
+
+    #[test]
+    fn parses_plain_and_percent_levels() {
+        assert_eq!(parse_saturation("2.5"), Ok(2.5));
+        assert_eq!(parse_saturation("150%"), Ok(1.5));
+        assert_eq!(parse_saturation(" 80 % "), Ok(0.8));
+        assert!(parse_saturation("401%").is_err());
+        assert!(parse_saturation("-1").is_err());
+        assert!(parse_saturation("lots%").is_err());
+    }
+
+    #[test]
+    fn extreme_levels_stay_within_protocol_limits() {
+        assert!(check_protocol_level(100.0).is_ok());
+        assert!(check_protocol_level(-100.0).is_ok());
+        assert!(check_protocol_level(1e12).is_err());
+        assert!(check_protocol_level(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn percentages_round_trip() {
+        for percent in ["7%", "14%", "28%", "55%", "57%", "80%", "12.5%"] {
+            let fraction = parse_fraction(percent).unwrap();
+            assert_eq!(format_percent(fraction), percent);
+            assert_eq!(parse_fraction(&format_percent(fraction)), Ok(fraction));
+        }
+    }
+
+    #[test]
+    fn nv_vibrance_covers_whole_range() {
+        assert_eq!(nv_vibrance_to_saturation(0), 1.0);
+        assert_eq!(nv_vibrance_to_saturation(NV_VIBRANCE_MIN), SATURATION_MIN);
+        assert_eq!(nv_vibrance_to_saturation(NV_VIBRANCE_MAX), SATURATION_MAX);
+        assert!(parse_nv_vibrance("1024").is_err());
+    }
+
+    #[test]
+    fn parses_temperatures_with_and_without_unit() {
+        assert_eq!(parse_temperature("5000K"), Ok(5000.0));
+        assert_eq!(parse_temperature("3400k"), Ok(3400.0));
+        assert_eq!(parse_temperature(" 6500 "), Ok(TEMPERATURE_NEUTRAL));
+        assert!(parse_temperature("500K").is_err());
+        assert!(parse_temperature("warm").is_err());
+    }
+
+    #[test]
+    fn parses_brightness_gains() {
+        assert_eq!(parse_brightness("80%"), Ok(0.8));
+        assert_eq!(parse_brightness("1.5"), Ok(1.5));
+        assert!(parse_brightness("250%").is_err());
+        assert!(parse_brightness("-0.1").is_err());
+    }
+
+    #[test]
+    fn parses_fractions() {
+        assert_eq!(parse_fraction("50%"), Ok(0.5));
+        assert_eq!(parse_fraction("0.25"), Ok(0.25));
+        assert!(parse_fraction("120%").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Local record of the programs that go fullscreen without any rule
+//! matching them, kept in `$XDG_STATE_HOME/hyprland-vibrance/usage.json`
+//! when the daemon runs with `--track-usage`. The `suggest` subcommand
+//! turns the most frequent ones into rules. Nothing is recorded unless
+//! asked, and the file never leaves the machine.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::persistence;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct AppUsage {
+    /// Times a window of the program went fullscreen.
+    pub fullscreen_count: u32,
+    /// Class Hyprland reported when the window was opened, if known.
+    pub initial_class: Option<String>,
+    pub last_title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Usage {
+    /// By app id, or by initial class when the app id isn't known.
+    #[serde(default)]
+    pub apps: BTreeMap<String, AppUsage>,
+}
+
+fn usage_path() -> io::Result<PathBuf> {
+    Ok(persistence::state_dir()?.join("usage.json"))
+}
+
+impl Usage {
+    pub fn load() -> io::Result<Usage> {
+        match fs::read_to_string(usage_path()?) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(io::Error::other),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Usage::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = usage_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(
+            &tmp_path,
+            serde_json::to_vec_pretty(self).map_err(io::Error::other)?,
+        )?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn record_fullscreen(
+        &mut self,
+        app_id: &str,
+        initial_class: Option<&str>,
+        title: Option<&str>,
+    ) {
+        let entry = self.apps.entry(app_id.to_string()).or_default();
+        entry.fullscreen_count += 1;
+        if initial_class.is_some() {
+            entry.initial_class = initial_class.map(str::to_string);
+        }
+        if title.is_some() {
+            entry.last_title = title.map(str::to_string);
+        }
+    }
+
+    /// Programs that went fullscreen at least `min_count` times, most
+    /// frequent first.
+    pub fn frequent(&self, min_count: u32) -> Vec<(&String, &AppUsage)> {
+        let mut apps: Vec<(&String, &AppUsage)> = self
+            .apps
+            .iter()
+            .filter(|(_, usage)| usage.fullscreen_count >= min_count)
+            .collect();
+        apps.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.fullscreen_count));
+        apps
+    }
+}
@@ -0,0 +1,129 @@
+//! Status of the running daemon as a Waybar custom module, one JSON
+//! line every time it changes:
+//!
+//! ```json
+//! "custom/vibrance": {
+//!     "exec": "hyprland-vibrance status --stream",
+//!     "return-type": "json",
+//!     "format": "vibrance {}",
+//!     "on-click": "hyprland-vibrance toggle"
+//! }
+//! ```
+//!
+//! The class is one of `active`, `idle`, `paused`, `disabled` and
+//! `stopped`, for styling. The lines only carry the keys Waybar reads,
+//! so they work as is with eww or anything else taking JSON lines too.
+
+use std::{thread, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    control::{self, Status},
+    units,
+};
+
+/// How often to try reaching the daemon while it isn't running.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Line {
+    text: String,
+    /// Same as the class, so `format-icons` can be keyed by state.
+    alt: String,
+    tooltip: String,
+    class: String,
+    /// Highest applied level, relative to the maximum one.
+    percentage: u8,
+}
+
+fn format_level(saturation: f64) -> String {
+    format!("{:.0}%", units::saturation_to_percent(saturation))
+}
+
+fn line(status: &Status) -> Line {
+    let level = status
+        .outputs
+        .iter()
+        .filter(|output| output.matrix.is_some())
+        .map(|output| output.saturation)
+        .max_by(f64::total_cmp);
+
+    let (text, class) = if status.disabled_by_file {
+        ("disabled".to_string(), "disabled")
+    } else if status.paused {
+        ("paused".to_string(), "paused")
+    } else if let Some(level) = level {
+        (format_level(level), "active")
+    } else {
+        ("off".to_string(), "idle")
+    };
+
+    let mut tooltip = Vec::new();
+    if let Some(top_level) = status.top_levels.iter().find(|top_level| top_level.focused) {
+        tooltip.push(format!(
+            "Focused: {} ({})",
+            top_level.title.as_deref().unwrap_or("<no title>"),
+            top_level.app_id.as_deref().unwrap_or("-")
+        ));
+    }
+    if let Some(saturation) = status.saturation_override {
+        tooltip.push(format!(
+            "Level set to {} until reset",
+            format_level(saturation)
+        ));
+    }
+    for output in status.outputs.iter() {
+        let mut level = format_level(output.saturation);
+        if let Some(temperature) = output.temperature {
+            level.push_str(&format!(" {}K", temperature));
+        }
+        tooltip.push(match output.source.as_deref() {
+            Some(source) => format!("{}: {} ({})", output.name, level, source),
+            None => format!("{}: {}", output.name, level),
+        });
+    }
+
+    Line {
+        text,
+        alt: class.to_string(),
+        tooltip: tooltip.join("\n"),
+        class: class.to_string(),
+        percentage: (level.unwrap_or(1.0) / units::SATURATION_MAX * 100.0)
+            .round()
+            .clamp(0.0, 100.0) as u8,
+    }
+}
+
+fn stopped() -> Line {
+    Line {
+        text: "stopped".to_string(),
+        alt: "stopped".to_string(),
+        tooltip: "hyprland-vibrance isn't running".to_string(),
+        class: "stopped".to_string(),
+        percentage: 0,
+    }
+}
+
+fn print(line: &Line, last: &mut Option<String>) {
+    let encoded = serde_json::to_string(line).unwrap();
+    if last.as_ref() != Some(&encoded) {
+        println!("{}", encoded);
+        *last = Some(encoded);
+    }
+}
+
+/// Prints a line for the current status and then for every change,
+/// forever. While the daemon isn't running a `stopped` line is printed,
+/// and the stream picks up again once it's back.
+pub fn stream() -> ! {
+    let mut last = None;
+    loop {
+        let _ = control::subscribe(|status| {
+            print(&line(&status), &mut last);
+            true
+        });
+        print(&stopped(), &mut last);
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}